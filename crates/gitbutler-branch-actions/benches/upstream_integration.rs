@@ -0,0 +1,63 @@
+use std::fs;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use gitbutler_branch::BranchCreateRequest;
+use gitbutler_branch_actions::upstream_integration_statuses;
+use gitbutler_project::Project;
+use gitbutler_testsupport::{paths, TestProject};
+use tempfile::TempDir;
+
+/// Returns a project with a commit upstream that isn't integrated yet, and `num_stacks`
+/// stacks with a commit each.
+fn workspace_with_stacks(num_stacks: usize) -> (TempDir, TestProject, Project) {
+    let data_dir = paths::data_dir();
+    let projects = gitbutler_project::Controller::from_path(data_dir.path());
+    let repository = TestProject::default();
+    let project = projects.add(repository.path()).unwrap();
+
+    fs::write(repository.path().join("file.txt"), "one").unwrap();
+    let first_commit_oid = repository.commit_all("first");
+    fs::write(repository.path().join("file.txt"), "two").unwrap();
+    repository.commit_all("second");
+    repository.push();
+    repository.reset_hard(Some(first_commit_oid));
+
+    gitbutler_branch_actions::set_base_branch(
+        &project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    for index in 0..num_stacks {
+        let stack_id = gitbutler_branch_actions::create_virtual_branch(
+            &project,
+            &BranchCreateRequest {
+                selected_for_changes: Some(true),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        fs::write(
+            repository.path().join(format!("stack-{index}.txt")),
+            "content",
+        )
+        .unwrap();
+        gitbutler_branch_actions::create_commit(&project, stack_id, "commit", None, false).unwrap();
+    }
+
+    (data_dir, repository, project)
+}
+
+pub fn benchmark_upstream_integration_statuses(c: &mut Criterion) {
+    const NUM_STACKS: usize = 30;
+    let (_data_dir, _repository, project) = workspace_with_stacks(NUM_STACKS);
+
+    let mut group = c.benchmark_group("upstream-integration-statuses");
+    group.throughput(Throughput::Elements(NUM_STACKS as u64));
+    group.bench_function(format!("{NUM_STACKS} stacks"), |b| {
+        b.iter(|| upstream_integration_statuses(black_box(&project), None).unwrap())
+    });
+}
+
+criterion_group!(benches, benchmark_upstream_integration_statuses);
+criterion_main!(benches);
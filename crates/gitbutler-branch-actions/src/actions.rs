@@ -5,7 +5,8 @@ use crate::move_commits;
 use crate::r#virtual::StackListResult;
 use crate::reorder::{self, StackOrder};
 use crate::upstream_integration::{
-    self, BaseBranchResolution, BaseBranchResolutionApproach, Resolution, StackStatuses,
+    self, BaseBranchResolution, BaseBranchResolutionApproach, ConflictChoice, IntegrationOutcome,
+    IntegrationPlan, IntegrationResult, IntegrationSnapshot, Resolution, StackStatuses,
     UpstreamIntegrationContext,
 };
 use crate::VirtualBranchHunkRangeMap;
@@ -577,20 +578,75 @@ pub fn upstream_integration_statuses(
     upstream_integration::upstream_integration_statuses(&context)
 }
 
+/// Returns how many stacks would conflict if the default target was updated to
+/// `candidate`, without changing anything.
+pub fn conflict_count_for_target(project: &Project, candidate: git2::Oid) -> Result<usize> {
+    Ok(upstream_integration_statuses(project, Some(candidate))?.conflicted_count())
+}
+
 pub fn integrate_upstream(
     project: &Project,
     resolutions: &[Resolution],
     base_branch_resolution: Option<BaseBranchResolution>,
-) -> Result<()> {
+) -> Result<IntegrationOutcome> {
     let command_context = CommandContext::open(project)?;
     let mut guard = project.exclusive_worktree_access();
 
-    let _ = command_context.project().create_snapshot(
-        SnapshotDetails::new(OperationKind::UpdateWorkspaceBase),
+    let snapshot_tree = command_context
+        .project()
+        .prepare_snapshot(guard.read_permission());
+    let outcome = upstream_integration::integrate_upstream(
+        &command_context,
+        resolutions,
+        base_branch_resolution,
         guard.write_permission(),
     );
+    let _ = snapshot_tree.and_then(|snapshot_tree| {
+        command_context.project().snapshot_upstream_integration(
+            snapshot_tree,
+            outcome.as_ref().map(IntegrationOutcome::summary_line),
+            guard.write_permission(),
+        )
+    });
+    outcome
+}
 
-    upstream_integration::integrate_upstream(
+pub fn integrate_upstream_partial(
+    project: &Project,
+    resolutions: &[Resolution],
+) -> Result<IntegrationOutcome> {
+    let command_context = CommandContext::open(project)?;
+    let mut guard = project.exclusive_worktree_access();
+
+    let snapshot_tree = command_context
+        .project()
+        .prepare_snapshot(guard.read_permission());
+    let outcome = upstream_integration::integrate_upstream_partial(
+        &command_context,
+        resolutions,
+        guard.write_permission(),
+    );
+    let _ = snapshot_tree.and_then(|snapshot_tree| {
+        command_context.project().snapshot_upstream_integration(
+            snapshot_tree,
+            outcome.as_ref().map(IntegrationOutcome::summary_line),
+            guard.write_permission(),
+        )
+    });
+    outcome
+}
+
+/// Works out what integrating upstream with `resolutions` would do, so it can be
+/// applied later with [`apply_integration_plan`].
+pub fn build_integration_plan(
+    project: &Project,
+    resolutions: Vec<Resolution>,
+    base_branch_resolution: Option<BaseBranchResolution>,
+) -> Result<IntegrationPlan> {
+    let command_context = CommandContext::open(project)?;
+    let mut guard = project.exclusive_worktree_access();
+
+    upstream_integration::build_plan(
         &command_context,
         resolutions,
         base_branch_resolution,
@@ -598,6 +654,49 @@ pub fn integrate_upstream(
     )
 }
 
+pub fn apply_integration_plan(
+    project: &Project,
+    plan: IntegrationPlan,
+) -> Result<IntegrationOutcome> {
+    let command_context = CommandContext::open(project)?;
+    let mut guard = project.exclusive_worktree_access();
+
+    let snapshot_tree = command_context
+        .project()
+        .prepare_snapshot(guard.read_permission());
+    let outcome =
+        upstream_integration::apply_plan(&command_context, guard.write_permission(), plan);
+    let _ = snapshot_tree.and_then(|snapshot_tree| {
+        command_context.project().snapshot_upstream_integration(
+            snapshot_tree,
+            outcome.as_ref().map(IntegrationOutcome::summary_line),
+            guard.write_permission(),
+        )
+    });
+    outcome
+}
+
+pub fn cherry_pick_into_branch(
+    project: &Project,
+    stack_id: StackId,
+    commit: git2::Oid,
+) -> Result<IntegrationResult> {
+    let command_context = CommandContext::open(project)?;
+    let mut guard = project.exclusive_worktree_access();
+
+    let _ = command_context.project().create_snapshot(
+        SnapshotDetails::new(OperationKind::CherryPick),
+        guard.write_permission(),
+    );
+
+    upstream_integration::cherry_pick_into_branch(
+        &command_context,
+        guard.write_permission(),
+        stack_id,
+        commit,
+    )
+}
+
 pub fn resolve_upstream_integration(
     project: &Project,
     resolution_approach: BaseBranchResolutionApproach,
@@ -612,6 +711,40 @@ pub fn resolve_upstream_integration(
     )
 }
 
+/// Resolves the conflicted file at `path` of `stack_id` as `choice` says, and remembers
+/// the choice for the integration in progress.
+pub fn resolve_integration_conflict(
+    project: &Project,
+    stack_id: StackId,
+    path: &str,
+    choice: ConflictChoice,
+) -> Result<git2::Oid> {
+    let command_context = CommandContext::open(project)?;
+    let mut guard = project.exclusive_worktree_access();
+
+    upstream_integration::resolve_conflicted_file(
+        &command_context,
+        stack_id,
+        path,
+        choice,
+        guard.write_permission(),
+    )
+}
+
+pub fn abort_integration(project: &Project, snapshot: &IntegrationSnapshot) -> Result<()> {
+    let command_context = CommandContext::open(project)?;
+    let mut guard = project.exclusive_worktree_access();
+
+    upstream_integration::abort_integration(&command_context, snapshot, guard.write_permission())
+}
+
+pub fn revert_target(project: &Project, to: git2::Oid) -> Result<()> {
+    let command_context = CommandContext::open(project)?;
+    let mut guard = project.exclusive_worktree_access();
+
+    upstream_integration::revert_target(&command_context, guard.write_permission(), to)
+}
+
 pub(crate) fn open_with_verify(project: &Project) -> Result<CommandContext> {
     let ctx = CommandContext::open(project)?;
     let mut guard = project.exclusive_worktree_access();
@@ -19,7 +19,7 @@ use gitbutler_repo::{
     RepositoryExt,
 };
 use gitbutler_repo_actions::RepoActionsExt;
-use gitbutler_stack::{BranchOwnershipClaims, Stack, Target, VirtualBranchesHandle};
+use gitbutler_stack::{BranchOwnershipClaims, PushDetails, Stack, Target, VirtualBranchesHandle};
 use serde::Serialize;
 
 #[derive(Debug, Serialize, PartialEq, Clone)]
@@ -400,6 +400,13 @@ fn default_target(base_path: &Path) -> Result<Target> {
 pub(crate) fn push(ctx: &CommandContext, with_force: bool) -> Result<()> {
     ctx.assure_resolved()?;
     let target = default_target(&ctx.project().gb_dir())?;
-    let _ = ctx.push(target.sha, &target.branch, with_force, None, None);
+    let _ = ctx.push(
+        &PushDetails {
+            head: target.sha,
+            remote_refname: target.branch,
+        },
+        with_force,
+        None,
+    );
     Ok(())
 }
@@ -5,11 +5,11 @@ use gitbutler_commit::commit_ext::CommitExt;
 use gitbutler_oplog::entry::{OperationKind, SnapshotDetails};
 use gitbutler_oplog::{OplogExt, SnapshotExt};
 use gitbutler_project::Project;
-use gitbutler_reference::normalize_branch_name;
+use gitbutler_reference::{normalize_branch_name, RemoteRefname};
 use gitbutler_repo_actions::RepoActionsExt;
 use gitbutler_stack::stack_context::{CommandContextExt, StackContext};
-use gitbutler_stack::{CommitOrChangeId, PatchReferenceUpdate, StackBranch};
-use gitbutler_stack::{Stack, StackId, Target};
+use gitbutler_stack::{CommitOrChangeId, PatchReferenceUpdate, PushDestination, StackBranch};
+use gitbutler_stack::{Stack, StackId, Target, UpdatableResolutionApproach};
 use serde::{Deserialize, Serialize};
 
 use crate::dependencies::{commit_dependencies_from_stack, StackDependencies};
@@ -167,9 +167,78 @@ pub fn update_series_pr_number(
     stack.set_pr_number(ctx, &head_name, pr_number)
 }
 
+/// Sets the target branch the stack is based on, or `None` to base it on the default target.
+/// Stacks based on another target are left out when integrating the default target.
+pub fn set_integration_target(
+    project: &Project,
+    stack_id: StackId,
+    integration_target: Option<RemoteRefname>,
+) -> Result<()> {
+    let ctx = &open_with_verify(project)?;
+    let mut guard = project.exclusive_worktree_access();
+    let _ = ctx.project().create_snapshot(
+        SnapshotDetails::new(OperationKind::GenericBranchUpdate),
+        guard.write_permission(),
+    );
+    assure_open_workspace_mode(ctx).context("Requires an open workspace mode")?;
+    let state = ctx.project().virtual_branches();
+    let mut stack = state.get_stack(stack_id)?;
+    stack.integration_target = integration_target;
+    state.set_stack(stack)
+}
+
+/// Sets how the stack is integrated with upstream unless another approach is picked,
+/// or `None` to rebase it.
+pub fn set_preferred_integration_approach(
+    project: &Project,
+    stack_id: StackId,
+    approach: Option<UpdatableResolutionApproach>,
+) -> Result<()> {
+    let ctx = &open_with_verify(project)?;
+    let mut guard = project.exclusive_worktree_access();
+    let _ = ctx.project().create_snapshot(
+        SnapshotDetails::new(OperationKind::GenericBranchUpdate),
+        guard.write_permission(),
+    );
+    assure_open_workspace_mode(ctx).context("Requires an open workspace mode")?;
+    let state = ctx.project().virtual_branches();
+    let mut stack = state.get_stack(stack_id)?;
+    stack.preferred_integration_approach = approach;
+    state.set_stack(stack)
+}
+
 /// Pushes all series in the stack to the remote.
 /// This operation will error out if the target has no push remote configured.
 pub fn push_stack(project: &Project, stack_id: StackId, with_force: bool) -> Result<()> {
+    push_stack_to(project, stack_id, with_force, PushDestination::Branch)
+}
+
+/// Pushes all series in the stack for review against `target_branch`, e.g. to `refs/for/main`
+/// with Gerrit, rather than to branches of their own.
+/// This operation will error out if the target has no push remote configured.
+pub fn push_stack_for_review(
+    project: &Project,
+    stack_id: StackId,
+    target_branch: String,
+) -> Result<()> {
+    push_stack_to(
+        project,
+        stack_id,
+        false,
+        PushDestination::Review {
+            target: target_branch,
+        },
+    )
+}
+
+/// Pushes all series in the stack to `destination` on the remote.
+/// This operation will error out if the target has no push remote configured.
+pub fn push_stack_to(
+    project: &Project,
+    stack_id: StackId,
+    with_force: bool,
+    destination: PushDestination,
+) -> Result<()> {
     let ctx = &open_with_verify(project)?;
     assure_open_workspace_mode(ctx).context("Requires an open workspace mode")?;
     let state = ctx.project().virtual_branches();
@@ -208,11 +277,12 @@ pub fn push_stack(project: &Project, stack_id: StackId, with_force: bool) -> Res
             continue;
         }
         let push_details = stack.push_details(ctx, branch.name)?;
-        ctx.push(
-            push_details.head,
-            &push_details.remote_refname,
+        let refspec = push_details.push_refspec_to(&destination, with_force)?;
+        tracing::debug!(%refspec, remote = %push_details.remote_refname.remote(), "pushing series");
+        ctx.push_to(
+            &push_details,
+            &destination,
             with_force,
-            None,
             Some(Some(stack.id)),
         )?
     }
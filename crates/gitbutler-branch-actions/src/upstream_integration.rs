@@ -1,28 +1,53 @@
 use crate::stack::branch_integrated;
 use crate::{r#virtual::IsCommitIntegrated, BranchManagerExt, VirtualBranchesExt as _};
-use anyhow::{anyhow, bail, Context, Result};
-use gitbutler_cherry_pick::RepositoryExt;
+use anyhow::{bail, Context, Result};
+use bstr::{BStr, ByteSlice as _};
+use gitbutler_cherry_pick::{
+    conflicting_paths, merge_trees_with_policy, ConflictPolicy, ConflictResolution, ConflictSides,
+    ConflictedTreeKey, RepositoryExt,
+};
 use gitbutler_command_context::CommandContext;
 use gitbutler_commit::commit_ext::CommitExt as _;
+use gitbutler_commit::commit_headers::{CommitHeadersV2, HasCommitHeaders as _};
 use gitbutler_oxidize::{git2_to_gix_object_id, gix_to_git2_oid, GixRepositoryExt};
 use gitbutler_project::access::WorktreeWritePermission;
+use gitbutler_project::AUTO_TRACK_LIMIT_BYTES;
+use gitbutler_reference::RemoteRefname;
 use gitbutler_repo::logging::RepositoryExt as _;
 use gitbutler_repo::RepositoryExt as _;
 use gitbutler_repo::{
     logging::LogUntil,
-    rebase::{cherry_rebase_group, gitbutler_merge_commits},
+    rebase::{
+        cherry_rebase_group, cherry_rebase_group_with_policy, gitbutler_merge_commits,
+        gitbutler_merge_commits_with_message, merge_commit_message,
+    },
 };
 use gitbutler_repo_actions::RepoActionsExt as _;
 use gitbutler_stack::stack_context::StackContext;
-use gitbutler_stack::{Stack, StackId, Target, VirtualBranchesHandle};
-use gitbutler_workspace::{checkout_branch_trees, compute_updated_branch_head, BranchHeadAndTree};
+use gitbutler_stack::{
+    CommitOrChangeId, Stack, StackBranch, StackId, Target, UpdatableResolutionApproach,
+    VirtualBranchesHandle,
+};
+use gitbutler_time::time::now_since_unix_epoch_ms;
+use gitbutler_workspace::{
+    checkout_branch_trees, compute_updated_branch_head,
+    compute_updated_branch_head_for_commits_with_policy, BranchHeadAndTree,
+};
+use gix::merge::blob::builtin_driver::text::Labels;
+use gix::merge::tree::TreatAsUnresolved;
+use gix::object::tree::EntryKind;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+use std::rc::Rc;
 
 #[derive(Serialize, PartialEq, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct NameAndStatus {
     name: String,
     status: BranchStatus,
+    commit_counts: CommitCounts,
 }
 
 #[derive(Serialize, PartialEq, Debug)]
@@ -32,6 +57,17 @@ pub struct StackStatus {
     branch_statuses: Vec<NameAndStatus>,
 }
 
+/// How much work integrating a branch involves, e.g. "rebase 5 commits over 3 new upstream commits".
+#[derive(Serialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CommitCounts {
+    /// The number of commits between the branch head and the old target, including those of
+    /// the branches below it in the stack. Integrated branches have none to replay.
+    commits_to_replay: usize,
+    /// The number of commits between the old target and the new target.
+    upstream_commits_incoming: usize,
+}
+
 #[derive(Serialize, PartialEq, Debug)]
 #[serde(tag = "type", content = "subject", rename_all = "camelCase")]
 pub enum TreeStatus {
@@ -59,7 +95,44 @@ pub enum StackStatuses {
     UpdatesRequired(Vec<(StackId, StackStatus)>),
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+impl StackStatuses {
+    /// Returns the number of stacks whose tree or branches conflict with the new target.
+    pub fn conflicted_count(&self) -> usize {
+        self.stack_statuses()
+            .filter(|status| status.is_conflicted())
+            .count()
+    }
+
+    /// Returns the number of stacks that can be updated without conflicts.
+    pub fn updatable_count(&self) -> usize {
+        self.stack_statuses()
+            .filter(|status| !status.is_conflicted())
+            .count()
+    }
+
+    /// Returns the ids of the stacks grouped by the kind of their status, one of
+    /// `"conflicted"`, `"safe"`, `"empty"` or `"integrated"`, e.g. to show them in sections.
+    /// Kinds without stacks are left out.
+    pub fn grouped(&self) -> HashMap<&'static str, Vec<StackId>> {
+        let mut groups: HashMap<&'static str, Vec<StackId>> = HashMap::new();
+        if let StackStatuses::UpdatesRequired(statuses) = self {
+            for (stack_id, status) in statuses {
+                groups.entry(status.kind()).or_default().push(*stack_id);
+            }
+        }
+        groups
+    }
+
+    fn stack_statuses(&self) -> impl Iterator<Item = &StackStatus> {
+        let statuses = match self {
+            StackStatuses::UpToDate => &[][..],
+            StackStatuses::UpdatesRequired(statuses) => statuses.as_slice(),
+        };
+        statuses.iter().map(|(_, status)| status)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
 #[serde(tag = "type", content = "subject", rename_all = "camelCase")]
 pub enum BaseBranchResolutionApproach {
     Rebase,
@@ -67,13 +140,52 @@ pub enum BaseBranchResolutionApproach {
     HardReset,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "type", content = "subject", rename_all = "camelCase")]
 pub enum ResolutionApproach {
     Rebase,
+    /// Merges the new target into the stack, adding the merge commit on top of it.
     Merge,
     Unapply,
     Delete,
+    /// Commits the uncommitted changes with the given message, then rebases.
+    CommitThenRebase {
+        message: String,
+    },
+    /// Rebases only the commits, and carries the uncommitted changes over without
+    /// committing them, even if they conflict with the new target. Files that conflict
+    /// keep their uncommitted version.
+    RebaseCommitsOnly,
+    /// Merges the stack into the new target instead, so the new target is the first
+    /// parent, e.g. to build an integration branch. The merge commit is reported as an
+    /// integration tip of its own, and the stack stays where it is.
+    MergeIntoTarget,
+}
+
+/// What to do when the old and new target share no common ancestor, e.g. after a repository graft.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "type", content = "subject", rename_all = "camelCase")]
+pub enum MergeBaseStrategy {
+    /// Fail with an error if there is no common ancestor.
+    #[default]
+    RequireCommonAncestor,
+    /// Use the empty tree as merge base if there is no common ancestor.
+    EmptyTreeFallback,
+}
+
+/// A way to resolve all stacks of an integration at once, see [`Resolution::from_preset`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResolutionPreset {
+    /// Rebase the stacks, taking the new target's version of conflicting files, and
+    /// delete the stacks that were integrated.
+    PreferUpstream,
+    /// Rebase the stacks, keeping the stack's version of conflicting files, and unapply
+    /// the stacks that were integrated.
+    PreferLocal,
+    /// Use the approach each stack prefers, and leave the conflicts to be resolved by hand.
+    /// The stacks that were integrated are unapplied.
+    Interactive,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -84,6 +196,168 @@ pub struct BaseBranchResolution {
     approach: BaseBranchResolutionApproach,
 }
 
+impl BranchStatus {
+    /// Returns a human readable description of the status, e.g. for CLI output.
+    pub fn describe(&self) -> String {
+        match self {
+            BranchStatus::SaflyUpdatable => "can be updated safely".to_string(),
+            BranchStatus::Integrated => "fully integrated".to_string(),
+            BranchStatus::Conflicted { rebasable: true } => {
+                "conflicted, but can be rebased without conflicts".to_string()
+            }
+            BranchStatus::Conflicted { rebasable: false } => "conflicted".to_string(),
+            BranchStatus::Empty => "empty".to_string(),
+        }
+    }
+}
+
+/// The error returned when the old and new target share no common ancestor,
+/// e.g. because the target branch was re-pointed to an unrelated history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnrelatedTargets {
+    pub old_target: git2::Oid,
+    pub new_target: git2::Oid,
+}
+
+impl std::fmt::Display for UnrelatedTargets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The old target {} and the new target {} share no common ancestor",
+            self.old_target, self.new_target
+        )
+    }
+}
+
+impl std::error::Error for UnrelatedTargets {}
+
+/// The error returned when the branch the default target tracks doesn't exist, e.g.
+/// because it was deleted on the remote and pruned. Carries the full name of the branch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetBranchMissing(pub String);
+
+impl std::fmt::Display for TargetBranchMissing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The target branch {} was not found", self.0)
+    }
+}
+
+impl std::error::Error for TargetBranchMissing {}
+
+/// The error returned when the stacks would conflict with each other once integrated,
+/// even though each of them can be integrated on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictingStacks {
+    /// The files that more than one stack changes differently.
+    pub paths: Vec<String>,
+}
+
+impl std::fmt::Display for ConflictingStacks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The integrated stacks conflict with each other in {}",
+            self.paths.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for ConflictingStacks {}
+
+impl MergeBaseStrategy {
+    /// Returns the merge base of the old and new target, or `None` if they share no
+    /// common ancestor and the strategy permits falling back to the empty tree.
+    fn merge_base(
+        &self,
+        repository: &git2::Repository,
+        old_target: git2::Oid,
+        new_target: git2::Oid,
+    ) -> Result<Option<git2::Oid>> {
+        match repository.merge_base(old_target, new_target) {
+            Ok(merge_base) => Ok(Some(merge_base)),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => match self {
+                MergeBaseStrategy::RequireCommonAncestor => Err(UnrelatedTargets {
+                    old_target,
+                    new_target,
+                }
+                .into()),
+                MergeBaseStrategy::EmptyTreeFallback => Ok(None),
+            },
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns the tree to use as merge base between the old and new target.
+    pub fn merge_base_tree(
+        &self,
+        repository: &git2::Repository,
+        old_target: git2::Oid,
+        new_target: git2::Oid,
+    ) -> Result<git2::Oid> {
+        match self.merge_base(repository, old_target, new_target)? {
+            Some(merge_base) => Ok(repository.find_commit(merge_base)?.tree_id()),
+            None => Ok(repository.treebuilder(None)?.write()?),
+        }
+    }
+}
+
+impl CommitCounts {
+    fn compute(
+        repository: &git2::Repository,
+        branch_head: git2::Oid,
+        old_target: git2::Oid,
+        upstream_commits: &[git2::Oid],
+    ) -> Result<Self> {
+        Ok(Self {
+            commits_to_replay: repository
+                .l(branch_head, LogUntil::Commit(old_target), false)?
+                .len(),
+            upstream_commits_incoming: upstream_commits.len(),
+        })
+    }
+}
+
+/// What the statuses of all stacks need to know about the new target. It only depends on
+/// the old and new target, so it is loaded once for all stacks rather than for each one.
+struct TargetState {
+    new_target_commit_id: gix::ObjectId,
+    new_target_tree_id: gix::ObjectId,
+    /// The commits between the old and new target, sorted so every stack can search
+    /// them without a copy of its own.
+    upstream_commits: Vec<git2::Oid>,
+}
+
+impl TargetState {
+    fn load(
+        repository: &git2::Repository,
+        gix_repository: &gix::Repository,
+        old_target: git2::Oid,
+        new_target_commit_id: gix::ObjectId,
+        merge_base_strategy: &MergeBaseStrategy,
+    ) -> Result<Self> {
+        // Unrelated targets are an error unless the strategy says how to merge them.
+        merge_base_strategy.merge_base_tree(
+            repository,
+            old_target,
+            gix_to_git2_oid(new_target_commit_id),
+        )?;
+        let mut upstream_commits = repository.l(
+            gix_to_git2_oid(new_target_commit_id),
+            LogUntil::Commit(old_target),
+            true,
+        )?;
+        upstream_commits.sort();
+        Ok(Self {
+            new_target_commit_id,
+            new_target_tree_id: gix_repository
+                .find_commit(new_target_commit_id)?
+                .tree_id()?
+                .detach(),
+            upstream_commits,
+        })
+    }
+}
+
 impl StackStatus {
     fn create(tree_status: TreeStatus, branch_statuses: Vec<NameAndStatus>) -> Result<Self> {
         if branch_statuses.is_empty() {
@@ -97,36 +371,80 @@ impl StackStatus {
     }
 
     fn resolution_acceptable(&self, approach: &ResolutionApproach) -> bool {
-        if self.tree_status == TreeStatus::Empty
-            && self
-                .branch_statuses
-                .iter()
-                .all(|branch_status| branch_status.status == BranchStatus::Integrated)
-        {
+        if self.is_integrated() {
             return matches!(
                 approach,
                 ResolutionApproach::Unapply | ResolutionApproach::Delete
             );
         }
 
+        // A conflicted merge can't be anchored as a tip, as nothing would resolve it.
+        if matches!(approach, ResolutionApproach::MergeIntoTarget) && self.is_conflicted() {
+            return false;
+        }
+
         if self.is_single() {
             matches!(
                 approach,
                 ResolutionApproach::Merge
                     | ResolutionApproach::Rebase
+                    | ResolutionApproach::CommitThenRebase { .. }
+                    | ResolutionApproach::RebaseCommitsOnly
+                    | ResolutionApproach::MergeIntoTarget
                     | ResolutionApproach::Unapply
             )
         } else {
             matches!(
                 approach,
-                ResolutionApproach::Rebase | ResolutionApproach::Unapply
+                ResolutionApproach::Rebase
+                    | ResolutionApproach::CommitThenRebase { .. }
+                    | ResolutionApproach::RebaseCommitsOnly
+                    | ResolutionApproach::MergeIntoTarget
+                    | ResolutionApproach::Unapply
             )
         }
     }
 
+    fn is_conflicted(&self) -> bool {
+        self.tree_status == TreeStatus::Conflicted
+            || self.branch_statuses.iter().any(|branch_status| {
+                matches!(branch_status.status, BranchStatus::Conflicted { .. })
+            })
+    }
+
+    /// Returns `true` if the stack has no changes of its own left, as all of its branches
+    /// were integrated.
+    fn is_integrated(&self) -> bool {
+        self.tree_status == TreeStatus::Empty
+            && self
+                .branch_statuses
+                .iter()
+                .all(|branch_status| branch_status.status == BranchStatus::Integrated)
+    }
+
     fn is_single(&self) -> bool {
         self.branch_statuses.len() == 1
     }
+
+    /// Returns the kind of the status that [`StackStatuses::grouped`] groups by.
+    fn kind(&self) -> &'static str {
+        let all_branches = |accepted: &[BranchStatus]| {
+            self.branch_statuses
+                .iter()
+                .all(|branch| accepted.contains(&branch.status))
+        };
+        if self.is_conflicted() {
+            "conflicted"
+        } else if self.tree_status != TreeStatus::Empty {
+            "safe"
+        } else if all_branches(&[BranchStatus::Empty]) {
+            "empty"
+        } else if all_branches(&[BranchStatus::Integrated, BranchStatus::Empty]) {
+            "integrated"
+        } else {
+            "safe"
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -138,41 +456,677 @@ pub struct Resolution {
     #[serde(with = "gitbutler_serde::oid")]
     pub branch_tree: git2::Oid,
     pub approach: ResolutionApproach,
+    /// Whether to fold `fixup!` and `squash!` commits into the commits they refer
+    /// to when rebasing, like `git rebase --autosquash`.
+    #[serde(default)]
+    pub autosquash: bool,
+    /// The commit to rebase the stack onto instead of the new target, for instance the
+    /// head of another stack. It must be reachable from the new target or from a stack
+    /// in the workspace.
+    #[serde(with = "gitbutler_serde::oid_opt", default)]
+    pub rebase_onto: Option<git2::Oid>,
+    /// A tree in which the stack was already merged with the new target by hand. When
+    /// merging, it becomes the tree of the merge commit and of the stack as is, instead
+    /// of merging again. It may not carry conflicts. Other approaches ignore it.
+    #[serde(with = "gitbutler_serde::oid_opt", default)]
+    pub resolved_tree: Option<git2::Oid>,
+    /// The message of the merge commit when merging, in which `{branch}`, `{old_target}`
+    /// and `{new_target}` are replaced by the name of the stack's top branch and the ids of
+    /// the old and new target. Without one, the merge commit gets the usual message naming
+    /// the target and the branch. Other approaches ignore it.
+    #[serde(default)]
+    pub merge_message_template: Option<String>,
+    /// Whether the commit of conflicting uncommitted changes that a rebase creates takes
+    /// its times from the stack's last update rather than the current time, so that
+    /// integrating the same inputs twice yields the same commit. Other approaches ignore it.
+    #[serde(default)]
+    pub deterministic_time: bool,
+    /// Whether rebased commits that replay a commit without a change id, e.g. one that
+    /// predates GitButler, get a new change id so they can be referenced by it. Otherwise
+    /// they stay without one, unless they end up conflicted. Other approaches ignore it.
+    #[serde(default)]
+    pub generate_missing_change_ids: bool,
+    /// Whether rebased commits keep the committer date of the commits they replay, instead
+    /// of getting the current time, so tools sorting by committer date keep their order.
+    /// Other approaches ignore it.
+    #[serde(default)]
+    pub preserve_committer_date: bool,
+    /// Whether rebased commits that replay a signed commit get signed again with the signing
+    /// key of the repository, as their old signature doesn't match them anymore. Commits
+    /// that weren't signed stay unsigned. Other approaches ignore it.
+    #[serde(default)]
+    pub resign_commits: bool,
+}
+
+impl ResolutionApproach {
+    /// Returns `true` if the approach removes the stack, or its changes, from the workspace.
+    pub fn is_destructive(&self) -> bool {
+        match self {
+            ResolutionApproach::Unapply | ResolutionApproach::Delete => true,
+            ResolutionApproach::Rebase
+            | ResolutionApproach::Merge
+            | ResolutionApproach::CommitThenRebase { .. }
+            | ResolutionApproach::RebaseCommitsOnly
+            | ResolutionApproach::MergeIntoTarget => false,
+        }
+    }
+
+    /// Returns `true` if the existing commits of the stack get new ids.
+    pub fn rewrites_history(&self) -> bool {
+        match self {
+            ResolutionApproach::Rebase
+            | ResolutionApproach::CommitThenRebase { .. }
+            | ResolutionApproach::RebaseCommitsOnly => true,
+            ResolutionApproach::Merge
+            | ResolutionApproach::MergeIntoTarget
+            | ResolutionApproach::Unapply
+            | ResolutionApproach::Delete => false,
+        }
+    }
+}
+
+impl From<UpdatableResolutionApproach> for ResolutionApproach {
+    fn from(approach: UpdatableResolutionApproach) -> Self {
+        match approach {
+            UpdatableResolutionApproach::Rebase => ResolutionApproach::Rebase,
+            UpdatableResolutionApproach::Merge => ResolutionApproach::Merge,
+            UpdatableResolutionApproach::MergeIntoTarget => ResolutionApproach::MergeIntoTarget,
+        }
+    }
+}
+
+impl Resolution {
+    /// Returns a resolution of the stack `branch_id` with `approach`, leaving all options
+    /// at their defaults.
+    pub fn new(branch_id: StackId, branch_tree: git2::Oid, approach: ResolutionApproach) -> Self {
+        Self {
+            branch_id,
+            branch_tree,
+            approach,
+            autosquash: false,
+            rebase_onto: None,
+            resolved_tree: None,
+            merge_message_template: None,
+            deterministic_time: false,
+            generate_missing_change_ids: false,
+            preserve_committer_date: false,
+            resign_commits: false,
+        }
+    }
+
+    /// Returns the resolution for a stack that the user hasn't picked an approach for,
+    /// honouring the stack's preferred integration approach and falling back to a rebase.
+    pub fn default_for_stack(stack: &Stack) -> Self {
+        Self::new(
+            stack.id,
+            stack.tree,
+            stack
+                .preferred_integration_approach
+                .unwrap_or_default()
+                .into(),
+        )
+    }
+
+    /// Returns a resolution for each stack in `statuses` according to `preset`, along with
+    /// the conflict preferences for the files that conflict in any of the stacks, to be set
+    /// with [`UpstreamIntegrationContext::with_conflict_preferences`].
+    pub fn from_preset(
+        context: &UpstreamIntegrationContext,
+        statuses: &StackStatuses,
+        preset: ResolutionPreset,
+    ) -> Result<(Vec<Self>, HashMap<String, ConflictPreference>)> {
+        let StackStatuses::UpdatesRequired(statuses) = statuses else {
+            return Ok((vec![], HashMap::new()));
+        };
+
+        let mut resolutions = vec![];
+        for (stack_id, status) in statuses {
+            let stack = context
+                .original_branch(*stack_id)
+                .context("Failed to find virtual branch")?;
+            let mut resolution = Resolution::default_for_stack(stack);
+            if status.is_integrated() {
+                resolution.approach = match preset {
+                    ResolutionPreset::PreferUpstream => ResolutionApproach::Delete,
+                    ResolutionPreset::PreferLocal | ResolutionPreset::Interactive => {
+                        ResolutionApproach::Unapply
+                    }
+                };
+            } else if preset != ResolutionPreset::Interactive
+                || !status.resolution_acceptable(&resolution.approach)
+            {
+                resolution.approach = ResolutionApproach::Rebase;
+            }
+            resolutions.push(resolution);
+        }
+
+        let preference = match preset {
+            ResolutionPreset::PreferUpstream => ConflictPreference::Theirs,
+            ResolutionPreset::PreferLocal => ConflictPreference::Ours,
+            ResolutionPreset::Interactive => return Ok((resolutions, HashMap::new())),
+        };
+        let conflict_preferences = all_conflicting_paths(context)?
+            .into_iter()
+            .map(|path| (path, preference))
+            .collect();
+
+        Ok((resolutions, conflict_preferences))
+    }
+}
+
+/// The trailer with which the head commit of a stack can pick the approach of its
+/// default resolution, e.g. `gitbutler-integrate: rebase`.
+pub const INTEGRATE_TRAILER: &str = "gitbutler-integrate";
+
+/// Returns the [default resolutions](Resolution::default_for_stack) of the stacks in the
+/// workspace, leaving out the stacks that are excluded from integration or based on
+/// another target, but not those a partial integration left behind. A stack whose head
+/// commit has an [`INTEGRATE_TRAILER`] gets the approach it names instead, and naming an
+/// approach that doesn't exist is an error.
+pub fn default_resolutions(context: &UpstreamIntegrationContext) -> Result<Vec<Resolution>> {
+    context
+        .stacks_in_workspace
+        .iter()
+        .filter(|stack| {
+            context.is_pending(stack)
+                || (!stack.integration_excluded && context.is_based_on_target(stack))
+        })
+        .map(|stack| {
+            let mut resolution = Resolution::default_for_stack(stack);
+            let head = context.repository.find_commit(stack.head())?;
+            if let Some(approach) = trailer_approach(&head)? {
+                resolution.approach = approach;
+            }
+            Ok(resolution)
+        })
+        .collect()
+}
+
+/// Returns the approach named by the last [`INTEGRATE_TRAILER`] of the message of `commit`,
+/// if any. Only approaches that take no arguments can be named.
+fn trailer_approach(commit: &git2::Commit) -> Result<Option<ResolutionApproach>> {
+    let Some(trailers) = commit
+        .message()
+        .and_then(|message| git2::message_trailers_strs(message).ok())
+    else {
+        return Ok(None);
+    };
+    let Some((_, value)) = trailers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case(INTEGRATE_TRAILER))
+        .last()
+    else {
+        return Ok(None);
+    };
+    let approach = match value.trim().to_ascii_lowercase().as_str() {
+        "rebase" => ResolutionApproach::Rebase,
+        "merge" => ResolutionApproach::Merge,
+        "unapply" => ResolutionApproach::Unapply,
+        "delete" => ResolutionApproach::Delete,
+        "rebase-commits-only" => ResolutionApproach::RebaseCommitsOnly,
+        "merge-into-target" => ResolutionApproach::MergeIntoTarget,
+        _ => bail!(
+            "Commit {} names an unknown approach in its {} trailer: {}",
+            commit.id(),
+            INTEGRATE_TRAILER,
+            value
+        ),
+    };
+    Ok(Some(approach))
+}
+
+/// What integrating upstream did to each stack.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrationOutcome {
+    /// The stacks that got updated to the new target.
+    pub integrated: Vec<StackId>,
+    /// The approach each of the integrated stacks was integrated with.
+    pub approaches: Vec<IntegratedStack>,
+    pub unapplied: Vec<StackId>,
+    pub deleted: Vec<StackId>,
+    /// The stacks that were left exactly as they were, as there was nothing to integrate.
+    pub unchanged: Vec<StackId>,
+    /// The integrated stacks that ended up with conflicted commits, and the stacks whose
+    /// integration tip is conflicted.
+    pub conflicted: Vec<StackId>,
+    /// The uncommitted changes that were set aside by a clean integration.
+    pub stashed: Vec<StashedTree>,
+    /// The merge commits of the stacks that were merged into the new target.
+    pub integration_tips: Vec<IntegrationTip>,
+    /// The files that only conflicted because of whitespace, and were merged by taking
+    /// the other side, see [`UpstreamIntegrationContext::with_ignore_whitespace`].
+    pub whitespace_resolved: Vec<WhitespaceResolved>,
+}
+
+/// How a stack was integrated.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegratedStack {
+    pub stack_id: StackId,
+    pub approach: ResolutionApproach,
+}
+
+/// A merge commit of a stack into the new target, with the new target as first parent.
+/// Once applied, the tip is kept alive by the reference named by [`integration_tip_refname`].
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrationTip {
+    pub stack_id: StackId,
+    #[serde(with = "gitbutler_serde::oid")]
+    pub tip: git2::Oid,
+}
+
+/// Returns the name of the reference that points to the [`IntegrationTip`] of `stack_id`.
+pub fn integration_tip_refname(stack_id: StackId) -> String {
+    format!("refs/gitbutler/integration-tips/{}", stack_id)
+}
+
+/// Returns the name of the reference that points to the [`StashedTree`] of `stack_id`.
+pub fn stash_refname(stack_id: StackId) -> String {
+    format!("refs/gitbutler/stash/{}", stack_id)
+}
+
+/// The files of a stack whose conflicts with the new target were only in whitespace.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WhitespaceResolved {
+    pub stack_id: StackId,
+    pub paths: Vec<String>,
+}
+
+/// Uncommitted changes of a stack that would have conflicted with the new target, and
+/// were set aside instead of being committed as a conflict. Once applied, the tree is
+/// kept alive by the reference named by [`stash_refname`].
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StashedTree {
+    pub stack_id: StackId,
+    /// The tree of the stack before the integration.
+    #[serde(with = "gitbutler_serde::oid")]
+    pub tree: git2::Oid,
+}
+
+impl IntegrationOutcome {
+    /// Returns a one line summary for the status line, e.g. "3 rebased, 1 merged, 1 conflicted".
+    /// Integrated stacks without a recorded approach are counted as integrated.
+    pub fn summary_line(&self) -> String {
+        let (mut rebased, mut merged, mut integrated) = (0, 0, 0);
+        for stack_id in &self.integrated {
+            match self
+                .approaches
+                .iter()
+                .find(|integrated_stack| integrated_stack.stack_id == *stack_id)
+                .map(|integrated_stack| &integrated_stack.approach)
+            {
+                Some(ResolutionApproach::Merge) => merged += 1,
+                Some(approach) if approach.rewrites_history() => rebased += 1,
+                _ => integrated += 1,
+            }
+        }
+        let counts = [
+            (rebased, "rebased"),
+            (merged, "merged"),
+            (integrated, "integrated"),
+            (self.conflicted.len(), "conflicted"),
+            (self.unapplied.len(), "unapplied"),
+            (self.deleted.len(), "deleted"),
+            (self.unchanged.len(), "unchanged"),
+            (self.stashed.len(), "stashed"),
+            (self.integration_tips.len(), "merged into the target"),
+        ];
+        let parts = counts
+            .into_iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, outcome)| format!("{} {}", count, outcome))
+            .collect::<Vec<_>>();
+        if parts.is_empty() {
+            "nothing to integrate".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+
+    fn from_results(
+        repository: &git2::Repository,
+        new_target: git2::Oid,
+        integration_results: &[(StackId, IntegrationResult)],
+    ) -> Result<Self> {
+        let mut outcome = Self::default();
+        for (stack_id, integration_result) in integration_results {
+            match integration_result {
+                IntegrationResult::UpdatedObjects {
+                    head, stashed_tree, ..
+                } => {
+                    outcome.integrated.push(*stack_id);
+                    if let Some(tree) = stashed_tree {
+                        outcome.stashed.push(StashedTree {
+                            stack_id: *stack_id,
+                            tree: *tree,
+                        });
+                    }
+                    if repository
+                        .log(*head, LogUntil::Commit(new_target), false)?
+                        .iter()
+                        .any(|commit| commit.is_conflicted())
+                    {
+                        outcome.conflicted.push(*stack_id);
+                    }
+                }
+                IntegrationResult::IntegrationTip { tip } => {
+                    outcome.integration_tips.push(IntegrationTip {
+                        stack_id: *stack_id,
+                        tip: *tip,
+                    });
+                    if repository.find_commit(*tip)?.is_conflicted() {
+                        outcome.conflicted.push(*stack_id);
+                    }
+                }
+                IntegrationResult::UnapplyBranch => outcome.unapplied.push(*stack_id),
+                IntegrationResult::DeleteBranch => outcome.deleted.push(*stack_id),
+                IntegrationResult::NoOp => outcome.unchanged.push(*stack_id),
+            }
+        }
+        Ok(outcome)
+    }
+
+    /// Records the approach of `resolutions` for each of the integrated stacks.
+    fn record_approaches(&mut self, resolutions: &[Resolution]) {
+        self.approaches = self
+            .integrated
+            .iter()
+            .filter_map(|stack_id| {
+                resolutions
+                    .iter()
+                    .find(|resolution| resolution.branch_id == *stack_id)
+            })
+            .map(|resolution| IntegratedStack {
+                stack_id: resolution.branch_id,
+                approach: resolution.approach.clone(),
+            })
+            .collect();
+    }
+}
+
+/// The heads and trees of the stacks before an integration was applied.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrationSnapshot {
+    /// The default target before the integration.
+    #[serde(with = "gitbutler_serde::oid")]
+    pub target_sha: git2::Oid,
+    pub stacks: Vec<StackSnapshot>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StackSnapshot {
+    pub id: StackId,
+    #[serde(with = "gitbutler_serde::oid")]
+    pub head: git2::Oid,
+    #[serde(with = "gitbutler_serde::oid")]
+    pub tree: git2::Oid,
+    /// The whole stack, with all of its branches including the archived ones, and
+    /// whether it was in the workspace. This is what gets restored, so stacks that the
+    /// integration deleted or unapplied come back as they were.
+    pub stack: Stack,
+}
+
+impl From<&Stack> for StackSnapshot {
+    fn from(stack: &Stack) -> Self {
+        StackSnapshot {
+            id: stack.id,
+            head: stack.head(),
+            tree: stack.tree,
+            stack: stack.clone(),
+        }
+    }
+}
+
+/// An integration that was worked out ahead of time, so it can be reviewed, stored,
+/// and applied later with [`apply_plan`].
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrationPlan {
+    /// The default target the plan was built against.
+    #[serde(with = "gitbutler_serde::oid")]
+    pub old_target: git2::Oid,
+    /// The commit the default target is moved to.
+    #[serde(with = "gitbutler_serde::oid")]
+    pub new_target: git2::Oid,
+    pub resolutions: Vec<Resolution>,
+    pub base_branch_resolution_approach: Option<BaseBranchResolutionApproach>,
+    /// What applying the plan is expected to do.
+    pub expected_outcome: IntegrationOutcome,
+}
+
+/// An integration that is in progress, e.g. while the user is resolving conflicts.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IntegrationState {
+    pub snapshot: IntegrationSnapshot,
+    /// The stacks that still have conflicts to resolve.
+    pub conflicted_stack_ids: Vec<StackId>,
+    /// How the conflicts were resolved so far.
+    #[serde(default)]
+    pub journal: ResolutionJournal,
+}
+
+/// A record of every conflict resolved with [`apply_file_resolution`], in order,
+/// so it can be audited or replayed.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolutionJournal {
+    pub entries: Vec<ResolutionJournalEntry>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolutionJournalEntry {
+    pub branch_id: StackId,
+    pub path: String,
+    pub choice: RecordedChoice,
+    /// When the conflict was resolved, in milliseconds since the unix epoch.
+    pub timestamp_ms: i64,
+}
+
+impl ResolutionJournal {
+    fn record(&mut self, branch_id: StackId, path: &str, choice: RecordedChoice) {
+        self.entries.push(ResolutionJournalEntry {
+            branch_id,
+            path: path.to_string(),
+            choice,
+            timestamp_ms: now_since_unix_epoch_ms(),
+        });
+    }
+}
+
+const INTEGRATION_STATE_FILE: &str = "integration.toml";
+
+/// Persists the state of an in-progress integration in the project's GitButler directory,
+/// so a later session can resume it.
+pub fn save_integration_state<P: AsRef<Path>>(gb_dir: P, state: &IntegrationState) -> Result<()> {
+    gitbutler_fs::write(
+        gb_dir.as_ref().join(INTEGRATION_STATE_FILE),
+        toml::to_string(state)?,
+    )
+}
+
+/// Reads the state of an in-progress integration, or `None` if there is none.
+pub fn load_integration_state<P: AsRef<Path>>(gb_dir: P) -> Result<Option<IntegrationState>> {
+    let path = gb_dir.as_ref().join(INTEGRATION_STATE_FILE);
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let state =
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(Some(state))
 }
 
-enum IntegrationResult {
-    UpdatedObjects { head: git2::Oid, tree: git2::Oid },
+/// Removes the state of an in-progress integration, if any.
+pub fn clear_integration_state<P: AsRef<Path>>(gb_dir: P) -> Result<()> {
+    match std::fs::remove_file(gb_dir.as_ref().join(INTEGRATION_STATE_FILE)) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// What integrating a stack does to it.
+#[derive(PartialEq, Debug)]
+pub enum IntegrationResult {
+    UpdatedObjects {
+        head: git2::Oid,
+        tree: git2::Oid,
+        /// Maps the commits that were rebased to the commits they were rewritten to.
+        rewritten_commits: HashMap<git2::Oid, git2::Oid>,
+        /// The tree of uncommitted changes that was left out of the stack to keep it clean.
+        stashed_tree: Option<git2::Oid>,
+    },
+    /// A merge commit of the branch into the new target, leaving the branch as it is.
+    IntegrationTip {
+        tip: git2::Oid,
+    },
     UnapplyBranch,
     DeleteBranch,
+    /// The resolution would leave the branch exactly where it is.
+    NoOp,
+}
+
+impl IntegrationResult {
+    /// Returns [`IntegrationResult::NoOp`] if the new head and tree match the stack's current ones.
+    fn updated_objects(
+        stack: &Stack,
+        head: git2::Oid,
+        tree: git2::Oid,
+        rewritten_commits: HashMap<git2::Oid, git2::Oid>,
+    ) -> Self {
+        if head == stack.head() && tree == stack.tree {
+            IntegrationResult::NoOp
+        } else {
+            IntegrationResult::UpdatedObjects {
+                head,
+                tree,
+                rewritten_commits,
+                stashed_tree: None,
+            }
+        }
+    }
+}
+
+/// Remembers which commits are ancestors of which for the duration of a single
+/// operation, as the same pairs get checked again for every stack and resolution.
+///
+/// Entries are keyed by commit ids, which can't change their ancestry, so moving
+/// references never makes them stale. The cache lives in the context, which is
+/// created anew for each operation, and can be [cleared](Self::clear) explicitly.
+#[derive(Debug, Default)]
+pub struct AncestryCache {
+    entries: RefCell<HashMap<(git2::Oid, git2::Oid), bool>>,
+}
+
+impl AncestryCache {
+    /// Returns whether `ancestor` is `descendant` itself or one of its ancestors.
+    pub fn is_ancestor(
+        &self,
+        repository: &git2::Repository,
+        ancestor: git2::Oid,
+        descendant: git2::Oid,
+    ) -> Result<bool> {
+        if ancestor == descendant {
+            return Ok(true);
+        }
+        if let Some(is_ancestor) = self.entries.borrow().get(&(ancestor, descendant)) {
+            return Ok(*is_ancestor);
+        }
+
+        let is_ancestor = repository.graph_descendant_of(descendant, ancestor)?;
+        self.entries
+            .borrow_mut()
+            .insert((ancestor, descendant), is_ancestor);
+        Ok(is_ancestor)
+    }
+
+    /// Forgets all remembered results.
+    pub fn clear(&self) {
+        self.entries.borrow_mut().clear();
+    }
 }
 
 pub struct UpstreamIntegrationContext<'a> {
     _permission: Option<&'a mut WorktreeWritePermission>,
     repository: &'a git2::Repository,
     stacks_in_workspace: Vec<Stack>,
+    stacks_not_in_workspace: Vec<Stack>,
     new_target: git2::Commit<'a>,
     target: Target,
+    merge_base_strategy: MergeBaseStrategy,
+    message_rewriter: Option<MessageRewriter<'a>>,
+    max_commits_to_replay: usize,
+    conflict_preferences: HashMap<String, ConflictPreference>,
+    force_clean: bool,
+    excluded_paths: Vec<String>,
+    worktree_changes: bool,
+    skip_empty: bool,
+    ignore_whitespace: bool,
+    conflict_labels: ConflictLabels,
+    ancestry: AncestryCache,
+    /// The files of each stack that the last integration merged by ignoring whitespace.
+    whitespace_resolved: RefCell<BTreeMap<StackId, BTreeSet<String>>>,
 }
 
+/// How many commits a single stack may have to replay before we assume the
+/// target is misconfigured, rather than walking the entire history.
+pub const DEFAULT_MAX_COMMITS_TO_REPLAY: usize = 10_000;
+
+/// Transforms the message of a commit replayed by a rebase, given the message
+/// and the id of the original commit. It is shared with the contexts derived for
+/// other targets.
+pub type MessageRewriter<'a> = Rc<dyn Fn(&str, git2::Oid) -> String + 'a>;
+
 impl<'a> UpstreamIntegrationContext<'a> {
     pub(crate) fn open(
         command_context: &'a CommandContext,
         target_commit_oid: Option<git2::Oid>,
         permission: &'a mut WorktreeWritePermission,
+    ) -> Result<Self> {
+        Self::open_with_merge_base_strategy(
+            command_context,
+            target_commit_oid,
+            MergeBaseStrategy::default(),
+            permission,
+        )
+    }
+
+    /// Opens a context, failing with [`UnrelatedTargets`] if the old and new target
+    /// share no history and `merge_base_strategy` doesn't allow for that.
+    pub fn open_with_merge_base_strategy(
+        command_context: &'a CommandContext,
+        target_commit_oid: Option<git2::Oid>,
+        merge_base_strategy: MergeBaseStrategy,
+        permission: &'a mut WorktreeWritePermission,
     ) -> Result<Self> {
         let virtual_branches_handle = command_context.project().virtual_branches();
         let target = virtual_branches_handle.get_default_target()?;
         let repository = command_context.repo();
         let target_branch = repository
             .maybe_find_branch_by_refname(&target.branch.clone().into())?
-            .ok_or(anyhow!("Branch not found"))?;
+            .ok_or_else(|| TargetBranchMissing(target.branch.to_string()))?;
 
         let new_target = target_commit_oid.map_or_else(
             || target_branch.get().peel_to_commit(),
             |oid| repository.find_commit(oid),
         )?;
 
+        merge_base_strategy.merge_base(repository, target.sha, new_target.id())?;
+
         let stacks_in_workspace = virtual_branches_handle.list_stacks_in_workspace()?;
+        let stacks_not_in_workspace = virtual_branches_handle
+            .list_all_stacks()?
+            .into_iter()
+            .filter(|stack| !stack.in_workspace)
+            .collect();
 
         Ok(Self {
             _permission: Some(permission),
@@ -180,42 +1134,349 @@ impl<'a> UpstreamIntegrationContext<'a> {
             new_target,
             target: target.clone(),
             stacks_in_workspace,
+            stacks_not_in_workspace,
+            merge_base_strategy,
+            message_rewriter: None,
+            max_commits_to_replay: DEFAULT_MAX_COMMITS_TO_REPLAY,
+            conflict_preferences: HashMap::new(),
+            force_clean: false,
+            excluded_paths: vec![],
+            worktree_changes: false,
+            skip_empty: false,
+            ignore_whitespace: false,
+            conflict_labels: ConflictLabels::default(),
+            ancestry: AncestryCache::default(),
+            whitespace_resolved: RefCell::default(),
         })
     }
-}
 
-/// Returns the status of a stack
-/// Takes both a gix and git2 repository. The git2 repository can't be in
-/// memory as the gix repository needs to be able to access those commits
-fn get_stack_status(
-    repository: &git2::Repository,
+    /// Opens a context that integrates against the commit a GitButler-managed
+    /// reference currently points to, rather than against the default target branch.
+    pub fn open_with_patch_reference(
+        command_context: &'a CommandContext,
+        permission: &'a mut WorktreeWritePermission,
+        reference: &StackBranch,
+    ) -> Result<Self> {
+        let virtual_branches_handle = command_context.project().virtual_branches();
+        let new_target = resolve_patch_reference(
+            command_context.repo(),
+            &virtual_branches_handle.get_default_target()?,
+            &virtual_branches_handle.list_stacks_in_workspace()?,
+            reference,
+        )?;
+        Self::open(command_context, Some(new_target), permission)
+    }
+
+    /// Opens a context that integrates against `refs/remotes/{remote}/{branch}`, so the
+    /// impact of a fetch can be seen before the target branch gets updated.
+    pub fn open_against_remote(
+        command_context: &'a CommandContext,
+        permission: &'a mut WorktreeWritePermission,
+        remote: &str,
+        branch: &str,
+    ) -> Result<Self> {
+        let refname = format!("refs/remotes/{}/{}", remote, branch);
+        let new_target = command_context
+            .repo()
+            .find_reference(&refname)
+            .and_then(|reference| reference.peel_to_commit())
+            .with_context(|| format!("Failed to find the remote branch {}", refname))?;
+        Self::open(command_context, Some(new_target.id()), permission)
+    }
+
+    /// Returns `true` if the context says how to resolve some conflicts, i.e. it has
+    /// conflict preferences, excluded paths or ignores whitespace.
+    fn resolves_conflicts(&self) -> bool {
+        !self.conflict_preferences.is_empty()
+            || !self.excluded_paths.is_empty()
+            || self.ignore_whitespace
+    }
+
+    /// Returns `true` if `path` matches one of the excluded paths.
+    fn is_excluded(&self, path: &str) -> bool {
+        self.excluded_paths
+            .iter()
+            .any(|pattern| path_matches(pattern, path))
+    }
+
+    /// Returns the files of each stack that the last integration merged by ignoring whitespace.
+    fn whitespace_resolved(&self) -> Vec<WhitespaceResolved> {
+        self.whitespace_resolved
+            .borrow()
+            .iter()
+            .map(|(stack_id, paths)| WhitespaceResolved {
+                stack_id: *stack_id,
+                paths: paths.iter().cloned().collect(),
+            })
+            .collect()
+    }
+
+    /// Returns the options for merging files while analysing conflicts.
+    fn merge_options(&self) -> git2::MergeOptions {
+        let mut options = git2::MergeOptions::new();
+        options
+            .ignore_whitespace_change(self.ignore_whitespace)
+            .ignore_whitespace_eol(self.ignore_whitespace);
+        options
+    }
+
+    /// Returns the commit the stacks get integrated with.
+    pub fn new_target_id(&self) -> git2::Oid {
+        self.new_target.id()
+    }
+
+    /// Returns the commit the target pointed to before the integration.
+    pub fn old_target_id(&self) -> git2::Oid {
+        self.target.sha
+    }
+
+    /// Returns the tree to merge the stacks with the new target on, see
+    /// [`MergeBaseStrategy::merge_base_tree`].
+    fn merge_base_tree(&self) -> Result<git2::Oid> {
+        self.merge_base_strategy.merge_base_tree(
+            self.repository,
+            self.target.sha,
+            self.new_target.id(),
+        )
+    }
+
+    /// Returns the stack with the given id as it was before the integration.
+    pub fn original_branch(&self, id: StackId) -> Option<&Stack> {
+        self.stacks_in_workspace.iter().find(|stack| stack.id == id)
+    }
+
+    /// Returns `true` if `stack` is [based on](Stack::integration_target) the target of
+    /// this context, which is the case for stacks without a target of their own, and
+    /// isn't [left behind](Stack::integration_base) on an older commit of it.
+    fn is_based_on_target(&self, stack: &Stack) -> bool {
+        stack
+            .integration_target
+            .as_ref()
+            .map_or(true, |branch| *branch == self.target.branch)
+            && stack
+                .integration_base
+                .map_or(true, |base| base == self.target.sha)
+    }
+
+    /// Returns `true` if a partial integration left `stack` behind on an older commit of
+    /// the target of this context.
+    fn is_pending(&self, stack: &Stack) -> bool {
+        stack
+            .integration_base
+            .is_some_and(|base| base != self.target.sha)
+            && !stack.integration_excluded
+            && stack
+                .integration_target
+                .as_ref()
+                .map_or(true, |branch| *branch == self.target.branch)
+    }
+
+    /// Returns a context for integrating `stack` with the new target from the commit of the
+    /// target a partial integration left it on, or `None` if it [isn't pending](Self::is_pending).
+    fn for_pending_stack(&self, stack: &Stack) -> Option<UpstreamIntegrationContext<'a>> {
+        let base = stack.integration_base.filter(|_| self.is_pending(stack))?;
+        Some(self.for_target(
+            Target {
+                sha: base,
+                ..self.target.clone()
+            },
+            self.new_target.clone(),
+            vec![stack.clone()],
+        ))
+    }
+
+    /// Returns a context for integrating `stacks` with `new_target` instead, as they are
+    /// based on `target`. All options are kept.
+    fn for_target(
+        &self,
+        target: Target,
+        new_target: git2::Commit<'a>,
+        stacks: Vec<Stack>,
+    ) -> UpstreamIntegrationContext<'a> {
+        UpstreamIntegrationContext {
+            _permission: None,
+            repository: self.repository,
+            stacks_in_workspace: stacks,
+            stacks_not_in_workspace: vec![],
+            new_target,
+            target,
+            merge_base_strategy: self.merge_base_strategy,
+            message_rewriter: self.message_rewriter.clone(),
+            max_commits_to_replay: self.max_commits_to_replay,
+            conflict_preferences: self.conflict_preferences.clone(),
+            force_clean: self.force_clean,
+            excluded_paths: self.excluded_paths.clone(),
+            worktree_changes: self.worktree_changes,
+            skip_empty: self.skip_empty,
+            ignore_whitespace: self.ignore_whitespace,
+            conflict_labels: self.conflict_labels.clone(),
+            ancestry: AncestryCache::default(),
+            whitespace_resolved: RefCell::default(),
+        }
+    }
+
+    /// Captures the heads and trees of the stacks, both in the workspace and out of it,
+    /// as they are before the integration is applied.
+    pub fn snapshot(&self) -> IntegrationSnapshot {
+        IntegrationSnapshot {
+            target_sha: self.target.sha,
+            stacks: self
+                .stacks_in_workspace
+                .iter()
+                .chain(&self.stacks_not_in_workspace)
+                .map(StackSnapshot::from)
+                .collect(),
+        }
+    }
+
+    /// Sets a function that rewrites the message of each commit that gets replayed
+    /// by a rebase, e.g. to add a trailer. By default messages are kept as they are.
+    pub fn with_message_rewriter(
+        mut self,
+        message_rewriter: impl Fn(&str, git2::Oid) -> String + 'a,
+    ) -> Self {
+        self.message_rewriter = Some(Rc::new(message_rewriter));
+        self
+    }
+
+    /// Sets which side to take for the given paths when they conflict, both when
+    /// integrating and in [`apply_conflict_preferences`].
+    pub fn with_conflict_preferences(
+        mut self,
+        conflict_preferences: HashMap<String, ConflictPreference>,
+    ) -> Self {
+        self.conflict_preferences = conflict_preferences;
+        self
+    }
+
+    /// Sets how many commits a stack may have to replay before the rebase errors.
+    pub fn with_max_commits_to_replay(mut self, max_commits_to_replay: usize) -> Self {
+        self.max_commits_to_replay = max_commits_to_replay;
+        self
+    }
+
+    /// Makes sure no conflicted commits end up in the stacks. Uncommitted changes that
+    /// would conflict are stashed instead, see [`IntegrationOutcome::stashed`], and
+    /// stacks whose commits would conflict fail the integration.
+    pub fn with_force_clean(mut self, force_clean: bool) -> Self {
+        self.force_clean = force_clean;
+        self
+    }
+
+    /// Sets paths that always keep the stack's version when they conflict with the new
+    /// target, e.g. generated files. A pattern ending in `/` matches everything in that
+    /// directory, and a pattern starting with `*` matches any path with that suffix.
+    pub fn with_excluded_paths(mut self, excluded_paths: Vec<String>) -> Self {
+        self.excluded_paths = excluded_paths;
+        self
+    }
+
+    /// Makes the statuses take changes on disk into account that aren't captured in the
+    /// stack trees yet. Like the next capture would, they are attributed to the stack that
+    /// is selected for changes.
+    pub fn with_worktree_changes(mut self, worktree_changes: bool) -> Self {
+        self.worktree_changes = worktree_changes;
+        self
+    }
+
+    /// Leaves branches without commits of their own out of the statuses, as there is
+    /// nothing to decide for them. The statuses are reported as up to date when all that
+    /// remains are integrated branches with untouched trees.
+    ///
+    /// This only affects the statuses, so resolutions still have to cover every stack.
+    pub fn with_skip_empty(mut self, skip_empty: bool) -> Self {
+        self.skip_empty = skip_empty;
+        self
+    }
+
+    /// Makes the conflict analysis treat changes that only differ in whitespace, including
+    /// line endings, as the same, so they don't conflict. Use [`analyze_conflicts`] to see
+    /// which conflicts this resolved.
+    ///
+    /// The statuses and the integration itself merge whole files instead: a file whose
+    /// changes on one side only touch whitespace takes the other side's version. These
+    /// files are listed in [`IntegrationOutcome::whitespace_resolved`].
+    pub fn with_ignore_whitespace(mut self, ignore_whitespace: bool) -> Self {
+        self.ignore_whitespace = ignore_whitespace;
+        self
+    }
+
+    /// Sets the labels of the conflict markers that [`merge_with_conflict_markers`] and
+    /// the merges of the integration write, e.g. the branch name and "upstream".
+    pub fn with_conflict_labels(mut self, conflict_labels: ConflictLabels) -> Self {
+        self.conflict_labels = conflict_labels;
+        self
+    }
+
+    /// Sets what to do when the old and new target share no common ancestor.
+    pub fn with_merge_base_strategy(mut self, merge_base_strategy: MergeBaseStrategy) -> Self {
+        self.merge_base_strategy = merge_base_strategy;
+        self
+    }
+}
+
+/// Resolves the commit a reference points to. Change ids are looked up within
+/// the stack the reference belongs to.
+fn resolve_patch_reference(
+    repository: &git2::Repository,
+    target: &Target,
+    stacks: &[Stack],
+    reference: &StackBranch,
+) -> Result<git2::Oid> {
+    match &reference.head {
+        CommitOrChangeId::CommitId(id) => Ok(repository.find_commit(id.parse()?)?.id()),
+        CommitOrChangeId::ChangeId(_) => {
+            let stack = stacks
+                .iter()
+                .find(|stack| stack.heads.iter().any(|head| head.name == reference.name))
+                .with_context(|| {
+                    format!(
+                        "The reference {} does not belong to any stack in the workspace",
+                        reference.name
+                    )
+                })?;
+            let stack_context = StackContext::new(repository, target.clone());
+            reference.head_oid(&stack_context, stack)
+        }
+    }
+}
+
+/// Returns the name of the remote the default target branch belongs to,
+/// e.g. `upstream` for `refs/remotes/upstream/main`.
+pub fn default_target_remote(command_context: &CommandContext) -> Result<String> {
+    let target = command_context
+        .project()
+        .virtual_branches()
+        .get_default_target()?;
+    Ok(target.branch.remote().to_string())
+}
+
+/// Returns the status of a stack
+/// Takes both a gix and git2 repository. The git2 repository can't be in
+/// memory as the gix repository needs to be able to access those commits
+fn get_stack_status(
+    repository: &git2::Repository,
     gix_repository: &gix::Repository,
     target: Target,
-    new_target_commit_id: gix::ObjectId,
+    target_state: &TargetState,
     stack: &Stack,
+    policy: Option<&dyn ConflictPolicy>,
 ) -> Result<StackStatus> {
     let cache = gix_repository.commit_graph_if_enabled()?;
     let mut graph = gix_repository.revision_graph(cache.as_ref());
-    let upstream_commit_oids = repository.l(
-        gix_to_git2_oid(new_target_commit_id),
-        LogUntil::Commit(target.sha),
-        true,
-    )?;
-    let new_target_tree_id = gix_repository
-        .find_commit(new_target_commit_id)?
-        .tree_id()?;
+    let old_target = target.sha;
     let mut check_commit = IsCommitIntegrated::new_basic(
         gix_repository,
         repository,
         &mut graph,
         git2_to_gix_object_id(target.sha),
-        new_target_tree_id.detach(),
-        upstream_commit_oids,
+        target_state.new_target_tree_id,
+        target_state.upstream_commits.as_slice(),
     );
 
     let mut unintegrated_branch_found = false;
 
-    let mut last_head: git2::Oid = gix_to_git2_oid(new_target_commit_id);
+    let mut last_head: git2::Oid = gix_to_git2_oid(target_state.new_target_commit_id);
 
     let mut branch_statuses: Vec<NameAndStatus> = vec![];
 
@@ -226,6 +1487,13 @@ fn get_stack_status(
             continue;
         }
 
+        let commit_counts = CommitCounts::compute(
+            repository,
+            branch.head_oid(&stack_context, stack)?,
+            old_target,
+            &target_state.upstream_commits,
+        )?;
+
         // If an integrated branch has been found, there is no need to bother
         // with subsequent branches.
         if !unintegrated_branch_found
@@ -234,6 +1502,10 @@ fn get_stack_status(
             branch_statuses.push(NameAndStatus {
                 name: branch.name.clone(),
                 status: BranchStatus::Integrated,
+                commit_counts: CommitCounts {
+                    commits_to_replay: 0,
+                    ..commit_counts
+                },
             });
 
             continue;
@@ -252,6 +1524,7 @@ fn get_stack_status(
             branch_statuses.push(NameAndStatus {
                 name: branch.name.clone(),
                 status: BranchStatus::Empty,
+                commit_counts,
             });
 
             continue;
@@ -266,7 +1539,13 @@ fn get_stack_status(
 
         let rebase_base = last_head;
 
-        let new_head_oid = cherry_rebase_group(repository, rebase_base, &local_commit_ids, false)?;
+        let new_head_oid = cherry_rebase_group_with_policy(
+            repository,
+            rebase_base,
+            &local_commit_ids,
+            false,
+            policy,
+        )?;
         let rebased_commits = repository.log(new_head_oid, LogUntil::Commit(rebase_base), false)?;
 
         last_head = new_head_oid;
@@ -280,35 +1559,22 @@ fn get_stack_status(
             } else {
                 BranchStatus::SaflyUpdatable
             },
+            commit_counts,
         });
     }
 
-    let stack_head = repository.find_commit(stack.head())?;
-
-    let tree_status = if stack.tree
-        == repository
-            .find_real_tree(&stack_head, Default::default())?
-            .id()
-    {
+    let tree_status = if !branch_has_uncommitted_changes(repository, stack)? {
         TreeStatus::Empty
     } else {
-        let (merge_options_fail_fast, conflict_kind) =
-            gix_repository.merge_options_no_rewrites_fail_fast()?;
-
-        let tree_merge_base = gix_repository
-            .find_commit(new_target_commit_id)?
-            .tree_id()?;
         let tree_id = git2_to_gix_object_id(stack.tree);
         let new_head_commit = repository.find_commit(last_head)?;
-        let tree_conflicted = gix_repository
-            .merge_trees(
-                tree_merge_base,
-                tree_id,
-                git2_to_gix_object_id(new_head_commit.tree_id()),
-                gix_repository.default_merge_labels(),
-                merge_options_fail_fast.clone(),
-            )?
-            .has_unresolved_conflicts(conflict_kind);
+        let tree_conflicted = trees_conflict_with_policy(
+            gix_repository,
+            target_state.new_target_tree_id,
+            git2_to_gix_object_id(new_head_commit.tree_id()),
+            tree_id,
+            policy,
+        )?;
 
         if tree_conflicted {
             TreeStatus::Conflicted
@@ -320,347 +1586,8123 @@ fn get_stack_status(
     StackStatus::create(tree_status, branch_statuses)
 }
 
+/// Returns `true` if the tree of the stack differs from the tree of its head commit, i.e.
+/// the stack has changes that aren't committed yet.
+pub fn branch_has_uncommitted_changes(
+    repository: &git2::Repository,
+    stack: &Stack,
+) -> Result<bool> {
+    let stack_head = repository.find_commit(stack.head())?;
+    Ok(stack.tree
+        != repository
+            .find_real_tree(&stack_head, Default::default())?
+            .id())
+}
+
+/// Returns `true` if merging the trees `ours` and `theirs` on top of `base` conflicts.
+///
+/// The merge stops at the first conflict. Merged files are written to `repository`, so
+/// pass one [with object memory](gix::Repository::with_object_memory) to leave the object
+/// database untouched.
+pub fn trees_conflict(
+    repository: &gix::Repository,
+    base: gix::ObjectId,
+    ours: gix::ObjectId,
+    theirs: gix::ObjectId,
+) -> Result<bool> {
+    let (merge_options_fail_fast, conflict_kind) =
+        repository.merge_options_no_rewrites_fail_fast()?;
+    Ok(repository
+        .merge_trees(
+            base,
+            ours,
+            theirs,
+            repository.default_merge_labels(),
+            merge_options_fail_fast,
+        )?
+        .has_unresolved_conflicts(conflict_kind))
+}
+
+/// Like [`trees_conflict`], but the files that conflict are resolved as `policy` says first.
+fn trees_conflict_with_policy(
+    repository: &gix::Repository,
+    base: gix::ObjectId,
+    ours: gix::ObjectId,
+    theirs: gix::ObjectId,
+    policy: Option<&dyn ConflictPolicy>,
+) -> Result<bool> {
+    if policy.is_none() {
+        return trees_conflict(repository, base, ours, theirs);
+    }
+
+    // Failing fast would stop before the policy got to see all conflicts.
+    let options = repository.merge_options_force_ours()?.with_rewrites(None);
+    Ok(
+        merge_trees_with_policy(repository, base, ours, theirs, options, policy)?
+            .has_unresolved_conflicts(TreatAsUnresolved::forced_resolution()),
+    )
+}
+
+/// Classifies a branch given the trees of the old target, the new target and the branch
+/// head, and `branch_tree`, the tree of the branch once its commits were rebased onto the
+/// new target, which has [conflict entries](ConflictedTreeKey) if the rebase conflicted.
+///
+/// A branch whose rebase conflicts is conflicted, and so is one whose head doesn't merge
+/// cleanly with the new target, though that one can still be rebased. A branch that merges
+/// into exactly the new target is integrated.
+///
+/// This is the tree-level counterpart of the status [`upstream_integration_statuses`]
+/// computes. The latter replays the commits one by one instead, as not everything that
+/// merges cleanly can also be rebased without conflicts.
+pub fn classify_merge(
+    repository: &git2::Repository,
+    old_tree: git2::Oid,
+    new_tree: git2::Oid,
+    head_tree: git2::Oid,
+    branch_tree: git2::Oid,
+) -> Result<BranchStatus> {
+    if head_tree == old_tree {
+        return Ok(BranchStatus::Empty);
+    }
+
+    if repository
+        .find_tree(branch_tree)?
+        .get_name(&ConflictedTreeKey::ConflictFiles)
+        .is_some()
+    {
+        return Ok(BranchStatus::Conflicted { rebasable: false });
+    }
+
+    let mut index = repository.merge_trees(
+        &repository.find_tree(old_tree)?,
+        &repository.find_tree(new_tree)?,
+        &repository.find_tree(head_tree)?,
+        None,
+    )?;
+    if index.has_conflicts() {
+        return Ok(BranchStatus::Conflicted { rebasable: true });
+    }
+
+    if index.write_tree_to(repository)? == new_tree {
+        Ok(BranchStatus::Integrated)
+    } else {
+        Ok(BranchStatus::SaflyUpdatable)
+    }
+}
+
+/// Returns `true` if the new target differs from the current one, or a partial integration
+/// left stacks behind, without computing the status of any stack.
+pub fn needs_integration(context: &UpstreamIntegrationContext) -> bool {
+    context.new_target.id() != context.target.sha
+        || context
+            .stacks_in_workspace
+            .iter()
+            .any(|stack| context.is_pending(stack))
+}
+
+/// Returns the stacks in the workspace whose head moved away from the commit that
+/// was last pushed, for instance because integrating upstream rebased them.
+pub fn branches_needing_push(command_context: &CommandContext) -> Result<Vec<StackId>> {
+    let stacks = command_context
+        .project()
+        .virtual_branches()
+        .list_stacks_in_workspace()?;
+
+    Ok(stacks
+        .into_iter()
+        .filter(|stack| {
+            stack
+                .upstream_head
+                .is_some_and(|upstream_head| upstream_head != stack.head())
+        })
+        .map(|stack| stack.id)
+        .collect())
+}
+
+/// Returns the status of each stack in the workspace, except for the stacks that are
+/// [excluded from integration](Stack::integration_excluded) or
+/// [based on another target](Stack::integration_target).
+/// The stacks a partial integration [left behind](Stack::integration_base) are listed
+/// too, even once the default target is at the new target.
 pub fn upstream_integration_statuses(
     context: &UpstreamIntegrationContext,
 ) -> Result<StackStatuses> {
+    if !needs_integration(context) {
+        return Ok(StackStatuses::UpToDate);
+    };
+
+    let mut statuses = if context.new_target.id() != context.target.sha {
+        statuses_lazy(context)?.collect::<Result<Vec<_>>>()?
+    } else {
+        vec![]
+    };
+    // The stacks a partial integration left behind are integrated from where they are.
+    for stack in &context.stacks_in_workspace {
+        if let Some(pending_context) = context.for_pending_stack(stack) {
+            statuses.extend(statuses_lazy(&pending_context)?.collect::<Result<Vec<_>>>()?);
+        }
+    }
+
+    if context.skip_empty {
+        statuses.retain_mut(|(_, status)| {
+            let has_commits = status
+                .branch_statuses
+                .iter()
+                .any(|branch| branch.status != BranchStatus::Empty);
+            if has_commits {
+                status
+                    .branch_statuses
+                    .retain(|branch| branch.status != BranchStatus::Empty);
+            }
+            has_commits || status.tree_status != TreeStatus::Empty
+        });
+
+        let nothing_to_update = statuses.iter().all(|(_, status)| {
+            status.tree_status == TreeStatus::Empty
+                && status
+                    .branch_statuses
+                    .iter()
+                    .all(|branch| branch.status == BranchStatus::Integrated)
+        });
+        if nothing_to_update {
+            return Ok(StackStatuses::UpToDate);
+        }
+    }
+
+    Ok(StackStatuses::UpdatesRequired(statuses))
+}
+
+/// Returns the stacks that can be integrated without user input, as neither their
+/// branches nor their uncommitted changes conflict with the new target. This is what a
+/// one-click integration would touch, so its size can be shown beforehand.
+pub fn auto_integratable(context: &UpstreamIntegrationContext) -> Result<Vec<StackId>> {
+    let StackStatuses::UpdatesRequired(statuses) = upstream_integration_statuses(context)? else {
+        return Ok(vec![]);
+    };
+    Ok(statuses
+        .into_iter()
+        .filter(|(_, status)| !status.is_conflicted())
+        .map(|(stack_id, _)| stack_id)
+        .collect())
+}
+
+/// Returns the statuses of the stacks in the workspace grouped by the target they are
+/// [based on](Stack::integration_target). Stacks without a target of their own are based
+/// on the target of the context and are integrated with its new target; `other_targets`
+/// lists the other targets along with the commit to integrate for each of them.
+///
+/// Targets without stacks are left out. Fails if a stack is based on a target that
+/// isn't listed.
+pub fn statuses_by_target(
+    context: &UpstreamIntegrationContext,
+    other_targets: &[(Target, git2::Oid)],
+) -> Result<Vec<(RemoteRefname, StackStatuses)>> {
+    let based_on = |stack: &Stack, branch: &RemoteRefname| {
+        stack
+            .integration_target
+            .as_ref()
+            .unwrap_or(&context.target.branch)
+            == branch
+    };
+    if let Some(stack) = context.stacks_in_workspace.iter().find(|stack| {
+        !based_on(stack, &context.target.branch)
+            && !other_targets
+                .iter()
+                .any(|(target, _)| based_on(stack, &target.branch))
+    }) {
+        bail!(
+            "Stack {} is based on an unknown target {}",
+            stack.name,
+            stack
+                .integration_target
+                .as_ref()
+                .unwrap_or(&context.target.branch)
+        );
+    }
+
+    let targets = std::iter::once((context.target.clone(), context.new_target.id()))
+        .chain(other_targets.iter().cloned());
+    let mut statuses = vec![];
+    for (target, new_target) in targets {
+        let stacks = context
+            .stacks_in_workspace
+            .iter()
+            .filter(|stack| based_on(stack, &target.branch))
+            .cloned()
+            .collect::<Vec<_>>();
+        if stacks.is_empty() {
+            continue;
+        }
+        let branch = target.branch.clone();
+        let target_context =
+            context.for_target(target, context.repository.find_commit(new_target)?, stacks);
+        statuses.push((branch, upstream_integration_statuses(&target_context)?));
+    }
+
+    Ok(statuses)
+}
+
+/// Returns the status the unapplied stacks would have against the new target, e.g. to
+/// tell whether re-applying one would conflict. These are kept apart from
+/// [`upstream_integration_statuses`], as integrating upstream leaves them untouched.
+pub fn unapplied_stack_statuses(
+    context: &UpstreamIntegrationContext,
+) -> Result<Vec<(StackId, StackStatus)>> {
+    if !needs_integration(context) {
+        return Ok(vec![]);
+    };
+
+    stack_statuses(context, &context.stacks_not_in_workspace)?.collect()
+}
+
+/// Returns an iterator that computes the status of one stack in the workspace at a time,
+/// in the order [`upstream_integration_statuses`] lists them, so they can be shown as
+/// they come in and the remaining ones can be skipped.
+///
+/// Unlike [`upstream_integration_statuses`], this doesn't check whether the workspace is
+/// up to date and doesn't leave out empty branches.
+pub fn statuses_lazy<'a>(
+    context: &'a UpstreamIntegrationContext,
+) -> Result<impl Iterator<Item = Result<(StackId, StackStatus)>> + 'a> {
+    stack_statuses(context, &context.stacks_in_workspace)
+}
+
+fn stack_statuses<'a>(
+    context: &'a UpstreamIntegrationContext,
+    stacks: &'a [Stack],
+) -> Result<impl Iterator<Item = Result<(StackId, StackStatus)>> + 'a> {
+    let repository: &'a git2::Repository = context.repository;
     let UpstreamIntegrationContext {
-        repository,
         new_target,
         target,
-        stacks_in_workspace,
+        merge_base_strategy,
         ..
     } = context;
-    let old_target = repository.find_commit(target.sha)?;
 
     let gix_repository = gitbutler_command_context::gix_repository_for_merging(repository.path())?;
     let gix_repository_in_memory = gix_repository.clone().with_object_memory();
 
-    if new_target.id() == old_target.id() {
-        return Ok(StackStatuses::UpToDate);
+    let selected_stack = if context.worktree_changes {
+        selected_stack_with_worktree_changes(context)?
+    } else {
+        None
     };
 
-    let statuses = stacks_in_workspace
+    let target = target.clone();
+    let target_state = TargetState::load(
+        repository,
+        &gix_repository_in_memory,
+        target.sha,
+        git2_to_gix_object_id(new_target.id()),
+        merge_base_strategy,
+    )?;
+    // Without a policy, the merges can stop at the first conflict.
+    let policy = context
+        .resolves_conflicts()
+        .then(|| IntegrationConflictPolicy::for_rebase(context));
+    Ok(stacks
         .iter()
-        .map(|stack| {
+        .filter(|stack| !stack.integration_excluded && context.is_based_on_target(stack))
+        .map(move |stack| {
+            let stack = selected_stack
+                .as_ref()
+                .filter(|selected_stack| selected_stack.id == stack.id)
+                .unwrap_or(stack);
             Ok((
                 stack.id,
                 get_stack_status(
                     repository,
                     &gix_repository_in_memory,
                     target.clone(),
-                    git2_to_gix_object_id(new_target.id()),
+                    &target_state,
                     stack,
+                    policy.as_ref().map(|policy| policy as &dyn ConflictPolicy),
                 )?,
             ))
-        })
-        .collect::<Result<Vec<_>>>()?;
-
-    Ok(StackStatuses::UpdatesRequired(statuses))
+        }))
 }
 
-pub(crate) fn integrate_upstream(
-    command_context: &CommandContext,
-    resolutions: &[Resolution],
-    base_branch_resolution: Option<BaseBranchResolution>,
-    permission: &mut WorktreeWritePermission,
-) -> Result<()> {
-    let (target_commit_oid, base_branch_resolution_approach) = base_branch_resolution
-        .map(|r| (Some(r.target_commit_oid), Some(r.approach)))
-        .unwrap_or((None, None));
+/// Returns the stack that is selected for changes with the changes on disk that aren't
+/// captured in any stack tree yet added to its tree, or `None` if no stack is selected.
+fn selected_stack_with_worktree_changes(
+    context: &UpstreamIntegrationContext,
+) -> Result<Option<Stack>> {
+    let Some(selected_stack) = context
+        .stacks_in_workspace
+        .iter()
+        .filter(|stack| stack.selected_for_changes.is_some())
+        .max_by_key(|stack| stack.selected_for_changes)
+    else {
+        return Ok(None);
+    };
+    let repository = context.repository;
 
-    let context = UpstreamIntegrationContext::open(command_context, target_commit_oid, permission)?;
-    let virtual_branches_state = VirtualBranchesHandle::new(command_context.project().gb_dir());
-    let default_target = virtual_branches_state.get_default_target()?;
+    let old_target_tree = repository.find_commit(context.target.sha)?.tree()?;
+    let mut captured_tree = old_target_tree.clone();
+    for stack in &context.stacks_in_workspace {
+        let mut index = repository.merge_trees(
+            &old_target_tree,
+            &captured_tree,
+            &repository.find_tree(stack.tree)?,
+            None,
+        )?;
+        if index.has_conflicts() {
+            bail!("The stacks in the workspace conflict with each other")
+        }
+        captured_tree = repository.find_tree(index.write_tree_to(repository)?)?;
+    }
 
-    // Ensure resolutions match current statuses
-    {
-        let statuses = upstream_integration_statuses(&context)?;
+    let worktree_tree = repository.create_wd_tree(AUTO_TRACK_LIMIT_BYTES)?;
+    let mut index = repository.merge_trees(
+        &captured_tree,
+        &repository.find_tree(selected_stack.tree)?,
+        &worktree_tree,
+        None,
+    )?;
+    if index.has_conflicts() {
+        bail!(
+            "The changes on disk conflict with the tree of {}",
+            selected_stack.name
+        )
+    }
 
-        let StackStatuses::UpdatesRequired(statuses) = statuses else {
-            bail!("Branches are all up to date")
-        };
+    let mut stack = selected_stack.clone();
+    stack.tree = index.write_tree_to(repository)?;
+    Ok(Some(stack))
+}
 
-        if resolutions.len() != context.stacks_in_workspace.len() {
-            bail!(
-                "Chosen resolutions do not match quantity of applied virtual branches. {:?} {:?}",
-                resolutions,
-                context.stacks_in_workspace
-            )
-        }
+/// Returns what a stack adds on top of the new target, i.e. the diff from the new
+/// target's tree to the stack's tree merged with it. Files that conflict keep the
+/// stack's version in the merged tree, so the diff shows them as the stack has them.
+pub fn branch_contribution_diff<'a>(
+    context: &UpstreamIntegrationContext<'a>,
+    branch_id: StackId,
+) -> Result<git2::Diff<'a>> {
+    let repository = context.repository;
+    let stack = context
+        .original_branch(branch_id)
+        .context("Failed to find virtual branch")?;
+    let new_target_tree = context.new_target.tree()?;
+    let (merged_tree, _) = merged_tree_keeping_stack_version(context, stack)?;
 
-        let all_resolutions_are_up_to_date = resolutions.iter().all(|resolution| {
-            // This is O(n^2), in reality, n is unlikly to be more than 3 or 4
-            let Some(branch) = context
-                .stacks_in_workspace
-                .iter()
-                .find(|branch| branch.id == resolution.branch_id)
-            else {
-                return false;
-            };
+    Ok(repository.diff_tree_to_tree(
+        Some(&new_target_tree),
+        Some(&repository.find_tree(merged_tree)?),
+        None,
+    )?)
+}
 
-            if resolution.branch_tree != branch.tree {
-                return false;
-            };
+/// Returns the commits of a stack that would get new ids when integrating it with
+/// `approach` onto the new target, child-most first. A stack already based on the new
+/// target, or one that can be fast-forwarded, keeps all of its commits.
+pub fn rewritten_commits(
+    context: &UpstreamIntegrationContext,
+    branch_id: StackId,
+    approach: &ResolutionApproach,
+) -> Result<Vec<git2::Oid>> {
+    let stack = context
+        .original_branch(branch_id)
+        .context("Failed to find virtual branch")?;
+    let new_target_id = context.new_target.id();
+    if !approach.rewrites_history()
+        || context
+            .ancestry
+            .is_ancestor(context.repository, new_target_id, stack.head())?
+    {
+        return Ok(vec![]);
+    }
 
-            let Some(status) = statuses
-                .iter()
-                .find(|status| status.0 == resolution.branch_id)
-            else {
-                return false;
-            };
+    Ok(commits_to_replay(
+        context.repository,
+        stack,
+        stack.head(),
+        new_target_id,
+        context.max_commits_to_replay,
+    )?
+    .iter()
+    .map(|commit| commit.id())
+    .collect())
+}
 
-            status.1.resolution_acceptable(&resolution.approach)
-        });
+/// Returns the most recent commit of a stack that is already part of the new target,
+/// following first parents from its head. This is where the stack's own work begins.
+/// Returns `None` if the stack shares no history with the new target.
+pub fn nearest_integrated_ancestor(
+    context: &UpstreamIntegrationContext,
+    branch_id: StackId,
+) -> Result<Option<git2::Oid>> {
+    let stack = context
+        .original_branch(branch_id)
+        .context("Failed to find virtual branch")?;
 
-        if !all_resolutions_are_up_to_date {
-            bail!("Chosen resolutions do not match current integration statuses")
+    let mut revwalk = context.repository.revwalk()?;
+    revwalk.simplify_first_parent()?;
+    revwalk.push(stack.head())?;
+    for commit_id in revwalk {
+        let commit_id = commit_id?;
+        if context
+            .ancestry
+            .is_ancestor(context.repository, commit_id, context.new_target.id())?
+        {
+            return Ok(Some(commit_id));
         }
     }
 
-    let integration_results =
-        compute_resolutions(&context, resolutions, base_branch_resolution_approach)?;
-
-    {
-        // We preform the updates in stages. If deleting or unapplying fails, we
-        // could enter a much worse state if we're simultaniously updating trees
+    Ok(None)
+}
 
-        // Delete branches
-        for (stack_id, integration_result) in &integration_results {
-            if !matches!(integration_result, IntegrationResult::DeleteBranch) {
-                continue;
-            };
+/// Returns the stacks that have to be rebased along with `base_stack_id`: the stack
+/// itself, followed by every stack whose head descends from its head.
+pub fn affected_by_rebase(
+    context: &UpstreamIntegrationContext,
+    base_stack_id: StackId,
+) -> Result<Vec<StackId>> {
+    let base_stack = context
+        .original_branch(base_stack_id)
+        .context("Failed to find virtual branch")?;
 
-            let stack = virtual_branches_state.get_stack(*stack_id)?;
-            virtual_branches_state.delete_branch_entry(stack_id)?;
-            command_context.delete_branch_reference(&stack)?;
+    let mut affected = vec![base_stack.id];
+    for stack in &context.stacks_in_workspace {
+        if stack.id != base_stack.id
+            && context
+                .ancestry
+                .is_ancestor(context.repository, base_stack.head(), stack.head())?
+        {
+            affected.push(stack.id);
         }
+    }
 
-        let permission = context._permission.expect("Permission provided above");
-
-        // Unapply branches
-        for (stack_id, integration_result) in &integration_results {
-            if !matches!(integration_result, IntegrationResult::UnapplyBranch) {
-                continue;
-            };
+    Ok(affected)
+}
 
-            command_context
-                .branch_manager()
-                .save_and_unapply(*stack_id, permission)?;
+/// Returns the pairs of stacks whose trees would be identical once merged with
+/// the new target, which suggests they carry the same changes and could be collapsed.
+///
+/// Stacks that conflict with the new target are not considered.
+pub fn overlapping_branches(
+    context: &UpstreamIntegrationContext,
+) -> Result<Vec<(StackId, StackId)>> {
+    let UpstreamIntegrationContext {
+        repository,
+        new_target,
+        target,
+        stacks_in_workspace,
+        merge_base_strategy,
+        ..
+    } = context;
+
+    let gix_repository = gitbutler_command_context::gix_repository_for_merging(repository.path())?
+        .with_object_memory();
+    let (merge_options_fail_fast, conflict_kind) =
+        gix_repository.merge_options_no_rewrites_fail_fast()?;
+    let merge_base_tree = git2_to_gix_object_id(merge_base_strategy.merge_base_tree(
+        repository,
+        target.sha,
+        new_target.id(),
+    )?);
+    let new_target_tree = git2_to_gix_object_id(new_target.tree_id());
+
+    let mut merged_trees = vec![];
+    for stack in stacks_in_workspace {
+        let mut merge = gix_repository.merge_trees(
+            merge_base_tree,
+            git2_to_gix_object_id(stack.tree),
+            new_target_tree,
+            gix_repository.default_merge_labels(),
+            merge_options_fail_fast.clone(),
+        )?;
+        if merge.has_unresolved_conflicts(conflict_kind) {
+            continue;
         }
+        merged_trees.push((stack.id, merge.tree.write()?.detach()));
+    }
 
-        let mut stacks = virtual_branches_state.list_stacks_in_workspace()?;
+    let mut overlapping = vec![];
+    for (index, (stack_id, tree)) in merged_trees.iter().enumerate() {
+        for (other_stack_id, other_tree) in &merged_trees[index + 1..] {
+            if tree == other_tree {
+                overlapping.push((*stack_id, *other_stack_id));
+            }
+        }
+    }
 
-        virtual_branches_state.set_default_target(Target {
-            sha: context.new_target.id(),
-            ..default_target
-        })?;
+    Ok(overlapping)
+}
 
-        // Update branch trees
-        for (branch_id, integration_result) in &integration_results {
-            let IntegrationResult::UpdatedObjects { head, tree } = integration_result else {
-                continue;
-            };
+/// How to resolve a single conflicted file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictChoice {
+    /// Keep the version from the stack.
+    Ours,
+    /// Take the version from the new target.
+    Theirs,
+    /// Use the given content.
+    Content(Vec<u8>),
+}
 
-            let Some(stack) = stacks.iter_mut().find(|branch| branch.id == *branch_id) else {
-                continue;
-            };
+/// A [`ConflictChoice`] as recorded in the [`ResolutionJournal`], with the chosen content
+/// written to a blob rather than kept inline.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum RecordedChoice {
+    /// The version from the stack was kept.
+    Ours,
+    /// The version from the new target was taken.
+    Theirs,
+    /// The id of the blob with the chosen content.
+    Content(#[serde(with = "gitbutler_serde::oid")] git2::Oid),
+}
 
-            stack.set_stack_head(command_context, *head, Some(*tree))?;
-            stack.archive_integrated_heads(command_context)?;
-        }
+/// Which side to take for a file that conflicts when integrating.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConflictPreference {
+    /// Keep the version from the stack.
+    Ours,
+    /// Take the version from the new target.
+    Theirs,
+}
 
-        // checkout_branch_trees won't checkout anything if there are no
-        // applied branches, and returns the current_wd_tree as its result.
-        // This is very sensible, but in this case, we want to checkout the
-        // new target sha.
-        if stacks.is_empty() {
-            context
-                .repository
-                .checkout_tree_builder(&context.new_target.tree()?)
-                .force()
-                .remove_untracked()
-                .checkout()?;
-        } else {
-            // Now that we've potentially updated the branch trees, lets checkout
-            // the result of merging them all together.
-            checkout_branch_trees(command_context, permission)?;
+/// The labels that conflict markers show for either side of a conflicted file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConflictLabels {
+    /// The label of the stack's version.
+    pub ours: String,
+    /// The label of the new target's version.
+    pub theirs: String,
+}
+
+impl Default for ConflictLabels {
+    fn default() -> Self {
+        Self {
+            ours: "ours".to_string(),
+            theirs: "theirs".to_string(),
         }
+    }
+}
 
-        crate::integration::update_workspace_commit(&virtual_branches_state, command_context)?;
+impl From<ConflictPreference> for ConflictChoice {
+    fn from(preference: ConflictPreference) -> Self {
+        match preference {
+            ConflictPreference::Ours => ConflictChoice::Ours,
+            ConflictPreference::Theirs => ConflictChoice::Theirs,
+        }
     }
+}
 
-    Ok(())
+/// Resolves a single file that conflicts when merging a stack's tree with the new target.
+///
+/// Returns the merged tree with `path` resolved according to `choice`. In any other
+/// conflicted files the conflicting lines keep the stack's version, so they still conflict
+/// with the new target and can be resolved by calling this again. The choice is recorded in `journal`.
+pub fn apply_file_resolution(
+    context: &UpstreamIntegrationContext,
+    stack_id: StackId,
+    path: &str,
+    choice: ConflictChoice,
+    journal: &mut ResolutionJournal,
+) -> Result<git2::Oid> {
+    let stack = context
+        .stacks_in_workspace
+        .iter()
+        .find(|stack| stack.id == stack_id)
+        .context("Failed to find virtual branch")?;
+    let merge_base_tree = context.merge_base_tree()?;
+    let choice = match choice {
+        ConflictChoice::Ours => RecordedChoice::Ours,
+        ConflictChoice::Theirs => RecordedChoice::Theirs,
+        ConflictChoice::Content(content) => {
+            RecordedChoice::Content(context.repository.blob(&content)?)
+        }
+    };
+
+    let resolved = resolve_file_conflict(
+        context.repository,
+        merge_base_tree,
+        stack.tree,
+        context.new_target.tree_id(),
+        path,
+        choice.clone(),
+    )?;
+    journal.record(stack_id, path, choice);
+    Ok(resolved)
 }
 
-pub(crate) fn resolve_upstream_integration(
-    command_context: &CommandContext,
-    resolution_approach: BaseBranchResolutionApproach,
-    permission: &mut WorktreeWritePermission,
+/// Resolves a single conflicted file with the repository's `merge.tool`, like `git mergetool`.
+///
+/// The stack's version, the new target's version and their merge base are written to
+/// temporary files, and `mergetool.<tool>.cmd` is run with `$LOCAL`, `$REMOTE`, `$BASE`
+/// and `$MERGED` pointing at them. `$MERGED` starts out with the conflict markers of
+/// [`merge_with_conflict_markers`], and whatever the tool leaves in it is used as resolution,
+/// see [`apply_file_resolution`]. Unless `mergetool.<tool>.trustExitCode` is set, a result
+/// that still has conflict markers, or that the tool left unchanged, is rejected.
+pub fn launch_mergetool(
+    context: &UpstreamIntegrationContext,
+    stack_id: StackId,
+    path: &str,
+    journal: &mut ResolutionJournal,
 ) -> Result<git2::Oid> {
-    let context = UpstreamIntegrationContext::open(command_context, None, permission)?;
-    let repo = command_context.repo();
-    let new_target_id = context.new_target.id();
-    let old_target_id = context.target.sha;
-    let fork_point = repo.merge_base(old_target_id, new_target_id)?;
+    let repository = context.repository;
+    let stack = context
+        .original_branch(stack_id)
+        .context("Failed to find virtual branch")?;
 
-    match resolution_approach {
-        BaseBranchResolutionApproach::HardReset => Ok(new_target_id),
-        BaseBranchResolutionApproach::Merge => {
-            let branch_name = context.target.branch.to_string();
-            let old_target = repo.find_commit(context.target.sha)?;
-            let new_head = gitbutler_merge_commits(
-                repo,
-                old_target,
-                context.new_target,
-                &branch_name,
-                &branch_name,
-            )?;
+    let config = repository.config()?;
+    let tool = config
+        .get_string("merge.tool")
+        .ok()
+        .filter(|tool| !tool.is_empty())
+        .context("No merge.tool is configured")?;
+    let tool_cmd = config
+        .get_string(&format!("mergetool.{}.cmd", tool))
+        .with_context(|| format!("No mergetool.{}.cmd is configured", tool))?;
+    let trust_exit_code = match config.get_bool(&format!("mergetool.{}.trustExitCode", tool)) {
+        Ok(trust_exit_code) => trust_exit_code,
+        Err(err) if err.code() == git2::ErrorCode::NotFound => false,
+        Err(err) => return Err(err.into()),
+    };
 
-            Ok(new_head.id())
+    let file_name = Path::new(path)
+        .file_name()
+        .with_context(|| format!("{} is not a file", path))?
+        .to_string_lossy();
+    let blob_content = |tree_id: git2::Oid| -> Result<Vec<u8>> {
+        match repository.find_tree(tree_id)?.get_path(Path::new(path)) {
+            Ok(entry) => Ok(repository.find_blob(entry.id())?.content().to_vec()),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(vec![]),
+            Err(err) => Err(err.into()),
         }
-        BaseBranchResolutionApproach::Rebase => {
-            let commits = repo.l(old_target_id, LogUntil::Commit(fork_point), false)?;
-            let new_head = cherry_rebase_group(repo, new_target_id, &commits, false)?;
+    };
 
-            Ok(new_head)
+    let temp_dir = tempfile::tempdir()?;
+    let write_version = |side: &str, content: Vec<u8>| -> Result<std::path::PathBuf> {
+        let version_path = temp_dir.path().join(format!("{}_{}", side, file_name));
+        std::fs::write(&version_path, content)?;
+        Ok(version_path)
+    };
+    let marked = blob_content(merge_with_conflict_markers(context, stack_id)?)?;
+    let base = write_version("BASE", blob_content(context.merge_base_tree()?)?)?;
+    let local = write_version("LOCAL", blob_content(stack.tree)?)?;
+    let remote = write_version("REMOTE", blob_content(context.new_target.tree_id())?)?;
+    let merged = write_version("MERGED", marked.clone())?;
+
+    let mut command: std::process::Command = gix::command::prepare(tool_cmd).with_shell().into();
+    let status = command
+        .env("BASE", &base)
+        .env("LOCAL", &local)
+        .env("REMOTE", &remote)
+        .env("MERGED", &merged)
+        .current_dir(temp_dir.path())
+        .status()
+        .with_context(|| format!("Failed to run the merge tool {}", tool))?;
+    if !status.success() {
+        bail!("The merge tool {} failed with {}", tool, status);
+    }
+
+    let resolved = std::fs::read(&merged)?;
+    if !trust_exit_code {
+        if has_conflict_markers(&resolved) {
+            bail!("The merge tool {} left conflict markers in {}", tool, path);
+        }
+        if resolved == marked {
+            bail!("The merge tool {} left {} unresolved", tool, path);
         }
     }
+
+    apply_file_resolution(
+        context,
+        stack_id,
+        path,
+        ConflictChoice::Content(resolved),
+        journal,
+    )
 }
 
-fn compute_resolutions(
+/// Resolves the files that conflict when merging a stack's tree with the new target by
+/// applying `patch`, a unified diff like `git diff` prints, e.g. from a script.
+///
+/// The patch applies to the merged tree in which the conflicted files keep the stack's
+/// version, like with [`apply_file_resolution`]. Returns the resolved tree, or fails if
+/// the patch doesn't apply cleanly or leaves conflict markers in a conflicted file.
+pub fn resolve_with_patch(
     context: &UpstreamIntegrationContext,
-    resolutions: &[Resolution],
-    base_branch_resolution_approach: Option<BaseBranchResolutionApproach>,
-) -> Result<Vec<(StackId, IntegrationResult)>> {
-    let UpstreamIntegrationContext {
-        repository,
-        new_target,
-        target,
-        stacks_in_workspace,
-        ..
-    } = context;
+    stack_id: StackId,
+    patch: &[u8],
+) -> Result<git2::Oid> {
+    let repository = context.repository;
+    let stack = context
+        .original_branch(stack_id)
+        .context("Failed to find virtual branch")?;
+    let (conflicted_tree, conflicts) = merged_tree_keeping_stack_version(context, stack)?;
 
-    let results = resolutions
-        .iter()
-        .map(|resolution| {
-            let Some(branch_stack) = stacks_in_workspace
-                .iter()
-                .find(|branch| branch.id == resolution.branch_id)
-            else {
-                bail!("Failed to find virtual branch");
-            };
+    let diff = git2::Diff::from_buffer(patch).context("Failed to parse the patch")?;
+    let mut index = repository
+        .apply_to_tree(&repository.find_tree(conflicted_tree)?, &diff, None)
+        .context("The patch doesn't apply to the conflicted tree")?;
+    let resolved_tree = repository.find_tree(index.write_tree_to(repository)?)?;
 
-            match resolution.approach {
-                ResolutionApproach::Unapply => {
-                    Ok((branch_stack.id, IntegrationResult::UnapplyBranch))
-                }
-                ResolutionApproach::Delete => {
-                    Ok((branch_stack.id, IntegrationResult::DeleteBranch))
-                }
-                ResolutionApproach::Merge => {
-                    // Make a merge commit on top of the branch commits,
-                    // then rebase the tree ontop of that. If the tree ends
-                    // up conflicted, commit the tree.
-                    let target_commit = repository.find_commit(branch_stack.head())?;
-                    let top_branch = branch_stack.heads.last().context("top branch not found")?;
+    for path in conflicts {
+        let entry = match resolved_tree.get_path(Path::new(&path)) {
+            Ok(entry) => entry,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let Ok(blob) = repository.find_blob(entry.id()) else {
+            continue;
+        };
+        if has_conflict_markers(blob.content()) {
+            bail!("The patch leaves conflict markers in {}", path);
+        }
+    }
+    Ok(resolved_tree.id())
+}
 
-                    // These two go into the merge commit message.
-                    let incoming_branch_name = target.branch.fullname();
-                    let target_branch_name = &top_branch.name;
+/// Returns `true` if `content` has a line that starts or ends a conflict, like the
+/// markers a merge writes into conflicted files.
+fn has_conflict_markers(content: &[u8]) -> bool {
+    content
+        .lines()
+        .any(|line| line.starts_with(b"<<<<<<<") || line.starts_with(b">>>>>>>"))
+}
 
-                    let new_head = gitbutler_merge_commits(
-                        repository,
-                        target_commit,
-                        new_target.clone(),
-                        target_branch_name,
-                        &incoming_branch_name,
-                    )?;
+/// Resolves the files that conflict when merging a stack's tree with the new target,
+/// for which the context has a [`ConflictPreference`].
+///
+/// Returns the merged tree. Like with [`apply_file_resolution`], the other conflicted
+/// files keep the stack's version, so they still show up in [`conflicted_files`].
+/// Files with a `merge` attribute in `.gitattributes` are merged with that driver
+/// first, see [`resolve_merge_attributes`].
+pub fn apply_conflict_preferences(
+    context: &UpstreamIntegrationContext,
+    stack_id: StackId,
+) -> Result<git2::Oid> {
+    let stack = context
+        .original_branch(stack_id)
+        .context("Failed to find virtual branch")?;
+    let merge_base_tree = context.merge_base_tree()?;
 
-                    // Get the updated tree oid
-                    let BranchHeadAndTree {
-                        head: new_head,
-                        tree: new_tree,
-                    } = compute_updated_branch_head(repository, branch_stack, new_head.id())?;
+    let mut index = context.repository.merge_trees(
+        &context.repository.find_tree(merge_base_tree)?,
+        &context.repository.find_tree(stack.tree)?,
+        &context.new_target.tree()?,
+        None,
+    )?;
+    resolve_merge_attributes(context.repository, &mut index)?;
+    resolve_excluded_paths(context, &mut index)?;
+    resolve_index_conflicts(context.repository, index, |path| {
+        context
+            .conflict_preferences
+            .get(path)
+            .map(|preference| (*preference).into())
+    })
+}
 
-                    Ok((
-                        branch_stack.id,
-                        IntegrationResult::UpdatedObjects {
-                            head: new_head,
-                            tree: new_tree,
-                        },
-                    ))
-                }
-                ResolutionApproach::Rebase => {
-                    let gix_repository =
-                        gitbutler_command_context::gix_repository_for_merging(repository.path())?;
-                    let cache = gix_repository.commit_graph_if_enabled()?;
-                    let mut graph = gix_repository.revision_graph(cache.as_ref());
-                    let upstream_commit_oids =
-                        repository.l(new_target.id(), LogUntil::Commit(target.sha), true)?;
-                    let mut check_commit = IsCommitIntegrated::new_basic(
-                        &gix_repository,
-                        repository,
-                        &mut graph,
-                        git2_to_gix_object_id(target.sha),
-                        git2_to_gix_object_id(new_target.tree_id()),
-                        upstream_commit_oids,
-                    );
+/// Merges a stack's tree with the new target, writing conflict markers into the files
+/// that conflict, labelled with the context's [`ConflictLabels`].
+///
+/// Returns the merged tree. Files that were added or removed on one of the sides have
+/// no markers to show and keep the stack's version. Files with a `merge` attribute are
+/// merged with that driver instead, like in [`apply_conflict_preferences`].
+pub fn merge_with_conflict_markers(
+    context: &UpstreamIntegrationContext,
+    stack_id: StackId,
+) -> Result<git2::Oid> {
+    let repository = context.repository;
+    let stack = context
+        .original_branch(stack_id)
+        .context("Failed to find virtual branch")?;
+    let merge_base_tree = context.merge_base_tree()?;
 
-                    // Rebase the commits, then try rebasing the tree. If
-                    // the tree ends up conflicted, commit the tree.
+    let mut index = repository.merge_trees(
+        &repository.find_tree(merge_base_tree)?,
+        &repository.find_tree(stack.tree)?,
+        &context.new_target.tree()?,
+        Some(&context.merge_options()),
+    )?;
+    resolve_merge_attributes(repository, &mut index)?;
 
-                    // If the base branch needs to resolve its divergence
-                    // pick only the commits that are ahead of the old target head
-                    let lower_bound = if base_branch_resolution_approach.is_some() {
-                        target.sha
-                    } else {
-                        new_target.id()
-                    };
+    let mut options = git2::MergeFileOptions::new();
+    options
+        .ancestor_label("base")
+        .our_label(&context.conflict_labels.ours)
+        .their_label(&context.conflict_labels.theirs);
+    let mut marked = HashMap::new();
+    for conflict in index.conflicts()?.collect::<Result<Vec<_>, _>>()? {
+        let (Some(path), Some(ancestor), Some(our), Some(their)) = (
+            conflict_path(&conflict),
+            &conflict.ancestor,
+            &conflict.our,
+            &conflict.their,
+        ) else {
+            continue;
+        };
+        let merged = repository.merge_file_from_index(ancestor, our, their, Some(&mut options))?;
+        marked.insert(path, ConflictChoice::Content(merged.content().to_vec()));
+    }
 
-                    // Rebase virtual branches' commits
-                    let virtual_branch_commits = repository.log(
-                        branch_stack.head(),
-                        LogUntil::Commit(lower_bound),
-                        false,
-                    )?;
+    resolve_index_conflicts(repository, index, |path| marked.get(path).cloned())
+}
 
-                    // Filter out any integrated commits
-                    let virtual_branch_commits = virtual_branch_commits
-                        .into_iter()
-                        .filter_map(|commit| {
-                            let is_integrated = check_commit.is_integrated(&commit).ok()?;
-                            if is_integrated {
-                                None
-                            } else {
-                                Some(commit.id())
-                            }
-                        })
-                        .collect::<Vec<_>>();
+/// Returns the files that conflict when merging a stack's `tree` with the new target.
+/// Pass the tree returned by [`apply_file_resolution`] to see which conflicts remain.
+pub fn conflicted_files(
+    context: &UpstreamIntegrationContext,
+    tree: git2::Oid,
+) -> Result<Vec<String>> {
+    let merge_base_tree = context.merge_base_tree()?;
+    let conflicts = tree_conflicts(
+        context.repository,
+        merge_base_tree,
+        tree,
+        context.new_target.tree_id(),
+        Some(&context.merge_options()),
+    )?;
+    Ok(conflicts
+        .into_iter()
+        .filter(|path| !context.is_excluded(path))
+        .collect())
+}
 
-                    let new_head = cherry_rebase_group(
-                        repository,
-                        new_target.id(),
-                        &virtual_branch_commits,
-                        false,
-                    )?;
+/// How likely a stack is to conflict with the new target, see [`conflict_risk`].
+#[derive(Serialize, PartialEq, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum RiskLevel {
+    /// The stack and the new target changed different files.
+    Low,
+    /// The stack and the new target changed some of the same files.
+    High,
+}
 
-                    // Get the updated tree oid
-                    let BranchHeadAndTree {
-                        head: new_head,
-                        tree: new_tree,
-                    } = compute_updated_branch_head(repository, branch_stack, new_head)?;
+/// Estimates whether a stack will conflict with the new target by comparing the files
+/// each of them changed since the old target, without merging any file contents. This is
+/// cheap enough to preview on big repositories, but files changed on both sides may
+/// still merge cleanly.
+pub fn conflict_risk(
+    context: &UpstreamIntegrationContext,
+    branch_id: StackId,
+) -> Result<RiskLevel> {
+    let repository = context.repository;
+    let stack = context
+        .original_branch(branch_id)
+        .context("Failed to find virtual branch")?;
+    let old_target_tree = repository.find_commit(context.target.sha)?.tree()?;
 
-                    Ok((
-                        branch_stack.id,
-                        IntegrationResult::UpdatedObjects {
-                            head: new_head,
-                            tree: new_tree,
-                        },
-                    ))
-                }
-            }
-        })
-        .collect::<Result<Vec<_>>>()?;
+    let changed_paths = |tree: &git2::Tree| -> Result<HashSet<std::path::PathBuf>> {
+        let diff = repository.diff_tree_to_tree(Some(&old_target_tree), Some(tree), None)?;
+        Ok(diff
+            .deltas()
+            .flat_map(|delta| [delta.old_file().path(), delta.new_file().path()])
+            .flatten()
+            .map(Path::to_path_buf)
+            .collect())
+    };
+    let stack_paths = changed_paths(&repository.find_tree(stack.tree)?)?;
+    let upstream_paths = changed_paths(&context.new_target.tree()?)?;
 
-    Ok(results)
+    if stack_paths.is_disjoint(&upstream_paths) {
+        Ok(RiskLevel::Low)
+    } else {
+        Ok(RiskLevel::High)
+    }
+}
+
+/// The conflicts of a stack's tree with the new target, see [`analyze_conflicts`].
+#[derive(Serialize, PartialEq, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ConflictAnalysis {
+    /// The files that conflict.
+    pub conflicts: Vec<String>,
+    /// The files that would conflict, but only differ in whitespace on one side. Always
+    /// empty unless whitespace is ignored.
+    pub whitespace_resolved: Vec<String>,
+}
+
+/// Returns the files that conflict when merging a stack's `tree` with the new target, like
+/// [`conflicted_files`], along with those that only merged cleanly because whitespace
+/// is ignored.
+pub fn analyze_conflicts(
+    context: &UpstreamIntegrationContext,
+    tree: git2::Oid,
+) -> Result<ConflictAnalysis> {
+    let conflicts = conflicted_files(context, tree)?;
+    if !context.ignore_whitespace {
+        return Ok(ConflictAnalysis {
+            conflicts,
+            whitespace_resolved: vec![],
+        });
+    }
+
+    let merge_base_tree = context.merge_base_tree()?;
+    let whitespace_resolved = tree_conflicts(
+        context.repository,
+        merge_base_tree,
+        tree,
+        context.new_target.tree_id(),
+        None,
+    )?
+    .into_iter()
+    .filter(|path| !context.is_excluded(path) && !conflicts.contains(path))
+    .collect();
+
+    Ok(ConflictAnalysis {
+        conflicts,
+        whitespace_resolved,
+    })
+}
+
+/// Returns the files that conflict with the new target for each stack in the workspace,
+/// like [`conflicted_files`] with the stack's tree. Stacks without conflicts are left out.
+pub fn all_conflicts(context: &UpstreamIntegrationContext) -> Result<Vec<(StackId, Vec<String>)>> {
+    let repository = context.repository;
+    let merge_base_tree = repository.find_tree(context.merge_base_tree()?)?;
+    let new_target_tree = context.new_target.tree()?;
+
+    let mut all_conflicts = vec![];
+    for stack in &context.stacks_in_workspace {
+        let index = repository.merge_trees(
+            &merge_base_tree,
+            &repository.find_tree(stack.tree)?,
+            &new_target_tree,
+            Some(&context.merge_options()),
+        )?;
+        let conflicts = index_conflicts(&index)?
+            .into_iter()
+            .filter(|path| !context.is_excluded(path))
+            .collect::<Vec<_>>();
+        if !conflicts.is_empty() {
+            all_conflicts.push((stack.id, conflicts));
+        }
+    }
+
+    Ok(all_conflicts)
+}
+
+/// Returns every file that conflicts with the new target in at least one stack, see
+/// [`all_conflicts`]. A file appears once, no matter how many stacks it conflicts in.
+pub fn all_conflicting_paths(context: &UpstreamIntegrationContext) -> Result<BTreeSet<String>> {
+    Ok(all_conflicts(context)?
+        .into_iter()
+        .flat_map(|(_, conflicts)| conflicts)
+        .collect())
+}
+
+/// Returns the files in which a stack's uncommitted changes conflict with the new target,
+/// leaving out conflicts of its commits. The commits are rebased onto the new target first,
+/// so this is what the uncommitted changes would conflict with after integrating.
+pub fn uncommitted_conflicts(
+    context: &UpstreamIntegrationContext,
+    branch_id: StackId,
+) -> Result<Vec<String>> {
+    let repository = context.repository;
+    let stack = context
+        .original_branch(branch_id)
+        .context("Failed to find virtual branch")?;
+    let head_tree =
+        repository.find_real_tree(&repository.find_commit(stack.head())?, Default::default())?;
+    if head_tree.id() == stack.tree {
+        return Ok(vec![]);
+    }
+
+    let commit_ids = commits_to_replay(
+        repository,
+        stack,
+        stack.head(),
+        context.target.sha,
+        context.max_commits_to_replay,
+    )?
+    .iter()
+    .map(|commit| commit.id())
+    .collect::<Vec<_>>();
+    let rebased_head =
+        cherry_rebase_group(repository, context.new_target.id(), &commit_ids, false)?;
+    let rebased_tree =
+        repository.find_real_tree(&repository.find_commit(rebased_head)?, Default::default())?;
+
+    let index = repository.merge_trees(
+        &head_tree,
+        &repository.find_tree(stack.tree)?,
+        &rebased_tree,
+        None,
+    )?;
+    Ok(index_conflicts(&index)?
+        .into_iter()
+        .filter(|path| !context.is_excluded(path))
+        .collect())
+}
+
+/// Formats the commits a stack has on top of the old target as a series of patches in
+/// mbox format, oldest first, like `git format-patch --stdout` would, e.g. to send them
+/// for review by email. Commits with a change id carry it as a `Change-Id` trailer.
+pub fn export_patch_series(
+    context: &UpstreamIntegrationContext,
+    branch_id: StackId,
+) -> Result<String> {
+    let repository = context.repository;
+    let stack = context
+        .original_branch(branch_id)
+        .context("Failed to find virtual branch")?;
+
+    let mut commit_ids = repository.l(stack.head(), LogUntil::Commit(context.target.sha), false)?;
+    commit_ids.reverse();
+
+    let mut series = String::new();
+    for (index, commit_id) in commit_ids.iter().enumerate() {
+        let commit = repository.find_commit(*commit_id)?;
+        let parent_tree = match commit.parent(0) {
+            Ok(parent) => Some(repository.find_real_tree(&parent, Default::default())?),
+            Err(_) => None,
+        };
+        let tree = repository.find_real_tree(&commit, Default::default())?;
+        let diff = repository.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+
+        let summary = commit.summary().unwrap_or_default();
+        let mut body = commit.body().unwrap_or_default().trim_end().to_string();
+        if let Some(change_id) = commit.change_id() {
+            if !body.is_empty() {
+                body.push_str("\n\n");
+            }
+            body.push_str(&format!("Change-Id: {}", change_id));
+        }
+
+        let email = git2::Email::from_diff(
+            &diff,
+            index + 1,
+            commit_ids.len(),
+            commit_id,
+            summary,
+            &body,
+            &commit.author(),
+            &mut git2::EmailCreateOptions::default(),
+        )?;
+        series.push_str(&String::from_utf8_lossy(email.as_slice()));
+    }
+
+    Ok(series)
+}
+
+/// Mask of the stage bits of an index entry's flags.
+const INDEX_ENTRY_STAGE_MASK: u16 = 0x3000;
+
+fn resolve_file_conflict(
+    repository: &git2::Repository,
+    ancestor: git2::Oid,
+    ours: git2::Oid,
+    theirs: git2::Oid,
+    path: &str,
+    choice: RecordedChoice,
+) -> Result<git2::Oid> {
+    let gix_repository = gitbutler_command_context::gix_repository_for_merging(repository.path())?;
+    let mut merge = gix_repository.merge_trees(
+        git2_to_gix_object_id(ancestor),
+        git2_to_gix_object_id(ours),
+        git2_to_gix_object_id(theirs),
+        gix_repository.default_merge_labels(),
+        gix_repository.merge_options_force_ours()?,
+    )?;
+
+    if !conflicting_paths(&merge, TreatAsUnresolved::forced_resolution())
+        .iter()
+        .any(|conflict_path| conflict_path == path)
+    {
+        bail!("{} is not conflicted", path);
+    }
+
+    let resolved_entry = match choice {
+        RecordedChoice::Ours => tree_entry(&gix_repository, ours, path)?,
+        RecordedChoice::Theirs => tree_entry(&gix_repository, theirs, path)?,
+        RecordedChoice::Content(blob) => {
+            let kind = tree_entry(&gix_repository, ours, path)?
+                .or(tree_entry(&gix_repository, theirs, path)?)
+                .map_or(EntryKind::Blob, |(kind, _)| kind);
+            Some((kind, git2_to_gix_object_id(blob)))
+        }
+    };
+    match resolved_entry {
+        Some((kind, id)) => merge.tree.upsert(BStr::new(path), kind, id)?,
+        None => merge.tree.remove(BStr::new(path))?,
+    };
+    Ok(gix_to_git2_oid(merge.tree.write()?))
+}
+
+/// Returns the kind and id of the entry at `path` in `tree`, or `None` if there is none.
+fn tree_entry(
+    repository: &gix::Repository,
+    tree: git2::Oid,
+    path: &str,
+) -> Result<Option<(EntryKind, gix::ObjectId)>> {
+    Ok(repository
+        .find_tree(git2_to_gix_object_id(tree))?
+        .lookup_entry_by_path(path)?
+        .map(|entry| (entry.mode().kind(), entry.object_id())))
+}
+
+/// Merges the tree of `stack` with the new target on top of the old target. The lines
+/// that conflict keep the stack's version, like in [`apply_file_resolution`].
+///
+/// Returns the merged tree along with the files that conflicted.
+fn merged_tree_keeping_stack_version(
+    context: &UpstreamIntegrationContext,
+    stack: &Stack,
+) -> Result<(git2::Oid, Vec<String>)> {
+    let merge_base_tree = context.merge_base_tree()?;
+    let gix_repository =
+        gitbutler_command_context::gix_repository_for_merging(context.repository.path())?;
+    let mut merge = gix_repository.merge_trees(
+        git2_to_gix_object_id(merge_base_tree),
+        git2_to_gix_object_id(stack.tree),
+        git2_to_gix_object_id(context.new_target.tree_id()),
+        gix_repository.default_merge_labels(),
+        gix_repository.merge_options_force_ours()?,
+    )?;
+    let conflicts = conflicting_paths(&merge, TreatAsUnresolved::forced_resolution())
+        .into_iter()
+        .map(|path| path.to_string())
+        .collect();
+    Ok((gix_to_git2_oid(merge.tree.write()?), conflicts))
+}
+
+/// Resolves the conflicts of `index` with the choice returned for their path, and
+/// writes the resulting tree. Conflicts without a choice keep our version.
+fn resolve_index_conflicts(
+    repository: &git2::Repository,
+    mut index: git2::Index,
+    choose: impl Fn(&str) -> Option<ConflictChoice>,
+) -> Result<git2::Oid> {
+    resolve_chosen_conflicts(repository, &mut index, |path| {
+        Some(choose(path).unwrap_or(ConflictChoice::Ours))
+    })?;
+    Ok(index.write_tree_to(repository)?)
+}
+
+/// Resolves the files that conflict when a stack gets integrated with the new target as
+/// the options of the context say:
+///
+/// * Files in the [excluded paths](UpstreamIntegrationContext::with_excluded_paths) keep
+///   the stack's version.
+/// * Files with a [conflict preference](UpstreamIntegrationContext::with_conflict_preferences)
+///   take the preferred side.
+/// * Files with a `merge` attribute are merged with that driver, see [`resolve_merge_attributes`].
+/// * When [ignoring whitespace](UpstreamIntegrationContext::with_ignore_whitespace), a file
+///   whose changes on one side only touch whitespace takes the other side.
+///
+/// The conflict markers of the remaining files are labelled with the context's [`ConflictLabels`].
+struct IntegrationConflictPolicy<'c, 'a> {
+    context: &'c UpstreamIntegrationContext<'a>,
+    /// The stack under which files merged by ignoring whitespace get recorded in the context.
+    stack_id: Option<StackId>,
+    /// Whether the stack is *ours* in the merges, rather than *theirs*.
+    stack_is_ours: bool,
+}
+
+impl<'c, 'a> IntegrationConflictPolicy<'c, 'a> {
+    /// Returns the policy for rebasing a stack, where the new target is *ours* and the
+    /// stack is *theirs*.
+    fn for_rebase(context: &'c UpstreamIntegrationContext<'a>) -> Self {
+        IntegrationConflictPolicy {
+            context,
+            stack_id: None,
+            stack_is_ours: false,
+        }
+    }
+
+    /// Like [`Self::for_rebase()`], but records the files merged by ignoring whitespace
+    /// for the stack with `stack_id`.
+    fn for_stack(context: &'c UpstreamIntegrationContext<'a>, stack_id: StackId) -> Self {
+        IntegrationConflictPolicy {
+            stack_id: Some(stack_id),
+            ..Self::for_rebase(context)
+        }
+    }
+
+    /// Returns the side to take if one side of `sides` only changed whitespace.
+    fn whitespace_resolution(
+        &self,
+        repository: &gix::Repository,
+        sides: &ConflictSides,
+    ) -> Result<Option<ConflictResolution>> {
+        let is_file = |kind: EntryKind| matches!(kind, EntryKind::Blob | EntryKind::BlobExecutable);
+        let (Some((base_kind, base)), Some((ours_kind, ours)), Some((theirs_kind, theirs))) =
+            (sides.base, sides.ours, sides.theirs)
+        else {
+            return Ok(None);
+        };
+        if !is_file(base_kind) || !is_file(ours_kind) || !is_file(theirs_kind) {
+            return Ok(None);
+        }
+
+        let base = repository.find_blob(base)?;
+        let base_lines = whitespace_normalized_lines(&base.data);
+        let only_whitespace_changed = |id: gix::ObjectId| -> Result<bool> {
+            let blob = repository.find_blob(id)?;
+            Ok(whitespace_normalized_lines(&blob.data) == base_lines)
+        };
+        Ok(if only_whitespace_changed(ours)? {
+            Some(ConflictResolution::Theirs)
+        } else if only_whitespace_changed(theirs)? {
+            Some(ConflictResolution::Ours)
+        } else {
+            None
+        })
+    }
+
+    /// Returns the side of the merge that `preference` refers to.
+    fn side(&self, preference: ConflictPreference) -> ConflictResolution {
+        match (preference, self.stack_is_ours) {
+            (ConflictPreference::Ours, true) | (ConflictPreference::Theirs, false) => {
+                ConflictResolution::Ours
+            }
+            (ConflictPreference::Ours, false) | (ConflictPreference::Theirs, true) => {
+                ConflictResolution::Theirs
+            }
+        }
+    }
+}
+
+impl ConflictPolicy for IntegrationConflictPolicy<'_, '_> {
+    fn labels(&self) -> Labels<'_> {
+        let ConflictLabels { ours, theirs } = &self.context.conflict_labels;
+        let (current, other) = if self.stack_is_ours {
+            (ours, theirs)
+        } else {
+            (theirs, ours)
+        };
+        Labels {
+            ancestor: Some("base".into()),
+            current: Some(current.as_str().into()),
+            other: Some(other.as_str().into()),
+        }
+    }
+
+    fn resolve(
+        &self,
+        repository: &gix::Repository,
+        path: &BStr,
+        sides: &ConflictSides,
+    ) -> Result<Option<ConflictResolution>> {
+        let path = path.to_str_lossy();
+        if self.context.is_excluded(&path) {
+            return Ok(Some(self.side(ConflictPreference::Ours)));
+        }
+        if let Some(preference) = self.context.conflict_preferences.get(path.as_ref()) {
+            return Ok(Some(self.side(*preference)));
+        }
+        match merge_driver(self.context.repository, &path)?.as_deref() {
+            Some("ours") => return Ok(Some(self.side(ConflictPreference::Ours))),
+            Some("union") => {
+                return Ok(union_merge(self.context.repository, &path, sides)?
+                    .map(|merged| repository.write_blob(merged))
+                    .transpose()?
+                    .map(|blob| ConflictResolution::Blob(blob.detach())));
+            }
+            Some(_) => return Ok(None),
+            None => {}
+        }
+        if !self.context.ignore_whitespace {
+            return Ok(None);
+        }
+
+        let resolution = self.whitespace_resolution(repository, sides)?;
+        if let (Some(_), Some(stack_id)) = (resolution, self.stack_id) {
+            self.context
+                .whitespace_resolved
+                .borrow_mut()
+                .entry(stack_id)
+                .or_default()
+                .insert(path.into_owned());
+        }
+        Ok(resolution)
+    }
+}
+
+/// Returns the lines of `content` split into words, so contents that only differ in the
+/// amount of whitespace or in their line endings compare equal.
+fn whitespace_normalized_lines(content: &[u8]) -> Vec<Vec<&[u8]>> {
+    content
+        .lines()
+        .map(|line| line.fields().map(|field| field.as_bytes()).collect())
+        .collect()
+}
+
+/// Resolves the conflicts in the excluded paths of the context to our version.
+fn resolve_excluded_paths(
+    context: &UpstreamIntegrationContext,
+    index: &mut git2::Index,
+) -> Result<()> {
+    if context.excluded_paths.is_empty() {
+        return Ok(());
+    }
+    resolve_chosen_conflicts(context.repository, index, |path| {
+        context.is_excluded(path).then_some(ConflictChoice::Ours)
+    })
+}
+
+/// Resolves conflicts in files that `.gitattributes` assigns one of the built-in merge
+/// drivers, like git does when merging:
+///
+/// * `merge=union` keeps the lines of both sides.
+/// * `merge=ours` keeps the stack's version.
+/// * `merge=binary`, or `-merge`, doesn't merge the contents, so the file stays conflicted.
+///
+/// Other drivers can't be run here, so their files stay conflicted as well. The attributes
+/// are looked up like git does, see [`merge_driver`].
+fn resolve_merge_attributes(repository: &git2::Repository, index: &mut git2::Index) -> Result<()> {
+    let mut union_options = git2::MergeFileOptions::new();
+    union_options.favor(git2::FileFavor::Union);
+    let mut choices = HashMap::new();
+    for conflict in index.conflicts()?.collect::<Result<Vec<_>, _>>()? {
+        let Some(path) = conflict_path(&conflict) else {
+            continue;
+        };
+        let Some(driver) = merge_driver(repository, &path)? else {
+            continue;
+        };
+        let choice = match (driver.as_str(), &conflict) {
+            ("ours", _) => ConflictChoice::Ours,
+            (
+                "union",
+                git2::IndexConflict {
+                    ancestor: Some(ancestor),
+                    our: Some(our),
+                    their: Some(their),
+                },
+            ) => {
+                let merged = repository.merge_file_from_index(
+                    ancestor,
+                    our,
+                    their,
+                    Some(&mut union_options),
+                )?;
+                ConflictChoice::Content(merged.content().to_vec())
+            }
+            _ => continue,
+        };
+        choices.insert(path, choice);
+    }
+
+    resolve_chosen_conflicts(repository, index, |path| choices.get(path).cloned())
+}
+
+/// Returns the merge driver that the `merge` attribute assigns to `path`, `binary` if the
+/// attribute is unset with `-merge`, or `None` if it's not set to a driver. Like when git
+/// merges, the `.gitattributes` files in the worktree are read first, then those in the index.
+fn merge_driver(repository: &git2::Repository, path: &str) -> Result<Option<String>> {
+    let value = repository.get_attr(
+        Path::new(path),
+        "merge",
+        git2::AttrCheckFlags::FILE_THEN_INDEX,
+    )?;
+    Ok(match git2::AttrValue::from_string(value) {
+        git2::AttrValue::String(driver) => Some(driver.to_string()),
+        git2::AttrValue::False => Some("binary".to_string()),
+        _ => None,
+    })
+}
+
+/// Merges the contents of `sides`, keeping the lines of both where they conflict, like the
+/// `union` merge driver does. Returns `None` if one of the sides isn't there to merge.
+fn union_merge(
+    repository: &git2::Repository,
+    path: &str,
+    sides: &ConflictSides,
+) -> Result<Option<Vec<u8>>> {
+    let (Some(base), Some(ours), Some(theirs)) = (sides.base, sides.ours, sides.theirs) else {
+        return Ok(None);
+    };
+    let index_entry = |(kind, id): (EntryKind, gix::ObjectId)| git2::IndexEntry {
+        ctime: git2::IndexTime::new(0, 0),
+        mtime: git2::IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode: kind as u32,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id: gix_to_git2_oid(id),
+        flags: 0,
+        flags_extended: 0,
+        path: path.as_bytes().to_vec(),
+    };
+
+    let mut options = git2::MergeFileOptions::new();
+    options.favor(git2::FileFavor::Union);
+    let merged = repository.merge_file_from_index(
+        &index_entry(base),
+        &index_entry(ours),
+        &index_entry(theirs),
+        Some(&mut options),
+    )?;
+    Ok(Some(merged.content().to_vec()))
+}
+
+/// Returns `true` if `path` matches the exclude `pattern`.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    if pattern.ends_with('/') {
+        path.starts_with(pattern)
+    } else if let Some(suffix) = pattern.strip_prefix('*') {
+        path.ends_with(suffix)
+    } else {
+        path == pattern
+    }
+}
+
+/// Resolves the conflicts of `index` for which `choose` returns a choice, and leaves
+/// the others in place.
+fn resolve_chosen_conflicts(
+    repository: &git2::Repository,
+    index: &mut git2::Index,
+    choose: impl Fn(&str) -> Option<ConflictChoice>,
+) -> Result<()> {
+    let conflicts = index.conflicts()?.collect::<Result<Vec<_>, _>>()?;
+    for conflict in conflicts {
+        let Some(conflict_path) = conflict_path(&conflict) else {
+            continue;
+        };
+        let Some(choice) = choose(&conflict_path) else {
+            continue;
+        };
+        let entry = match choice {
+            ConflictChoice::Ours => conflict.our,
+            ConflictChoice::Theirs => conflict.their,
+            ConflictChoice::Content(content) => {
+                let blob = repository.blob(&content)?;
+                let file_size = u32::try_from(content.len())
+                    .with_context(|| format!("The resolution of {} is too large", conflict_path))?;
+                conflict
+                    .our
+                    .or(conflict.their)
+                    .map(|entry| git2::IndexEntry {
+                        id: blob,
+                        file_size,
+                        ..entry
+                    })
+            }
+        };
+
+        index.conflict_remove(std::path::Path::new(&conflict_path))?;
+        if let Some(mut entry) = entry {
+            entry.flags &= !INDEX_ENTRY_STAGE_MASK;
+            index.add(&entry)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the paths that conflict when merging `ours` and `theirs`.
+fn tree_conflicts(
+    repository: &git2::Repository,
+    ancestor: git2::Oid,
+    ours: git2::Oid,
+    theirs: git2::Oid,
+    options: Option<&git2::MergeOptions>,
+) -> Result<Vec<String>> {
+    let index = repository.merge_trees(
+        &repository.find_tree(ancestor)?,
+        &repository.find_tree(ours)?,
+        &repository.find_tree(theirs)?,
+        options,
+    )?;
+    index_conflicts(&index)
+}
+
+/// Returns the paths that are conflicted in `index`.
+fn index_conflicts(index: &git2::Index) -> Result<Vec<String>> {
+    let conflicts = index.conflicts()?.collect::<Result<Vec<_>, _>>()?;
+    Ok(conflicts.iter().filter_map(conflict_path).collect())
+}
+
+fn conflict_path(conflict: &git2::IndexConflict) -> Option<String> {
+    conflict
+        .our
+        .as_ref()
+        .or(conflict.their.as_ref())
+        .or(conflict.ancestor.as_ref())
+        .map(|entry| String::from_utf8_lossy(&entry.path).into_owned())
+}
+
+/// Returns the tree the stack would have after being integrated with `approach`,
+/// without updating the stack. Useful to show the result before applying it.
+pub fn preview_integrated_tree(
+    context: &UpstreamIntegrationContext,
+    stack_id: StackId,
+    approach: UpdatableResolutionApproach,
+) -> Result<git2::Oid> {
+    let stack = context
+        .original_branch(stack_id)
+        .context("Failed to find virtual branch")?;
+    let resolution = Resolution::new(stack_id, stack.tree, approach.into());
+
+    let results = compute_resolutions(context, &[resolution], None)?;
+    match results.into_iter().next() {
+        Some((_, IntegrationResult::UpdatedObjects { tree, .. })) => Ok(tree),
+        Some((_, IntegrationResult::IntegrationTip { tip })) => Ok(context
+            .repository
+            .find_real_tree(&context.repository.find_commit(tip)?, Default::default())?
+            .id()),
+        Some((_, IntegrationResult::NoOp)) => Ok(stack.tree),
+        _ => bail!("Integrating {} doesn't produce a tree", stack.name),
+    }
+}
+
+/// Returns `true` if applying `resolutions` would leave conflicted commits in any of the
+/// stacks, without applying them. This is the same check that fills
+/// [`IntegrationOutcome::conflicted`].
+///
+/// The stacks aren't touched, but the commits and trees of the would-be integration are
+/// still written to the object database, as rebasing and merging needs them there. Nothing
+/// refers to them, so they are left for `git gc` to prune.
+pub fn would_produce_conflicts(
+    context: &UpstreamIntegrationContext,
+    resolutions: &[Resolution],
+) -> Result<bool> {
+    let integration_results = compute_resolutions(context, resolutions, None)?;
+    let outcome = IntegrationOutcome::from_results(
+        context.repository,
+        context.new_target.id(),
+        &integration_results,
+    )?;
+    Ok(!outcome.conflicted.is_empty())
+}
+
+/// Returns the tree of the workspace after applying `resolutions`, i.e. the trees of the
+/// integrated stacks merged on top of the new target. Unapplied and deleted stacks are left
+/// out. Fails with [`ConflictingStacks`] if the integrated stacks conflict with each other.
+pub fn integrated_workspace_tree(
+    context: &UpstreamIntegrationContext,
+    resolutions: &[Resolution],
+) -> Result<git2::Oid> {
+    let repository = context.repository;
+    let new_target_tree = context.new_target.tree()?;
+
+    let mut workspace_tree = new_target_tree.clone();
+    for (stack_id, result) in compute_resolutions(context, resolutions, None)? {
+        let stack_tree = match result {
+            IntegrationResult::UpdatedObjects { tree, .. } => tree,
+            // The tip already has the new target merged in, unlike the stack's own tree
+            // which would undo the upstream changes when merged on top of the new target.
+            IntegrationResult::IntegrationTip { tip } => repository
+                .find_real_tree(&repository.find_commit(tip)?, Default::default())?
+                .id(),
+            IntegrationResult::NoOp => {
+                context
+                    .original_branch(stack_id)
+                    .context("Failed to find virtual branch")?
+                    .tree
+            }
+            IntegrationResult::UnapplyBranch | IntegrationResult::DeleteBranch => continue,
+        };
+
+        let mut index = repository.merge_trees(
+            &new_target_tree,
+            &workspace_tree,
+            &repository.find_tree(stack_tree)?,
+            None,
+        )?;
+        if index.has_conflicts() {
+            return Err(ConflictingStacks {
+                paths: index_conflicts(&index)?,
+            }
+            .into());
+        }
+        workspace_tree = repository.find_tree(index.write_tree_to(repository)?)?;
+    }
+
+    Ok(workspace_tree.id())
+}
+
+/// Checks that there is exactly one resolution per stack in `statuses`, and that
+/// each of them is acceptable for the status of its stack.
+pub fn validate_resolutions(resolutions: &[Resolution], statuses: &StackStatuses) -> Result<()> {
+    let StackStatuses::UpdatesRequired(statuses) = statuses else {
+        bail!("Branches are all up to date")
+    };
+
+    if resolutions.len() != statuses.len() {
+        bail!(
+            "Chosen resolutions do not match quantity of applied virtual branches. {:?} {:?}",
+            resolutions,
+            statuses
+        )
+    }
+
+    if !resolutions_acceptable(resolutions, statuses) {
+        bail!("Chosen resolutions do not match current integration statuses")
+    }
+
+    Ok(())
+}
+
+/// Returns `true` if each resolution is acceptable for the status of its stack.
+fn resolutions_acceptable(resolutions: &[Resolution], statuses: &[(StackId, StackStatus)]) -> bool {
+    resolutions.iter().all(|resolution| {
+        // This is O(n^2), in reality, n is unlikly to be more than 3 or 4
+        let Some(status) = statuses
+            .iter()
+            .find(|status| status.0 == resolution.branch_id)
+        else {
+            return false;
+        };
+
+        status.1.resolution_acceptable(&resolution.approach)
+    })
+}
+
+/// Returns `true` if the stacks still have the trees the resolutions were chosen for.
+fn resolution_trees_up_to_date(
+    context: &UpstreamIntegrationContext,
+    resolutions: &[Resolution],
+) -> bool {
+    resolutions.iter().all(|resolution| {
+        context
+            .original_branch(resolution.branch_id)
+            .is_some_and(|branch| branch.tree == resolution.branch_tree)
+    })
+}
+
+/// Decodes the resolutions sent by the frontend, and validates them against `statuses`.
+pub fn parse_resolutions(json: &str, statuses: &StackStatuses) -> Result<Vec<Resolution>> {
+    let resolutions: Vec<Resolution> =
+        serde_json::from_str(json).context("Failed to decode resolutions")?;
+    validate_resolutions(&resolutions, statuses)?;
+    Ok(resolutions)
+}
+
+pub(crate) fn integrate_upstream(
+    command_context: &CommandContext,
+    resolutions: &[Resolution],
+    base_branch_resolution: Option<BaseBranchResolution>,
+    permission: &mut WorktreeWritePermission,
+) -> Result<IntegrationOutcome> {
+    let (target_commit_oid, base_branch_resolution_approach) = base_branch_resolution
+        .map(|r| (Some(r.target_commit_oid), Some(r.approach)))
+        .unwrap_or((None, None));
+
+    let context = UpstreamIntegrationContext::open(command_context, target_commit_oid, permission)?;
+
+    // A caller holding on to stale statuses may integrate the current target again,
+    // which would only rewrite the stacks for nothing.
+    if !needs_integration(&context) {
+        return Ok(IntegrationOutcome::default());
+    }
+
+    // Ensure resolutions match current statuses
+    {
+        let statuses = upstream_integration_statuses(&context)?;
+        validate_resolutions(resolutions, &statuses)?;
+
+        if !resolution_trees_up_to_date(&context, resolutions) {
+            bail!("Chosen resolutions do not match current integration statuses")
+        }
+    }
+
+    apply_resolutions(
+        command_context,
+        context,
+        resolutions,
+        base_branch_resolution_approach,
+        false,
+    )
+}
+
+/// Integrates only the stacks `resolutions` are given for, and leaves the others pending.
+/// Each resolution still has to be acceptable for the status of its stack.
+///
+/// The default target moves to the new target, and the remaining stacks remember the
+/// commit they are still [based on](Stack::integration_base), so they keep showing up
+/// in [`upstream_integration_statuses`] until they get integrated too.
+pub(crate) fn integrate_upstream_partial(
+    command_context: &CommandContext,
+    resolutions: &[Resolution],
+    permission: &mut WorktreeWritePermission,
+) -> Result<IntegrationOutcome> {
+    let context = UpstreamIntegrationContext::open(command_context, None, permission)?;
+    if !needs_integration(&context) {
+        return Ok(IntegrationOutcome::default());
+    }
+
+    let StackStatuses::UpdatesRequired(statuses) = upstream_integration_statuses(&context)? else {
+        bail!("Branches are all up to date")
+    };
+    if !resolutions_acceptable(resolutions, &statuses)
+        || !resolution_trees_up_to_date(&context, resolutions)
+    {
+        bail!("Chosen resolutions do not match current integration statuses")
+    }
+
+    apply_resolutions(command_context, context, resolutions, None, true)
+}
+
+/// Resolves a single conflicted file of `stack_id` like [`apply_file_resolution`], and
+/// records the choice in the journal of the [in-progress integration](IntegrationState),
+/// which is started if there is none yet.
+pub(crate) fn resolve_conflicted_file(
+    command_context: &CommandContext,
+    stack_id: StackId,
+    path: &str,
+    choice: ConflictChoice,
+    permission: &mut WorktreeWritePermission,
+) -> Result<git2::Oid> {
+    let gb_dir = command_context.project().gb_dir();
+    let context = UpstreamIntegrationContext::open(command_context, None, permission)?;
+    let mut state = match load_integration_state(&gb_dir)? {
+        Some(state) => state,
+        None => IntegrationState {
+            snapshot: context.snapshot(),
+            conflicted_stack_ids: vec![stack_id],
+            journal: ResolutionJournal::default(),
+        },
+    };
+
+    let resolved = apply_file_resolution(&context, stack_id, path, choice, &mut state.journal)?;
+    save_integration_state(&gb_dir, &state)?;
+    Ok(resolved)
+}
+
+/// Works out what integrating with `resolutions` would do, without changing anything.
+/// The resolutions have to match the current statuses, like for [`integrate_upstream`].
+pub(crate) fn build_plan(
+    command_context: &CommandContext,
+    resolutions: Vec<Resolution>,
+    base_branch_resolution: Option<BaseBranchResolution>,
+    permission: &mut WorktreeWritePermission,
+) -> Result<IntegrationPlan> {
+    let (target_commit_oid, base_branch_resolution_approach) = base_branch_resolution
+        .map(|r| (Some(r.target_commit_oid), Some(r.approach)))
+        .unwrap_or((None, None));
+
+    let context = UpstreamIntegrationContext::open(command_context, target_commit_oid, permission)?;
+    let statuses = upstream_integration_statuses(&context)?;
+    validate_resolutions(&resolutions, &statuses)?;
+    if !resolution_trees_up_to_date(&context, &resolutions) {
+        bail!("Chosen resolutions do not match current integration statuses")
+    }
+
+    let integration_results =
+        compute_resolutions(&context, &resolutions, base_branch_resolution_approach)?;
+    let mut expected_outcome = IntegrationOutcome::from_results(
+        context.repository,
+        context.new_target.id(),
+        &integration_results,
+    )?;
+    expected_outcome.record_approaches(&resolutions);
+    expected_outcome.whitespace_resolved = context.whitespace_resolved();
+
+    Ok(IntegrationPlan {
+        old_target: context.target.sha,
+        new_target: context.new_target.id(),
+        resolutions,
+        base_branch_resolution_approach,
+        expected_outcome,
+    })
+}
+
+/// Applies a plan from [`build_plan`]. Fails if the default target moved since the plan
+/// was built, or if the stacks changed so the resolutions no longer match them.
+pub(crate) fn apply_plan(
+    command_context: &CommandContext,
+    permission: &mut WorktreeWritePermission,
+    plan: IntegrationPlan,
+) -> Result<IntegrationOutcome> {
+    let context =
+        UpstreamIntegrationContext::open(command_context, Some(plan.new_target), permission)?;
+    if context.target.sha != plan.old_target {
+        bail!(
+            "The target moved from {} to {} since the plan was built",
+            plan.old_target,
+            context.target.sha
+        );
+    }
+
+    let statuses = upstream_integration_statuses(&context)?;
+    validate_resolutions(&plan.resolutions, &statuses)?;
+    if !resolution_trees_up_to_date(&context, &plan.resolutions) {
+        bail!("Chosen resolutions do not match current integration statuses")
+    }
+
+    apply_resolutions(
+        command_context,
+        context,
+        &plan.resolutions,
+        plan.base_branch_resolution_approach,
+        false,
+    )
+}
+
+/// Cherry-picks a single `commit`, e.g. from upstream, onto the head of a stack without
+/// moving the target. Conflicts get committed like when integrating, so the new head
+/// is conflicted if the commit didn't apply cleanly.
+pub(crate) fn cherry_pick_into_branch(
+    command_context: &CommandContext,
+    permission: &mut WorktreeWritePermission,
+    stack_id: StackId,
+    commit: git2::Oid,
+) -> Result<IntegrationResult> {
+    let repository = command_context.repo();
+    let virtual_branches_state = VirtualBranchesHandle::new(command_context.project().gb_dir());
+    let mut stack = virtual_branches_state.get_stack_in_workspace(stack_id)?;
+    let old_head = stack.head();
+
+    let new_head = cherry_rebase_group(repository, old_head, &[commit], false)?;
+    let BranchHeadAndTree { head, tree } =
+        compute_updated_branch_head(repository, &stack, new_head)?;
+    let result = IntegrationResult::updated_objects(&stack, head, tree, HashMap::new());
+
+    stack.set_stack_head(command_context, head, Some(tree))?;
+    checkout_branch_trees(command_context, permission)?;
+    crate::integration::update_workspace_commit(&virtual_branches_state, command_context)?;
+
+    Ok(result)
+}
+
+/// Computes the result of each resolution and writes them to the workspace, pointing
+/// the default target at the new target. With `partial` set, the stacks without a
+/// resolution are left behind on the old target instead of having to be resolved too.
+fn apply_resolutions(
+    command_context: &CommandContext,
+    context: UpstreamIntegrationContext,
+    resolutions: &[Resolution],
+    base_branch_resolution_approach: Option<BaseBranchResolutionApproach>,
+    partial: bool,
+) -> Result<IntegrationOutcome> {
+    let virtual_branches_state = VirtualBranchesHandle::new(command_context.project().gb_dir());
+    let default_target = virtual_branches_state.get_default_target()?;
+
+    let integration_results =
+        compute_resolutions(&context, resolutions, base_branch_resolution_approach)?;
+    let mut outcome = IntegrationOutcome::from_results(
+        context.repository,
+        context.new_target.id(),
+        &integration_results,
+    )?;
+    outcome.record_approaches(resolutions);
+    outcome.whitespace_resolved = context.whitespace_resolved();
+
+    {
+        // We preform the updates in stages. If deleting or unapplying fails, we
+        // could enter a much worse state if we're simultaniously updating trees
+
+        // Delete branches
+        for (stack_id, integration_result) in &integration_results {
+            if !matches!(integration_result, IntegrationResult::DeleteBranch) {
+                continue;
+            };
+
+            let stack = virtual_branches_state.get_stack(*stack_id)?;
+            virtual_branches_state.delete_branch_entry(stack_id)?;
+            command_context.delete_branch_reference(&stack)?;
+        }
+
+        let permission = context._permission.expect("Permission provided above");
+
+        // Unapply branches
+        for (stack_id, integration_result) in &integration_results {
+            if !matches!(integration_result, IntegrationResult::UnapplyBranch) {
+                continue;
+            };
+
+            command_context
+                .branch_manager()
+                .save_and_unapply(*stack_id, permission)?;
+        }
+
+        anchor_integration_results(context.repository, &integration_results)?;
+
+        let mut stacks = virtual_branches_state.list_stacks_in_workspace()?;
+
+        virtual_branches_state.set_default_target(Target {
+            sha: context.new_target.id(),
+            ..default_target
+        })?;
+
+        // Record which commit of the target each stack is based on now.
+        for stack in &mut stacks {
+            let resolved = resolutions
+                .iter()
+                .any(|resolution| resolution.branch_id == stack.id);
+            let integration_base = if resolved {
+                None
+            } else if partial
+                && !stack.integration_excluded
+                && context.is_based_on_target(stack)
+                && context.target.sha != context.new_target.id()
+            {
+                Some(context.target.sha)
+            } else {
+                continue;
+            };
+            if stack.integration_base != integration_base {
+                stack.integration_base = integration_base;
+                virtual_branches_state.set_stack(stack.clone())?;
+            }
+        }
+
+        // Update branch trees
+        for (branch_id, integration_result) in &integration_results {
+            let IntegrationResult::UpdatedObjects {
+                head,
+                tree,
+                rewritten_commits,
+                ..
+            } = integration_result
+            else {
+                continue;
+            };
+
+            let Some(stack) = stacks.iter_mut().find(|branch| branch.id == *branch_id) else {
+                continue;
+            };
+
+            stack.set_stack_head(command_context, *head, Some(*tree))?;
+            stack.remap_heads(command_context, rewritten_commits)?;
+            stack.archive_integrated_heads(command_context)?;
+        }
+
+        // checkout_branch_trees won't checkout anything if there are no
+        // applied branches, and returns the current_wd_tree as its result.
+        // This is very sensible, but in this case, we want to checkout the
+        // new target sha.
+        if stacks.is_empty() {
+            context
+                .repository
+                .checkout_tree_builder(&context.new_target.tree()?)
+                .force()
+                .remove_untracked()
+                .checkout()?;
+        } else {
+            // Now that we've potentially updated the branch trees, lets checkout
+            // the result of merging them all together.
+            checkout_branch_trees(command_context, permission)?;
+        }
+
+        crate::integration::update_workspace_commit(&virtual_branches_state, command_context)?;
+    }
+
+    Ok(outcome)
+}
+
+/// Points references at the integration tips and stashed trees in `integration_results`,
+/// so they aren't garbage collected as nothing else refers to them.
+fn anchor_integration_results(
+    repository: &git2::Repository,
+    integration_results: &[(StackId, IntegrationResult)],
+) -> Result<()> {
+    for (stack_id, integration_result) in integration_results {
+        match integration_result {
+            IntegrationResult::IntegrationTip { tip } => {
+                repository
+                    .reference(
+                        &integration_tip_refname(*stack_id),
+                        *tip,
+                        true,
+                        "integration tip",
+                    )
+                    .context("failed to create integration tip reference")?;
+            }
+            IntegrationResult::UpdatedObjects {
+                stashed_tree: Some(tree),
+                ..
+            } => {
+                repository
+                    .reference(&stash_refname(*stack_id), *tree, true, "stashed tree")
+                    .context("failed to create stash reference")?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the references [`anchor_integration_results`] points at the results for
+/// `stack_id`, if there are any.
+fn remove_integration_anchors(repository: &git2::Repository, stack_id: StackId) -> Result<()> {
+    for refname in [integration_tip_refname(stack_id), stash_refname(stack_id)] {
+        match repository.find_reference(&refname) {
+            Ok(mut reference) => reference
+                .delete()
+                .with_context(|| format!("failed to delete {}", refname))?,
+            Err(err) if err.code() == git2::ErrorCode::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Backs out of an integration by restoring the default target, and every stack in
+/// `snapshot` as it was, including stacks that were deleted or unapplied by the
+/// integration. The references to integration tips and stashed trees of those stacks
+/// are deleted, so commits created by the integration, like conflicted tree commits,
+/// are no longer referenced afterwards.
+pub(crate) fn abort_integration(
+    command_context: &CommandContext,
+    snapshot: &IntegrationSnapshot,
+    permission: &mut WorktreeWritePermission,
+) -> Result<()> {
+    let virtual_branches_state = VirtualBranchesHandle::new(command_context.project().gb_dir());
+    revert_target(command_context, permission, snapshot.target_sha)?;
+
+    for stack_snapshot in &snapshot.stacks {
+        virtual_branches_state.set_stack(stack_snapshot.stack.clone())?;
+        command_context.add_branch_reference(&stack_snapshot.stack)?;
+        remove_integration_anchors(command_context.repo(), stack_snapshot.id)?;
+    }
+
+    checkout_branch_trees(command_context, permission)?;
+    crate::integration::update_workspace_commit(&virtual_branches_state, command_context)?;
+    clear_integration_state(command_context.project().gb_dir())?;
+
+    Ok(())
+}
+
+/// Points the default target back at `to`, usually the target of an [`IntegrationSnapshot`]
+/// taken before integrating against the wrong commit. The stacks are left as they are,
+/// see [`abort_integration`] to restore them as well.
+pub(crate) fn revert_target(
+    command_context: &CommandContext,
+    _permission: &mut WorktreeWritePermission,
+    to: git2::Oid,
+) -> Result<()> {
+    command_context
+        .repo()
+        .find_commit(to)
+        .with_context(|| format!("The target {} doesn't exist", to))?;
+
+    let virtual_branches_state = VirtualBranchesHandle::new(command_context.project().gb_dir());
+    let default_target = virtual_branches_state.get_default_target()?;
+    virtual_branches_state.set_default_target(Target {
+        sha: to,
+        ..default_target
+    })
+}
+
+/// Returns the commits that the change ids in the stacks of `snapshot` point to now, for
+/// those that were rewritten since, e.g. by rebasing onto the new target. References to
+/// the old commits can be updated with this.
+pub fn obsoleted_change_ids(
+    snapshot: &IntegrationSnapshot,
+    command_context: &CommandContext,
+) -> Result<HashMap<String, git2::Oid>> {
+    let repository = command_context.repo();
+    let virtual_branches_state = command_context.project().virtual_branches();
+    let target = virtual_branches_state.get_default_target()?;
+
+    let mut obsoleted = HashMap::new();
+    for stack_snapshot in &snapshot.stacks {
+        let Some(stack) = virtual_branches_state.try_stack(stack_snapshot.id)? else {
+            continue;
+        };
+        let current_commits = repository
+            .log(stack.head(), LogUntil::Commit(target.sha), false)?
+            .into_iter()
+            .filter_map(|commit| Some((commit.change_id()?, commit.id())))
+            .collect::<HashMap<_, _>>();
+
+        for commit in repository.log(
+            stack_snapshot.head,
+            LogUntil::Commit(snapshot.target_sha),
+            false,
+        )? {
+            let Some(change_id) = commit.change_id() else {
+                continue;
+            };
+            if let Some(new_commit) = current_commits
+                .get(&change_id)
+                .filter(|new_commit| **new_commit != commit.id())
+            {
+                obsoleted.insert(change_id, *new_commit);
+            }
+        }
+    }
+
+    Ok(obsoleted)
+}
+
+/// Returns the stacks of `snapshot` that had commits of their own before the integration
+/// and have none left now, as all of them turned out to be part of the new target. Those
+/// are likely done and could be deleted. Uncommitted changes aren't taken into account.
+pub fn newly_empty_branches(
+    snapshot: &IntegrationSnapshot,
+    command_context: &CommandContext,
+) -> Result<Vec<StackId>> {
+    let repository = command_context.repo();
+    let virtual_branches_state = command_context.project().virtual_branches();
+    let target = virtual_branches_state.get_default_target()?;
+
+    let mut newly_empty = vec![];
+    for stack_snapshot in &snapshot.stacks {
+        let Some(stack) = virtual_branches_state.try_stack(stack_snapshot.id)? else {
+            continue;
+        };
+        let had_commits = !repository
+            .l(
+                stack_snapshot.head,
+                LogUntil::Commit(snapshot.target_sha),
+                false,
+            )?
+            .is_empty();
+        let has_commits = !repository
+            .l(stack.head(), LogUntil::Commit(target.sha), false)?
+            .is_empty();
+        if had_commits && !has_commits {
+            newly_empty.push(stack.id);
+        }
+    }
+
+    Ok(newly_empty)
+}
+
+pub(crate) fn resolve_upstream_integration(
+    command_context: &CommandContext,
+    resolution_approach: BaseBranchResolutionApproach,
+    permission: &mut WorktreeWritePermission,
+) -> Result<git2::Oid> {
+    let context = UpstreamIntegrationContext::open(command_context, None, permission)?;
+    let repo = command_context.repo();
+    let new_target_id = context.new_target.id();
+    let old_target_id = context.target.sha;
+    let fork_point = context
+        .merge_base_strategy
+        .merge_base(repo, old_target_id, new_target_id)?;
+
+    match resolution_approach {
+        BaseBranchResolutionApproach::HardReset => Ok(new_target_id),
+        BaseBranchResolutionApproach::Merge => {
+            let branch_name = context.target.branch.to_string();
+            let old_target = repo.find_commit(context.target.sha)?;
+            let new_head = gitbutler_merge_commits(
+                repo,
+                old_target,
+                context.new_target,
+                &branch_name,
+                &branch_name,
+            )?;
+
+            Ok(new_head.id())
+        }
+        BaseBranchResolutionApproach::Rebase => {
+            let commits = match fork_point {
+                Some(fork_point) => repo.l(old_target_id, LogUntil::Commit(fork_point), false)?,
+                None => repo.l(old_target_id, LogUntil::End, false)?,
+            };
+            let new_head = cherry_rebase_group(repo, new_target_id, &commits, false)?;
+
+            Ok(new_head)
+        }
+    }
+}
+
+/// Carries the uncommitted changes of a stack, the difference between the tree of `head` and
+/// `tree`, over to `new_head` without committing them, and returns the resulting tree.
+/// Files that conflict keep their uncommitted version.
+fn carry_over_uncommitted_changes(
+    repository: &git2::Repository,
+    head: git2::Oid,
+    tree: git2::Oid,
+    new_head: git2::Oid,
+) -> Result<git2::Oid> {
+    let head_tree = repository
+        .find_real_tree(&repository.find_commit(head)?, Default::default())?
+        .id();
+    let new_head_tree = repository
+        .find_real_tree(&repository.find_commit(new_head)?, Default::default())?
+        .id();
+
+    let gix_repository = gitbutler_command_context::gix_repository_for_merging(repository.path())?;
+    let mut merge = gix_repository.merge_trees(
+        git2_to_gix_object_id(head_tree),
+        git2_to_gix_object_id(tree),
+        git2_to_gix_object_id(new_head_tree),
+        gix_repository.default_merge_labels(),
+        gix_repository.merge_options_force_ours()?,
+    )?;
+    Ok(gix_to_git2_oid(merge.tree.write()?))
+}
+
+/// Returns the commits of `stack` from `head` down to `lower_bound`, newest first,
+/// failing if there are more than `max` of them.
+fn commits_to_replay<'repository>(
+    repository: &'repository git2::Repository,
+    stack: &Stack,
+    head: git2::Oid,
+    lower_bound: git2::Oid,
+    max: usize,
+) -> Result<Vec<git2::Commit<'repository>>> {
+    let mut revwalk = repository.revwalk()?;
+    revwalk.simplify_first_parent()?;
+    revwalk.push(head)?;
+    revwalk.hide(lower_bound)?;
+
+    let commit_ids = revwalk
+        .take(max.saturating_add(1))
+        .collect::<Result<Vec<_>, _>>()?;
+    if commit_ids.len() > max {
+        bail!(
+            "The branch {} has more than {} commits to replay, check that the target is correct",
+            stack.name,
+            max
+        );
+    }
+
+    commit_ids
+        .into_iter()
+        .map(|commit_id| Ok(repository.find_commit(commit_id)?))
+        .collect()
+}
+
+/// Commits `resolved_tree` as the merge of `head` and the new target, trusting that
+/// it already resolves their conflicts. Trees that still carry the entries of a
+/// conflicted commit are refused.
+fn commit_pre_resolved_merge(
+    repository: &git2::Repository,
+    head: &git2::Commit,
+    new_target: &git2::Commit,
+    resolved_tree: git2::Oid,
+    message: &str,
+) -> Result<git2::Oid> {
+    let tree = repository
+        .find_tree(resolved_tree)
+        .with_context(|| format!("The resolved tree {} doesn't exist", resolved_tree))?;
+
+    let conflict_entries = [
+        ConflictedTreeKey::Ours,
+        ConflictedTreeKey::Theirs,
+        ConflictedTreeKey::Base,
+        ConflictedTreeKey::AutoResolution,
+        ConflictedTreeKey::ConflictFiles,
+    ];
+    if conflict_entries
+        .iter()
+        .any(|key| tree.get_name(key).is_some())
+    {
+        bail!(
+            "The resolved tree {} still contains conflicts",
+            resolved_tree
+        );
+    }
+
+    let (author, committer) = repository.signatures()?;
+    repository.commit_with_signature(
+        None,
+        &author,
+        &committer,
+        message,
+        &tree,
+        &[head, new_target],
+        None,
+    )
+}
+
+/// Commits the uncommitted changes of `stack` on top of its head, returning the
+/// new head. If there are no uncommitted changes, the head is returned as is.
+///
+/// The commit is authored by the identity configured for the project's repository,
+/// falling back to the global Git identity.
+fn commit_uncommitted_changes(
+    repository: &git2::Repository,
+    stack: &Stack,
+    message: &str,
+) -> Result<git2::Oid> {
+    let head = repository.find_commit(stack.head())?;
+    if head.tree_id() == stack.tree {
+        return Ok(head.id());
+    }
+
+    let (author, committer) = repository.signatures()?;
+    repository.commit_with_signature(
+        None,
+        &author,
+        &committer,
+        message,
+        &repository.find_tree(stack.tree)?,
+        &[&head],
+        Default::default(),
+    )
+}
+
+/// Rebases `commits` (newest first) onto `base` one by one, so that stacked
+/// references can follow the commits they point to.
+///
+/// Returns the new head, and a map of the original commits to the commits they
+/// were rewritten to. Commits that end up empty map onto their new parent.
+///
+/// Replayed commits keep their change id. Those without one, e.g. because they predate
+/// GitButler, only get a new one with `generate_missing_change_ids`, or if they end up
+/// conflicted, as conflicts are recorded next to the change id.
+#[allow(clippy::too_many_arguments)]
+fn rebase_with_mapping(
+    repository: &git2::Repository,
+    base: git2::Oid,
+    commits: &[git2::Oid],
+    message_rewriter: Option<&MessageRewriter>,
+    autosquash: bool,
+    preserve_committer_date: bool,
+    resign_commits: bool,
+    generate_missing_change_ids: bool,
+    policy: Option<&dyn ConflictPolicy>,
+) -> Result<(git2::Oid, HashMap<git2::Oid, git2::Oid>)> {
+    let commits = commits.iter().rev().copied().collect::<Vec<_>>();
+    let steps = if autosquash {
+        autosquash_steps(repository, &commits)?
+    } else {
+        commits.into_iter().map(|commit| (commit, vec![])).collect()
+    };
+
+    let mut head = base;
+    let mut rewritten_commits = HashMap::new();
+    for (commit, folded_commits) in steps {
+        let new_head = cherry_rebase_group_with_policy(repository, head, &[commit], false, policy)?;
+        // Only touch commits that were actually replayed, not ones that were
+        // kept as they are, or dropped for being empty.
+        let replayed = new_head != commit && new_head != head;
+        head = match message_rewriter {
+            Some(message_rewriter) if replayed => {
+                rewrite_commit_message(repository, new_head, commit, message_rewriter)?
+            }
+            _ => new_head,
+        };
+        if replayed && preserve_committer_date {
+            let original_time = repository.find_commit(commit)?.committer().when();
+            head = with_times(repository, head, None, original_time, None)?;
+        }
+        if replayed
+            && !generate_missing_change_ids
+            && repository.find_commit(commit)?.change_id().is_none()
+        {
+            head = without_change_id(repository, head)?;
+        }
+        if replayed
+            && resign_commits
+            && is_signed(repository, commit)
+            && !is_signed(repository, head)
+        {
+            head = sign_commit(repository, head)?;
+        }
+        rewritten_commits.insert(commit, head);
+
+        for (folded_commit, keep_message) in folded_commits {
+            head = fold_commit(repository, head, folded_commit, keep_message)?;
+            rewritten_commits.insert(commit, head);
+            rewritten_commits.insert(folded_commit, head);
+        }
+    }
+    Ok((head, rewritten_commits))
+}
+
+/// Recreates `commit_id` without the GitButler headers, and thus without a change id,
+/// unless it is conflicted, which the headers record. A signed commit is signed again.
+fn without_change_id(repository: &git2::Repository, commit_id: git2::Oid) -> Result<git2::Oid> {
+    let commit = repository.find_commit(commit_id)?;
+    if commit.change_id().is_none() || commit.is_conflicted() {
+        return Ok(commit_id);
+    }
+    let parents = commit.parents().collect::<Vec<_>>();
+    let new_commit_id = repository.commit(
+        None,
+        &commit.author(),
+        &commit.committer(),
+        &commit.message_bstr().to_str_lossy(),
+        &commit.tree()?,
+        &parents.iter().collect::<Vec<_>>(),
+    )?;
+    if is_signed(repository, commit_id) {
+        sign_commit(repository, new_commit_id)
+    } else {
+        Ok(new_commit_id)
+    }
+}
+
+/// A commit to replay, along with the `fixup!` and `squash!` commits to fold into it.
+/// The flag tells whether the message of the folded commit should be kept.
+type AutosquashStep = (git2::Oid, Vec<(git2::Oid, bool)>);
+
+/// Groups `commits` (oldest first) so that `fixup!` and `squash!` commits follow the
+/// commit they refer to. Those that don't refer to an earlier commit are replayed as is.
+fn autosquash_steps(
+    repository: &git2::Repository,
+    commits: &[git2::Oid],
+) -> Result<Vec<AutosquashStep>> {
+    let mut steps: Vec<AutosquashStep> = vec![];
+    let mut subjects = vec![];
+    for commit_id in commits {
+        let commit = repository.find_commit(*commit_id)?;
+        let message = commit.message_bstr().to_str_lossy();
+        let subject = message.lines().next().unwrap_or_default().to_string();
+
+        if let Some((target, keep_message)) = autosquash_target(&subject) {
+            let target_index = steps.iter().zip(&subjects).position(|((id, _), subject)| {
+                subject == target || (target.len() >= 4 && id.to_string().starts_with(target))
+            });
+            if let Some(target_index) = target_index {
+                steps[target_index].1.push((*commit_id, keep_message));
+                continue;
+            }
+        }
+
+        steps.push((*commit_id, vec![]));
+        subjects.push(subject);
+    }
+    Ok(steps)
+}
+
+/// Returns what the subject of a `fixup!` or `squash!` commit refers to, and whether
+/// it is a `squash!`, whose message should be kept.
+fn autosquash_target(subject: &str) -> Option<(&str, bool)> {
+    let (mut target, keep_message) = if let Some(target) = subject.strip_prefix("fixup! ") {
+        (target, false)
+    } else if let Some(target) = subject.strip_prefix("squash! ") {
+        (target, true)
+    } else {
+        return None;
+    };
+
+    // Fixups of fixups refer to the same commit.
+    while let Some(rest) = target
+        .strip_prefix("fixup! ")
+        .or_else(|| target.strip_prefix("squash! "))
+    {
+        target = rest;
+    }
+    Some((target, keep_message))
+}
+
+/// Squashes `commit` into `head`, returning the squashed commit. If `commit` turns out
+/// empty, `head` is returned, and if it conflicts, it is kept as a separate commit.
+fn fold_commit(
+    repository: &git2::Repository,
+    head: git2::Oid,
+    commit: git2::Oid,
+    keep_message: bool,
+) -> Result<git2::Oid> {
+    let picked = repository.find_commit(cherry_rebase_group(repository, head, &[commit], true)?)?;
+    if picked.id() == head || picked.is_conflicted() {
+        return Ok(picked.id());
+    }
+
+    let head = repository.find_commit(head)?;
+    let head_message = head.message_bstr().to_str_lossy();
+    let message = if keep_message {
+        let picked_message = picked.message_bstr().to_str_lossy();
+        let body = picked_message
+            .split_once('\n')
+            .map(|(_, body)| body.trim())
+            .unwrap_or_default();
+        if body.is_empty() {
+            head_message.to_string()
+        } else {
+            format!("{}\n\n{}", head_message.trim_end(), body)
+        }
+    } else {
+        head_message.to_string()
+    };
+
+    let parents = head.parents().collect::<Vec<_>>();
+    repository.commit_with_signature(
+        None,
+        &head.author(),
+        &head.committer(),
+        &message,
+        &picked.tree()?,
+        &parents.iter().collect::<Vec<_>>(),
+        head.gitbutler_headers(),
+    )
+}
+
+/// Fills in the placeholders of a [merge message template](Resolution::merge_message_template).
+fn render_merge_message(
+    template: &str,
+    branch: &str,
+    old_target: git2::Oid,
+    new_target: git2::Oid,
+) -> String {
+    template
+        .replace("{branch}", branch)
+        .replace("{old_target}", &old_target.to_string())
+        .replace("{new_target}", &new_target.to_string())
+}
+
+fn rewrite_commit_message(
+    repository: &git2::Repository,
+    commit_id: git2::Oid,
+    original_commit_id: git2::Oid,
+    message_rewriter: &MessageRewriter,
+) -> Result<git2::Oid> {
+    let commit = repository.find_commit(commit_id)?;
+    let message = commit.message_bstr().to_str_lossy();
+    let new_message = message_rewriter(&message, original_commit_id);
+    if new_message == message {
+        return Ok(commit_id);
+    }
+
+    let parents = commit.parents().collect::<Vec<_>>();
+    repository.commit_with_signature(
+        None,
+        &commit.author(),
+        &commit.committer(),
+        &new_message,
+        &commit.tree()?,
+        &parents.iter().collect::<Vec<_>>(),
+        commit.gitbutler_headers(),
+    )
+}
+
+/// Returns `true` if the commit carries a signature.
+fn is_signed(repository: &git2::Repository, commit_id: git2::Oid) -> bool {
+    repository.extract_signature(&commit_id, None).is_ok()
+}
+
+/// Recreates the unsigned `commit_id` with a signature made with the signing key of the
+/// repository, even if `gitbutler.signCommits` is off.
+fn sign_commit(repository: &git2::Repository, commit_id: git2::Oid) -> Result<git2::Oid> {
+    let object = repository.odb()?.read(commit_id)?;
+    let content = std::str::from_utf8(object.data()).context("Can't sign a non-utf8 commit")?;
+    let signature = repository.sign_buffer(object.data())?;
+    Ok(repository.commit_signed(content, &signature.to_str_lossy(), None)?)
+}
+
+/// Recreates `commit_id` with the given committer time, and author time unless it is
+/// `None`. A `change_id` replaces the one in its headers. Returns `commit_id` itself if
+/// nothing would change.
+fn with_times(
+    repository: &git2::Repository,
+    commit_id: git2::Oid,
+    author_time: Option<git2::Time>,
+    committer_time: git2::Time,
+    change_id: Option<String>,
+) -> Result<git2::Oid> {
+    let commit = repository.find_commit(commit_id)?;
+    let same_time = |signature: &git2::Signature, time: git2::Time| {
+        signature.when().seconds() == time.seconds()
+            && signature.when().offset_minutes() == time.offset_minutes()
+    };
+    let commit_headers = commit.gitbutler_headers();
+    if same_time(&commit.committer(), committer_time)
+        && author_time.map_or(true, |time| same_time(&commit.author(), time))
+        && change_id.as_ref().map_or(true, |change_id| {
+            commit_headers
+                .as_ref()
+                .is_some_and(|commit_headers| commit_headers.change_id == *change_id)
+        })
+    {
+        return Ok(commit_id);
+    }
+
+    let with_time = |signature: git2::Signature, time: git2::Time| {
+        git2::Signature::new(
+            signature.name().unwrap_or_default(),
+            signature.email().unwrap_or_default(),
+            &time,
+        )
+    };
+    let author = match author_time {
+        Some(time) => with_time(commit.author(), time)?,
+        None => commit.author(),
+    };
+    let commit_headers = match change_id {
+        Some(change_id) => Some(CommitHeadersV2 {
+            change_id,
+            ..commit_headers.unwrap_or_default()
+        }),
+        None => commit_headers,
+    };
+
+    let parents = commit.parents().collect::<Vec<_>>();
+    repository.commit_with_signature(
+        None,
+        &author,
+        &with_time(commit.committer(), committer_time)?,
+        &commit.message_bstr().to_str_lossy(),
+        &commit.tree()?,
+        &parents.iter().collect::<Vec<_>>(),
+        commit_headers,
+    )
+}
+
+/// Returns the commit the stack of `resolution` gets rebased onto, which is the new
+/// target unless the resolution names another destination.
+fn rebase_destination(
+    repository: &git2::Repository,
+    ancestry: &AncestryCache,
+    new_target: &git2::Commit,
+    stacks_in_workspace: &[Stack],
+    resolution: &Resolution,
+) -> Result<git2::Oid> {
+    let Some(onto) = resolution.rebase_onto else {
+        return Ok(new_target.id());
+    };
+    repository
+        .find_commit(onto)
+        .with_context(|| format!("The rebase destination {} doesn't exist", onto))?;
+
+    let tips = std::iter::once(new_target.id())
+        .chain(stacks_in_workspace.iter().map(|stack| stack.head()));
+    for tip in tips {
+        if ancestry.is_ancestor(repository, onto, tip)? {
+            return Ok(onto);
+        }
+    }
+
+    bail!(
+        "The rebase destination {} is not reachable from the new target or any stack in the workspace",
+        onto
+    )
+}
+
+/// Recreates `commit_id` with its times set to when `stack` was last updated. Its change id
+/// is derived from the stack and its tree instead of being random, so its id only depends
+/// on its content and the stack. It is formatted like the change ids the crate generates.
+fn with_stack_time(
+    repository: &git2::Repository,
+    commit_id: git2::Oid,
+    stack: &Stack,
+) -> Result<git2::Oid> {
+    let commit = repository.find_commit(commit_id)?;
+    let seed = git2::Oid::hash_object(
+        git2::ObjectType::Blob,
+        format!("{}:{}", stack.id, commit.tree_id()).as_bytes(),
+    )?;
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&seed.as_bytes()[..16]);
+    let change_id = uuid::Builder::from_random_bytes(bytes)
+        .into_uuid()
+        .to_string();
+
+    let time = git2::Time::new((stack.updated_timestamp_ms / 1000) as i64, 0);
+    with_times(repository, commit_id, Some(time), time, Some(change_id))
+}
+
+/// Returns the result of a clean integration, where `committed_head` is the stack's
+/// commits replayed onto `base`, and `head` and `tree` include the uncommitted changes.
+/// If those changes had to be committed as a conflict, they get stashed instead.
+fn clean_updated_objects(
+    repository: &git2::Repository,
+    stack: &Stack,
+    base: git2::Oid,
+    committed_head: git2::Oid,
+    head: git2::Oid,
+    tree: git2::Oid,
+    rewritten_commits: HashMap<git2::Oid, git2::Oid>,
+) -> Result<IntegrationResult> {
+    if repository
+        .log(committed_head, LogUntil::Commit(base), false)?
+        .iter()
+        .any(|commit| commit.is_conflicted())
+    {
+        bail!(
+            "The commits of {} conflict with the new target and can't be integrated cleanly",
+            stack.name
+        );
+    }
+
+    if head == committed_head {
+        return Ok(IntegrationResult::updated_objects(
+            stack,
+            head,
+            tree,
+            rewritten_commits,
+        ));
+    }
+
+    Ok(IntegrationResult::UpdatedObjects {
+        head: committed_head,
+        tree: repository.find_commit(committed_head)?.tree_id(),
+        rewritten_commits,
+        stashed_tree: Some(stack.tree),
+    })
+}
+
+fn compute_resolutions(
+    context: &UpstreamIntegrationContext,
+    resolutions: &[Resolution],
+    base_branch_resolution_approach: Option<BaseBranchResolutionApproach>,
+) -> Result<Vec<(StackId, IntegrationResult)>> {
+    let UpstreamIntegrationContext {
+        repository,
+        new_target,
+        target,
+        stacks_in_workspace,
+        message_rewriter,
+        max_commits_to_replay,
+        force_clean,
+        ancestry,
+        ..
+    } = context;
+    context.whitespace_resolved.borrow_mut().clear();
+
+    let results = resolutions
+        .iter()
+        .map(|resolution| {
+            let Some(branch_stack) = stacks_in_workspace
+                .iter()
+                .find(|branch| branch.id == resolution.branch_id)
+            else {
+                bail!("Failed to find virtual branch");
+            };
+            if let Some(pending_context) = context.for_pending_stack(branch_stack) {
+                let result = compute_resolutions(
+                    &pending_context,
+                    std::slice::from_ref(resolution),
+                    base_branch_resolution_approach,
+                )?
+                .pop()
+                .context("Failed to integrate the pending branch")?;
+                context
+                    .whitespace_resolved
+                    .borrow_mut()
+                    .extend(pending_context.whitespace_resolved.take());
+                return Ok(result);
+            }
+            if !context.is_based_on_target(branch_stack) {
+                bail!(
+                    "{} is based on another target than {}",
+                    branch_stack.name,
+                    target.branch
+                );
+            }
+            let policy = IntegrationConflictPolicy::for_stack(context, branch_stack.id);
+
+            match &resolution.approach {
+                ResolutionApproach::Unapply => {
+                    Ok((branch_stack.id, IntegrationResult::UnapplyBranch))
+                }
+                ResolutionApproach::Delete => {
+                    Ok((branch_stack.id, IntegrationResult::DeleteBranch))
+                }
+                ResolutionApproach::Merge => {
+                    // Make a merge commit on top of the branch commits,
+                    // then rebase the tree ontop of that. If the tree ends
+                    // up conflicted, commit the tree.
+                    let target_commit = repository.find_commit(branch_stack.head())?;
+                    let top_branch = branch_stack.heads.last().context("top branch not found")?;
+
+                    let message = match &resolution.merge_message_template {
+                        Some(template) => render_merge_message(
+                            template,
+                            &top_branch.name,
+                            target.sha,
+                            new_target.id(),
+                        ),
+                        None => merge_commit_message(&top_branch.name, &target.branch.fullname()),
+                    };
+
+                    let merge_commit = match resolution.resolved_tree {
+                        Some(resolved_tree) => commit_pre_resolved_merge(
+                            repository,
+                            &target_commit,
+                            new_target,
+                            resolved_tree,
+                            &message,
+                        )?,
+                        None => gitbutler_merge_commits_with_message(
+                            repository,
+                            target_commit,
+                            new_target.clone(),
+                            &message,
+                            Some(&policy),
+                        )?
+                        .id(),
+                    };
+
+                    // Get the updated tree oid
+                    let BranchHeadAndTree {
+                        head: new_head,
+                        tree: new_tree,
+                    } = compute_updated_branch_head_for_commits_with_policy(
+                        repository,
+                        branch_stack.head(),
+                        branch_stack.tree,
+                        merge_commit,
+                        Some(&policy),
+                    )?;
+
+                    if *force_clean {
+                        return Ok((
+                            branch_stack.id,
+                            clean_updated_objects(
+                                repository,
+                                branch_stack,
+                                new_target.id(),
+                                merge_commit,
+                                new_head,
+                                new_tree,
+                                HashMap::new(),
+                            )?,
+                        ));
+                    }
+
+                    Ok((
+                        branch_stack.id,
+                        IntegrationResult::updated_objects(
+                            branch_stack,
+                            new_head,
+                            new_tree,
+                            HashMap::new(),
+                        ),
+                    ))
+                }
+                ResolutionApproach::MergeIntoTarget => {
+                    let top_branch = branch_stack.heads.last().context("top branch not found")?;
+                    let merge_commit = gitbutler_merge_commits(
+                        repository,
+                        new_target.clone(),
+                        repository.find_commit(branch_stack.head())?,
+                        &target.branch.fullname(),
+                        &top_branch.name,
+                    )?;
+
+                    Ok((
+                        branch_stack.id,
+                        IntegrationResult::IntegrationTip {
+                            tip: merge_commit.id(),
+                        },
+                    ))
+                }
+                ResolutionApproach::Rebase
+                | ResolutionApproach::CommitThenRebase { .. }
+                | ResolutionApproach::RebaseCommitsOnly => {
+                    let stack_head = match &resolution.approach {
+                        ResolutionApproach::CommitThenRebase { message } => {
+                            commit_uncommitted_changes(repository, branch_stack, message)?
+                        }
+                        _ => branch_stack.head(),
+                    };
+                    let onto = rebase_destination(
+                        repository,
+                        ancestry,
+                        new_target,
+                        stacks_in_workspace,
+                        resolution,
+                    )?;
+
+                    // A pristine branch has neither commits nor changes of its
+                    // own, so it simply fast-forwards to the destination.
+                    if stack_head == target.sha
+                        && branch_stack.tree == repository.find_commit(target.sha)?.tree_id()
+                    {
+                        let onto_tree = repository.find_commit(onto)?.tree_id();
+                        return Ok((
+                            branch_stack.id,
+                            IntegrationResult::updated_objects(
+                                branch_stack,
+                                onto,
+                                onto_tree,
+                                HashMap::new(),
+                            ),
+                        ));
+                    }
+
+                    // If the branch is already based on the destination there
+                    // is nothing to rebase, so only the tree gets updated.
+                    // This keeps the commit ids stable.
+                    if ancestry.is_ancestor(repository, onto, stack_head)? {
+                        let BranchHeadAndTree {
+                            head: new_head,
+                            tree: new_tree,
+                        } = if resolution.approach == ResolutionApproach::RebaseCommitsOnly {
+                            BranchHeadAndTree {
+                                head: stack_head,
+                                tree: carry_over_uncommitted_changes(
+                                    repository,
+                                    stack_head,
+                                    branch_stack.tree,
+                                    stack_head,
+                                )?,
+                            }
+                        } else {
+                            compute_updated_branch_head_for_commits_with_policy(
+                                repository,
+                                stack_head,
+                                branch_stack.tree,
+                                stack_head,
+                                Some(&policy),
+                            )?
+                        };
+
+                        return Ok((
+                            branch_stack.id,
+                            IntegrationResult::updated_objects(
+                                branch_stack,
+                                new_head,
+                                new_tree,
+                                HashMap::new(),
+                            ),
+                        ));
+                    }
+
+                    let gix_repository =
+                        gitbutler_command_context::gix_repository_for_merging(repository.path())?;
+                    let cache = gix_repository.commit_graph_if_enabled()?;
+                    let mut graph = gix_repository.revision_graph(cache.as_ref());
+                    let upstream_commit_oids =
+                        repository.l(new_target.id(), LogUntil::Commit(target.sha), true)?;
+                    let mut check_commit = IsCommitIntegrated::new_basic(
+                        &gix_repository,
+                        repository,
+                        &mut graph,
+                        git2_to_gix_object_id(target.sha),
+                        git2_to_gix_object_id(new_target.tree_id()),
+                        upstream_commit_oids,
+                    );
+
+                    // Rebase the commits, then try rebasing the tree. If
+                    // the tree ends up conflicted, commit the tree.
+
+                    // If the base branch needs to resolve its divergence
+                    // pick only the commits that are ahead of the old target head
+                    let lower_bound = if base_branch_resolution_approach.is_some() {
+                        target.sha
+                    } else {
+                        new_target.id()
+                    };
+
+                    // Rebase virtual branches' commits
+                    let virtual_branch_commits = commits_to_replay(
+                        repository,
+                        branch_stack,
+                        stack_head,
+                        lower_bound,
+                        *max_commits_to_replay,
+                    )?;
+
+                    // Filter out any integrated commits
+                    let virtual_branch_commits = virtual_branch_commits
+                        .into_iter()
+                        .filter_map(|commit| {
+                            let is_integrated = check_commit.is_integrated(&commit).ok()?;
+                            if is_integrated {
+                                None
+                            } else {
+                                Some(commit.id())
+                            }
+                        })
+                        .collect::<Vec<_>>();
+
+                    let (rebased_head, rewritten_commits) = rebase_with_mapping(
+                        repository,
+                        onto,
+                        &virtual_branch_commits,
+                        message_rewriter.as_ref(),
+                        resolution.autosquash,
+                        resolution.preserve_committer_date,
+                        resolution.resign_commits,
+                        resolution.generate_missing_change_ids,
+                        Some(&policy),
+                    )?;
+
+                    // Get the updated tree oid
+                    let BranchHeadAndTree {
+                        head: new_head,
+                        tree: new_tree,
+                    } = if resolution.approach == ResolutionApproach::RebaseCommitsOnly {
+                        BranchHeadAndTree {
+                            head: rebased_head,
+                            tree: carry_over_uncommitted_changes(
+                                repository,
+                                stack_head,
+                                branch_stack.tree,
+                                rebased_head,
+                            )?,
+                        }
+                    } else {
+                        compute_updated_branch_head_for_commits_with_policy(
+                            repository,
+                            stack_head,
+                            branch_stack.tree,
+                            rebased_head,
+                            Some(&policy),
+                        )?
+                    };
+
+                    // A new head means the uncommitted changes were committed as a conflict.
+                    let new_head = if resolution.deterministic_time && new_head != rebased_head {
+                        with_stack_time(repository, new_head, branch_stack)?
+                    } else {
+                        new_head
+                    };
+
+                    if *force_clean {
+                        return Ok((
+                            branch_stack.id,
+                            clean_updated_objects(
+                                repository,
+                                branch_stack,
+                                onto,
+                                rebased_head,
+                                new_head,
+                                new_tree,
+                                rewritten_commits,
+                            )?,
+                        ));
+                    }
+
+                    Ok((
+                        branch_stack.id,
+                        IntegrationResult::updated_objects(
+                            branch_stack,
+                            new_head,
+                            new_tree,
+                            rewritten_commits,
+                        ),
+                    ))
+                }
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use gitbutler_stack::StackBranch;
+    use gitbutler_testsupport::testing_repository::{
+        assert_commit_tree_matches, assert_tree_matches, TestingRepository,
+    };
+
+    fn target(sha: git2::Oid) -> Target {
+        Target {
+            branch: RemoteRefname::new("origin", "master"),
+            remote_url: String::new(),
+            sha,
+            push_remote_name: None,
+        }
+    }
+
+    /// Creates a stack with a single branch pointing at `head`.
+    fn stack(head: &git2::Commit, tree: git2::Oid) -> Stack {
+        #[allow(deprecated)]
+        let mut stack = Stack::new(
+            "stack".into(),
+            None,
+            None,
+            None,
+            tree,
+            head.id(),
+            0,
+            None,
+            true,
+        );
+        stack.heads = vec![StackBranch {
+            head: head.clone().into(),
+            name: "branch".into(),
+            description: None,
+            pr_number: None,
+            archived: false,
+        }];
+        stack
+    }
+
+    fn integration_context<'a>(
+        repository: &'a git2::Repository,
+        stacks_in_workspace: Vec<Stack>,
+        old_target: git2::Oid,
+        new_target: git2::Commit<'a>,
+    ) -> UpstreamIntegrationContext<'a> {
+        UpstreamIntegrationContext {
+            _permission: None,
+            repository,
+            stacks_in_workspace,
+            stacks_not_in_workspace: vec![],
+            new_target,
+            target: target(old_target),
+            merge_base_strategy: MergeBaseStrategy::default(),
+            message_rewriter: None,
+            max_commits_to_replay: DEFAULT_MAX_COMMITS_TO_REPLAY,
+            conflict_preferences: HashMap::new(),
+            force_clean: false,
+            excluded_paths: vec![],
+            worktree_changes: false,
+            skip_empty: false,
+            ignore_whitespace: false,
+            conflict_labels: ConflictLabels::default(),
+            ancestry: AncestryCache::default(),
+            whitespace_resolved: RefCell::default(),
+        }
+    }
+
+    fn stack_status(
+        repository: &git2::Repository,
+        old_target: git2::Oid,
+        new_target: git2::Oid,
+        stack: &Stack,
+    ) -> StackStatus {
+        let gix_repository =
+            gitbutler_command_context::gix_repository_for_merging(repository.path())
+                .unwrap()
+                .with_object_memory();
+        let target_state = TargetState::load(
+            repository,
+            &gix_repository,
+            old_target,
+            git2_to_gix_object_id(new_target),
+            &MergeBaseStrategy::default(),
+        )
+        .unwrap();
+        get_stack_status(
+            repository,
+            &gix_repository,
+            target(old_target),
+            &target_state,
+            stack,
+            None,
+        )
+        .unwrap()
+    }
+
+    mod compute_resolutions {
+        use super::*;
+
+        /// The results are keyed by stack id, so they don't depend on the order
+        /// of the stacks in the workspace.
+        #[test]
+        fn results_carry_the_stack_id() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_b = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack_a = stack(&local_a, local_a.tree_id());
+            let stack_b = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack_a.clone(), stack_b.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[
+                    Resolution::new(stack_b.id, stack_b.tree, ResolutionApproach::Unapply),
+                    Resolution::new(stack_a.id, stack_a.tree, ResolutionApproach::Delete),
+                ],
+                None,
+            )
+            .unwrap();
+
+            assert_eq!(
+                results,
+                vec![
+                    (stack_b.id, IntegrationResult::UnapplyBranch),
+                    (stack_a.id, IntegrationResult::DeleteBranch),
+                ]
+            );
+        }
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A (bottom) -> B (top)
+        /// Result: Base -> X -> A' (bottom) -> B' (top)
+        #[test]
+        fn rebase_remaps_stacked_references() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_b = test_repository.commit_tree(Some(&local_a), &[("foo.txt", "foo2")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let mut stack = stack(&local_b, local_b.tree_id());
+            stack.heads.insert(
+                0,
+                StackBranch {
+                    head: local_a.clone().into(),
+                    name: "bottom".into(),
+                    description: None,
+                    pr_number: None,
+                    archived: false,
+                },
+            );
+
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(
+                _,
+                IntegrationResult::UpdatedObjects {
+                    head,
+                    rewritten_commits,
+                    ..
+                },
+            )] = results.as_slice()
+            else {
+                panic!("Expected a single updated stack");
+            };
+
+            let new_a = test_repository
+                .repository
+                .find_commit(rewritten_commits[&local_a.id()])
+                .unwrap();
+            let new_b = test_repository
+                .repository
+                .find_commit(rewritten_commits[&local_b.id()])
+                .unwrap();
+            assert_eq!(new_a.parent_id(0).unwrap(), upstream_x.id());
+            assert_eq!(new_b.parent_id(0).unwrap(), new_a.id());
+            assert_eq!(*head, new_b.id());
+        }
+    }
+
+    mod message_rewriter {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A
+        /// Result: Base -> X -> A' (with trailer)
+        #[test]
+        fn replayed_commits_get_rewritten_messages() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree_with_message(
+                Some(&base_commit),
+                "A",
+                &[("foo.txt", "foo1")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            )
+            .with_message_rewriter(|message, original_commit_id| {
+                format!("{message}\n\nCherry-picked-from: {original_commit_id}")
+            });
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice() else {
+                panic!("Expected a single updated stack");
+            };
+            let new_a = test_repository.repository.find_commit(*head).unwrap();
+            assert_eq!(new_a.parent_id(0).unwrap(), upstream_x.id());
+            assert_eq!(
+                new_a.message().unwrap(),
+                format!("A\n\nCherry-picked-from: {}", local_a.id())
+            );
+        }
+    }
+
+    mod max_commits_to_replay {
+        use super::*;
+
+        /// Trunk: Base -> X
+        /// Stack: Base -> A -> B
+        #[test]
+        fn errors_when_exceeded() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_b = test_repository.commit_tree(Some(&local_a), &[("foo.txt", "foo2")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            )
+            .with_max_commits_to_replay(1);
+
+            let result = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            );
+
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                format!(
+                    "The branch {} has more than 1 commits to replay, check that the target is correct",
+                    stack.name
+                )
+            );
+        }
+
+        #[test]
+        fn allows_commits_up_to_the_cap() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_b = test_repository.commit_tree(Some(&local_a), &[("foo.txt", "foo2")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            )
+            .with_max_commits_to_replay(2);
+
+            let result = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            );
+
+            assert!(result.is_ok());
+        }
+    }
+
+    mod autosquash {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A -> B -> fixup! A
+        /// Result: Base -> X -> A' -> B'
+        #[test]
+        fn fixup_is_folded_into_its_target() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree_with_message(
+                Some(&base_commit),
+                "A",
+                &[("foo.txt", "foo1")],
+            );
+            let local_b = test_repository.commit_tree_with_message(
+                Some(&local_a),
+                "B",
+                &[("foo.txt", "foo1"), ("baz.txt", "baz")],
+            );
+            let fixup_a = test_repository.commit_tree_with_message(
+                Some(&local_b),
+                "fixup! A",
+                &[("foo.txt", "foo2"), ("baz.txt", "baz")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&fixup_a, fixup_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution {
+                    autosquash: true,
+                    ..Resolution::new(stack.id, stack.tree, ResolutionApproach::Rebase)
+                }],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice() else {
+                panic!("Expected a single updated stack");
+            };
+            let new_b = test_repository.repository.find_commit(*head).unwrap();
+            assert_eq!(new_b.message(), Some("B"));
+            assert_commit_tree_matches(
+                &test_repository.repository,
+                &new_b,
+                &[
+                    ("foo.txt", b"foo2"),
+                    ("bar.txt", b"bar"),
+                    ("baz.txt", b"baz"),
+                ],
+            );
+
+            let new_a = new_b.parent(0).unwrap();
+            assert_eq!(new_a.message(), Some("A"));
+            assert_commit_tree_matches(
+                &test_repository.repository,
+                &new_a,
+                &[("foo.txt", b"foo2"), ("bar.txt", b"bar")],
+            );
+            assert_eq!(new_a.parent_id(0).unwrap(), upstream_x.id());
+        }
+    }
+
+    mod rebase_commits_only {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A (+ uncommitted baz.txt)
+        /// Result: Base -> X -> A' (+ uncommitted baz.txt)
+        #[test]
+        fn uncommitted_changes_stay_uncommitted() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let uncommitted = test_repository
+                .commit_tree(Some(&local_a), &[("foo.txt", "foo1"), ("baz.txt", "baz")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, uncommitted.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::RebaseCommitsOnly,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, tree, .. })] = results.as_slice()
+            else {
+                panic!("Expected a single updated stack");
+            };
+            let new_a = test_repository.repository.find_commit(*head).unwrap();
+            assert_eq!(new_a.message(), local_a.message());
+            assert_eq!(new_a.parent_id(0).unwrap(), upstream_x.id());
+            assert_commit_tree_matches(
+                &test_repository.repository,
+                &new_a,
+                &[("foo.txt", b"foo1"), ("bar.txt", b"bar")],
+            );
+            assert_tree_matches(
+                &test_repository.repository,
+                &test_repository.repository.find_tree(*tree).unwrap(),
+                &[
+                    ("foo.txt", b"foo1"),
+                    ("bar.txt", b"bar"),
+                    ("baz.txt", b"baz"),
+                ],
+            );
+        }
+
+        /// Trunk:  Base -> X (changes bar.txt)
+        /// Stack:  Base -> A (+ uncommitted change to bar.txt)
+        /// Result: Base -> X -> A' (+ uncommitted change to bar.txt)
+        #[test]
+        fn conflicting_uncommitted_changes_stay_uncommitted() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit =
+                test_repository.commit_tree(None, &[("foo.txt", "foo"), ("bar.txt", "bar")]);
+            let local_a = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo1"), ("bar.txt", "bar")],
+            );
+            let uncommitted = test_repository.commit_tree(
+                Some(&local_a),
+                &[("foo.txt", "foo1"), ("bar.txt", "bar-ours")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar-theirs")],
+            );
+
+            let stack = stack(&local_a, uncommitted.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::RebaseCommitsOnly,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, tree, .. })] = results.as_slice()
+            else {
+                panic!("Expected a single updated stack");
+            };
+            let new_a = test_repository.repository.find_commit(*head).unwrap();
+            assert!(!new_a.is_conflicted());
+            assert_eq!(new_a.message(), local_a.message());
+            assert_eq!(new_a.parent_id(0).unwrap(), upstream_x.id());
+            assert_tree_matches(
+                &test_repository.repository,
+                &test_repository.repository.find_tree(*tree).unwrap(),
+                &[("foo.txt", b"foo1"), ("bar.txt", b"bar-ours")],
+            );
+        }
+    }
+
+    mod commit_then_rebase {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A (+ uncommitted baz.txt)
+        /// Result: Base -> X -> A' -> WIP
+        #[test]
+        fn uncommitted_changes_become_a_commit() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let uncommitted = test_repository
+                .commit_tree(Some(&local_a), &[("foo.txt", "foo1"), ("baz.txt", "baz")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, uncommitted.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::CommitThenRebase {
+                        message: "WIP".to_string(),
+                    },
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, tree, .. })] = results.as_slice()
+            else {
+                panic!("Expected a single updated stack");
+            };
+            let wip = test_repository.repository.find_commit(*head).unwrap();
+            assert_eq!(wip.message(), Some("WIP"));
+            assert_eq!(wip.tree_id(), *tree);
+            assert_commit_tree_matches(
+                &test_repository.repository,
+                &wip,
+                &[
+                    ("foo.txt", b"foo1"),
+                    ("bar.txt", b"bar"),
+                    ("baz.txt", b"baz"),
+                ],
+            );
+
+            let new_a = wip.parent(0).unwrap();
+            assert_eq!(new_a.message(), local_a.message());
+            assert_eq!(new_a.parent_id(0).unwrap(), upstream_x.id());
+        }
+
+        #[test]
+        fn the_commit_is_authored_by_the_project_identity() {
+            let test_repository = TestingRepository::open();
+            let mut config = test_repository
+                .repository
+                .config()
+                .unwrap()
+                .open_level(git2::ConfigLevel::Local)
+                .unwrap();
+            config.set_str("user.name", "Project Author").unwrap();
+            config.set_str("user.email", "author@project.test").unwrap();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let uncommitted =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&base_commit, uncommitted.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::CommitThenRebase {
+                        message: "WIP".to_string(),
+                    },
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice() else {
+                panic!("Expected a single updated stack");
+            };
+            let wip = test_repository.repository.find_commit(*head).unwrap();
+            assert_eq!(wip.message(), Some("WIP"));
+            assert_eq!(wip.author().name(), Some("Project Author"));
+            assert_eq!(wip.author().email(), Some("author@project.test"));
+        }
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stack:  Base, with foo.txt changed but not committed
+        /// Result: Base -> X -> Uncommited changes (conflicted)
+        #[test]
+        fn conflicting_uncommitted_changes_are_committed_by_the_project_identity() {
+            let test_repository = TestingRepository::open();
+            let mut config = test_repository
+                .repository
+                .config()
+                .unwrap()
+                .open_level(git2::ConfigLevel::Local)
+                .unwrap();
+            config.set_str("user.name", "Project Author").unwrap();
+            config.set_str("user.email", "author@project.test").unwrap();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let uncommitted =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+
+            let stack = stack(&base_commit, uncommitted.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice() else {
+                panic!("Expected a single updated stack");
+            };
+            let wip = test_repository.repository.find_commit(*head).unwrap();
+            assert!(wip.is_conflicted());
+            assert_eq!(wip.message(), Some("Uncommited changes"));
+            assert_eq!(wip.parent_id(0).unwrap(), upstream_x.id());
+            assert_eq!(wip.author().name(), Some("Project Author"));
+            assert_eq!(wip.author().email(), Some("author@project.test"));
+        }
+    }
+
+    mod no_op {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> X -> A
+        #[test]
+        fn rebasing_an_already_integrated_branch_is_a_no_op() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            let local_a = test_repository.commit_tree(
+                Some(&upstream_x),
+                &[("foo.txt", "foo1"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            assert!(matches!(results.as_slice(), [(_, IntegrationResult::NoOp)]));
+            assert_eq!(
+                IntegrationOutcome::from_results(
+                    &test_repository.repository,
+                    upstream_x.id(),
+                    &results
+                )
+                .unwrap()
+                .unchanged,
+                vec![stack.id]
+            );
+        }
+    }
+
+    mod stable_commit_ids {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> X -> A -> B, with uncommitted changes
+        #[test]
+        fn branch_based_on_new_target_keeps_its_commits() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            let local_a = test_repository.commit_tree(
+                Some(&upstream_x),
+                &[("foo.txt", "foo1"), ("bar.txt", "bar")],
+            );
+            let local_b = test_repository
+                .commit_tree(Some(&local_a), &[("foo.txt", "foo2"), ("bar.txt", "bar")]);
+            let uncommitted = test_repository.commit_tree(
+                None,
+                &[("foo.txt", "foo2"), ("bar.txt", "bar"), ("baz.txt", "baz")],
+            );
+
+            let stack = stack(&local_b, uncommitted.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            // Neither the commits nor the tree had to change
+            assert!(matches!(results.as_slice(), [(_, IntegrationResult::NoOp)]));
+            assert_eq!(
+                test_repository
+                    .repository
+                    .l(stack.head(), LogUntil::Commit(upstream_x.id()), false)
+                    .unwrap(),
+                vec![local_b.id(), local_a.id()]
+            );
+        }
+    }
+
+    mod integration_state {
+        use super::*;
+
+        #[test]
+        fn round_trip() {
+            let test_repository = TestingRepository::open();
+            let gb_dir = tempfile::tempdir().unwrap();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x = test_repository.commit_tree(Some(&base_commit), &[("bar.txt", "bar")]);
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+            let mut journal = ResolutionJournal::default();
+            journal.record(
+                stack.id,
+                "foo.txt",
+                RecordedChoice::Content(test_repository.repository.blob(b"foo").unwrap()),
+            );
+            let state = IntegrationState {
+                snapshot: context.snapshot(),
+                conflicted_stack_ids: vec![stack.id],
+                journal,
+            };
+
+            assert_eq!(load_integration_state(gb_dir.path()).unwrap(), None);
+
+            save_integration_state(gb_dir.path(), &state).unwrap();
+            assert_eq!(load_integration_state(gb_dir.path()).unwrap(), Some(state));
+
+            clear_integration_state(gb_dir.path()).unwrap();
+            assert_eq!(load_integration_state(gb_dir.path()).unwrap(), None);
+        }
+    }
+
+    mod file_resolution {
+        use super::*;
+
+        /// Base:   a.txt = a, b.txt = b
+        /// Ours:   a.txt = a1, b.txt = b1
+        /// Theirs: a.txt = a2, b.txt = b2
+        #[test]
+        fn resolving_one_conflict_leaves_the_other() {
+            let test_repository = TestingRepository::open();
+            let repository = &test_repository.repository;
+
+            let base_commit = test_repository.commit_tree(None, &[("a.txt", "a"), ("b.txt", "b")]);
+            let ours = test_repository.commit_tree(None, &[("a.txt", "a1"), ("b.txt", "b1")]);
+            let theirs = test_repository.commit_tree(None, &[("a.txt", "a2"), ("b.txt", "b2")]);
+
+            assert_eq!(
+                tree_conflicts(
+                    repository,
+                    base_commit.tree_id(),
+                    ours.tree_id(),
+                    theirs.tree_id(),
+                    None
+                )
+                .unwrap(),
+                vec!["a.txt", "b.txt"]
+            );
+
+            let resolved = resolve_file_conflict(
+                repository,
+                base_commit.tree_id(),
+                ours.tree_id(),
+                theirs.tree_id(),
+                "a.txt",
+                RecordedChoice::Theirs,
+            )
+            .unwrap();
+
+            let resolved_tree = repository.find_tree(resolved).unwrap();
+            let blob_content = |path: &str| {
+                let entry = resolved_tree.get_path(std::path::Path::new(path)).unwrap();
+                let blob = repository.find_blob(entry.id()).unwrap();
+                String::from_utf8(blob.content().to_vec()).unwrap()
+            };
+            assert_eq!(blob_content("a.txt"), "a2");
+            assert_eq!(blob_content("b.txt"), "b1");
+
+            assert_eq!(
+                tree_conflicts(
+                    repository,
+                    base_commit.tree_id(),
+                    resolved,
+                    theirs.tree_id(),
+                    None
+                )
+                .unwrap(),
+                vec!["b.txt"]
+            );
+        }
+
+        #[test]
+        fn resolving_with_content() {
+            let test_repository = TestingRepository::open();
+            let repository = &test_repository.repository;
+
+            let base_commit = test_repository.commit_tree(None, &[("a.txt", "a")]);
+            let ours = test_repository.commit_tree(None, &[("a.txt", "a1")]);
+            let theirs = test_repository.commit_tree(None, &[("a.txt", "a2")]);
+
+            let resolved = resolve_file_conflict(
+                repository,
+                base_commit.tree_id(),
+                ours.tree_id(),
+                theirs.tree_id(),
+                "a.txt",
+                RecordedChoice::Content(repository.blob(b"a1 and a2").unwrap()),
+            )
+            .unwrap();
+
+            let entry = repository
+                .find_tree(resolved)
+                .unwrap()
+                .get_path(std::path::Path::new("a.txt"))
+                .unwrap();
+            assert_eq!(
+                repository.find_blob(entry.id()).unwrap().content(),
+                b"a1 and a2"
+            );
+        }
+
+        #[test]
+        fn resolutions_are_journaled() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("a.txt", "a"), ("b.txt", "b")]);
+            let local_a = test_repository
+                .commit_tree(Some(&base_commit), &[("a.txt", "a1"), ("b.txt", "b1")]);
+            let upstream_x = test_repository
+                .commit_tree(Some(&base_commit), &[("a.txt", "a2"), ("b.txt", "b2")]);
+
+            let stack = stack(&base_commit, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let mut journal = ResolutionJournal::default();
+            apply_file_resolution(
+                &context,
+                stack.id,
+                "a.txt",
+                ConflictChoice::Theirs,
+                &mut journal,
+            )
+            .unwrap();
+            apply_file_resolution(
+                &context,
+                stack.id,
+                "b.txt",
+                ConflictChoice::Ours,
+                &mut journal,
+            )
+            .unwrap();
+            assert!(apply_file_resolution(
+                &context,
+                stack.id,
+                "c.txt",
+                ConflictChoice::Ours,
+                &mut journal
+            )
+            .is_err());
+
+            assert_eq!(
+                journal
+                    .entries
+                    .iter()
+                    .map(|entry| (entry.branch_id, entry.path.as_str(), entry.choice.clone()))
+                    .collect::<Vec<_>>(),
+                vec![
+                    (stack.id, "a.txt", RecordedChoice::Theirs),
+                    (stack.id, "b.txt", RecordedChoice::Ours),
+                ]
+            );
+            assert!(journal.entries[0].timestamp_ms <= journal.entries[1].timestamp_ms);
+        }
+
+        #[test]
+        fn resolving_a_file_without_conflicts_fails() {
+            let test_repository = TestingRepository::open();
+            let repository = &test_repository.repository;
+
+            let base_commit = test_repository.commit_tree(None, &[("a.txt", "a")]);
+            let ours = test_repository.commit_tree(None, &[("a.txt", "a1")]);
+            let theirs = test_repository.commit_tree(None, &[("a.txt", "a")]);
+
+            let result = resolve_file_conflict(
+                repository,
+                base_commit.tree_id(),
+                ours.tree_id(),
+                theirs.tree_id(),
+                "a.txt",
+                RecordedChoice::Ours,
+            );
+            assert_eq!(result.unwrap_err().to_string(), "a.txt is not conflicted");
+        }
+    }
+
+    mod needs_integration {
+        use super::*;
+
+        #[test]
+        fn equal_targets() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let context = integration_context(
+                &test_repository.repository,
+                vec![],
+                base_commit.id(),
+                base_commit.clone(),
+            );
+
+            assert!(!needs_integration(&context));
+        }
+
+        #[test]
+        fn differing_targets() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "x")]);
+            let context = integration_context(
+                &test_repository.repository,
+                vec![],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            assert!(needs_integration(&context));
+        }
+    }
+
+    mod original_branch {
+        use super::*;
+
+        #[test]
+        fn finds_stack_by_id() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_b = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+
+            let stack_a = stack(&local_a, local_a.tree_id());
+            let stack_b = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack_a.clone(), stack_b.clone()],
+                base_commit.id(),
+                base_commit.clone(),
+            );
+
+            let original = context.original_branch(stack_b.id).unwrap();
+            assert_eq!(original.id, stack_b.id);
+            assert_eq!(original.head(), local_b.id());
+            assert_eq!(original.tree, local_b.tree_id());
+        }
+
+        #[test]
+        fn unknown_id_is_none() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let stack = stack(&base_commit, base_commit.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack],
+                base_commit.id(),
+                base_commit.clone(),
+            );
+
+            assert!(context.original_branch(StackId::generate()).is_none());
+        }
+    }
+
+    mod patch_reference {
+        use super::*;
+
+        #[test]
+        fn commit_id_reference_resolves_to_its_commit() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "x")]);
+
+            let reference = StackBranch {
+                head: CommitOrChangeId::CommitId(upstream_x.id().to_string()),
+                name: "managed".to_string(),
+                description: None,
+                pr_number: None,
+                archived: false,
+            };
+
+            let new_target = resolve_patch_reference(
+                &test_repository.repository,
+                &target(base_commit.id()),
+                &[],
+                &reference,
+            )
+            .unwrap();
+
+            assert_eq!(new_target, upstream_x.id());
+        }
+
+        #[test]
+        fn change_id_reference_outside_workspace_errors() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let stack = stack(&base_commit, base_commit.tree_id());
+
+            let reference = StackBranch {
+                head: CommitOrChangeId::ChangeId("some-change".to_string()),
+                name: "managed".to_string(),
+                description: None,
+                pr_number: None,
+                archived: false,
+            };
+
+            let result = resolve_patch_reference(
+                &test_repository.repository,
+                &target(base_commit.id()),
+                &[stack],
+                &reference,
+            );
+
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "The reference managed does not belong to any stack in the workspace"
+            );
+        }
+    }
+
+    mod overlapping_branches {
+        use super::*;
+
+        /// Trunk:   Base -> X
+        /// Stack A: Base -> A (adds baz.txt)
+        /// Stack B: Base -> B (adds baz.txt)
+        /// Stack C: Base -> C (adds qux.txt)
+        #[test]
+        fn stacks_with_the_same_change_overlap() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("baz.txt", "baz")],
+            );
+            let local_b = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("baz.txt", "baz")],
+            );
+            let local_c = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("qux.txt", "qux")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack_a = stack(&local_a, local_a.tree_id());
+            let stack_b = stack(&local_b, local_b.tree_id());
+            let stack_c = stack(&local_c, local_c.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack_a.clone(), stack_b.clone(), stack_c],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            assert_eq!(
+                overlapping_branches(&context).unwrap(),
+                vec![(stack_a.id, stack_b.id)]
+            );
+        }
+    }
+
+    mod resolution_approach {
+        use super::*;
+
+        #[test]
+        fn classification() {
+            assert!(!ResolutionApproach::Rebase.is_destructive());
+            assert!(ResolutionApproach::Rebase.rewrites_history());
+
+            assert!(!ResolutionApproach::Merge.is_destructive());
+            assert!(!ResolutionApproach::Merge.rewrites_history());
+
+            assert!(ResolutionApproach::Unapply.is_destructive());
+            assert!(!ResolutionApproach::Unapply.rewrites_history());
+
+            assert!(ResolutionApproach::Delete.is_destructive());
+            assert!(!ResolutionApproach::Delete.rewrites_history());
+
+            assert!(!ResolutionApproach::RebaseCommitsOnly.is_destructive());
+            assert!(ResolutionApproach::RebaseCommitsOnly.rewrites_history());
+
+            assert!(!ResolutionApproach::MergeIntoTarget.is_destructive());
+            assert!(!ResolutionApproach::MergeIntoTarget.rewrites_history());
+
+            let commit_then_rebase = ResolutionApproach::CommitThenRebase {
+                message: "wip".into(),
+            };
+            assert!(!commit_then_rebase.is_destructive());
+            assert!(commit_then_rebase.rewrites_history());
+        }
+    }
+
+    mod conflict_preferences {
+        use super::*;
+
+        #[test]
+        fn preferred_paths_are_resolved() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit =
+                test_repository.commit_tree(None, &[("Cargo.lock", "lock"), ("src.rs", "src")]);
+            let local_a = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("Cargo.lock", "lock-ours"), ("src.rs", "src-ours")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("Cargo.lock", "lock-theirs"), ("src.rs", "src-theirs")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            )
+            .with_conflict_preferences(HashMap::from([(
+                "Cargo.lock".to_string(),
+                ConflictPreference::Theirs,
+            )]));
+
+            let tree = apply_conflict_preferences(&context, stack.id).unwrap();
+
+            assert_tree_matches(
+                &test_repository.repository,
+                &test_repository.repository.find_tree(tree).unwrap(),
+                &[("Cargo.lock", b"lock-theirs"), ("src.rs", b"src-ours")],
+            );
+            assert_eq!(
+                conflicted_files(&context, tree).unwrap(),
+                vec!["src.rs".to_string()]
+            );
+        }
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A
+        /// Result: Base -> X -> A', where A' takes the preferred version of the lock file
+        #[test]
+        fn preferred_paths_are_resolved_when_integrating() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("Cargo.lock", "lock")]);
+            let local_a = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("Cargo.lock", "lock-ours"), ("src.rs", "src-ours")],
+            );
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("Cargo.lock", "lock-theirs")]);
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            )
+            .with_conflict_preferences(HashMap::from([(
+                "Cargo.lock".to_string(),
+                ConflictPreference::Theirs,
+            )]));
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let IntegrationResult::UpdatedObjects { head, .. } = results[0].1 else {
+                panic!("Expected the stack to be updated")
+            };
+            let head = test_repository.repository.find_commit(head).unwrap();
+            assert!(!head.is_conflicted());
+            assert_commit_tree_matches(
+                &test_repository.repository,
+                &head,
+                &[("Cargo.lock", b"lock-theirs"), ("src.rs", b"src-ours")],
+            );
+        }
+    }
+
+    mod affected_by_rebase {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack1: Base -> A
+        /// Stack2: Base -> A -> B
+        /// Stack3: Base -> C
+        #[test]
+        fn only_dependent_stacks_are_affected() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("a.txt", "a")]);
+            let local_b = test_repository.commit_tree(
+                Some(&local_a),
+                &[("foo.txt", "foo"), ("a.txt", "a"), ("b.txt", "b")],
+            );
+            let local_c = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("c.txt", "c")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack_a = stack(&local_a, local_a.tree_id());
+            let stack_b = stack(&local_b, local_b.tree_id());
+            let stack_c = stack(&local_c, local_c.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack_a.clone(), stack_b.clone(), stack_c.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert_eq!(
+                affected_by_rebase(&context, stack_a.id).unwrap(),
+                vec![stack_a.id, stack_b.id]
+            );
+            assert_eq!(
+                affected_by_rebase(&context, stack_c.id).unwrap(),
+                vec![stack_c.id]
+            );
+        }
+    }
+
+    mod all_conflicts {
+        use super::*;
+
+        #[test]
+        fn lists_the_conflicts_of_each_stack() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository
+                .commit_tree(None, &[("a.txt", "a"), ("b.txt", "b"), ("c.txt", "c")]);
+            let local_a = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("a.txt", "a1"), ("b.txt", "b"), ("c.txt", "c")],
+            );
+            let local_b = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("a.txt", "a"), ("b.txt", "b1"), ("c.txt", "c")],
+            );
+            let local_c = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("a.txt", "a"), ("b.txt", "b"), ("c.txt", "c1")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("a.txt", "a2"), ("b.txt", "b2"), ("c.txt", "c")],
+            );
+
+            let stack_a = stack(&local_a, local_a.tree_id());
+            let stack_b = stack(&local_b, local_b.tree_id());
+            let stack_c = stack(&local_c, local_c.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack_a.clone(), stack_b.clone(), stack_c],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert_eq!(
+                all_conflicts(&context).unwrap(),
+                vec![
+                    (stack_a.id, vec!["a.txt".to_string()]),
+                    (stack_b.id, vec!["b.txt".to_string()]),
+                ]
+            );
+        }
+
+        #[test]
+        fn paths_conflicting_in_several_stacks_are_listed_once() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("a.txt", "a"), ("b.txt", "b")]);
+            let local_a =
+                test_repository.commit_tree(Some(&base_commit), &[("a.txt", "a1"), ("b.txt", "b")]);
+            let local_b = test_repository
+                .commit_tree(Some(&base_commit), &[("a.txt", "a3"), ("b.txt", "b1")]);
+            let upstream_x = test_repository
+                .commit_tree(Some(&base_commit), &[("a.txt", "a2"), ("b.txt", "b2")]);
+
+            let context = integration_context(
+                &test_repository.repository,
+                vec![
+                    stack(&local_a, local_a.tree_id()),
+                    stack(&local_b, local_b.tree_id()),
+                ],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert_eq!(
+                all_conflicting_paths(&context).unwrap(),
+                BTreeSet::from(["a.txt".to_string(), "b.txt".to_string()])
+            );
+        }
+    }
+
+    mod worktree_changes {
+        use super::*;
+
+        /// Trunk:  Base -> X (adds bar.txt)
+        /// Stack:  Base -> A (changes foo.txt), with another change to foo.txt only on disk
+        #[test]
+        fn changes_on_disk_are_taken_into_account() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            std::fs::write(test_repository.tempdir.path().join("foo.txt"), "foo2").unwrap();
+
+            let mut stack = stack(&local_a, local_a.tree_id());
+            stack.selected_for_changes = Some(1);
+            let tree_conflicted = |context: &UpstreamIntegrationContext| {
+                let StackStatuses::UpdatesRequired(statuses) =
+                    upstream_integration_statuses(context).unwrap()
+                else {
+                    panic!("Expected updates to be required");
+                };
+                let [(_, status)] = statuses.as_slice() else {
+                    panic!("Expected a single status");
+                };
+                status.tree_status == TreeStatus::Conflicted
+            };
+
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+            assert!(!tree_conflicted(&context));
+
+            let context = context.with_worktree_changes(true);
+            assert!(tree_conflicted(&context));
+        }
+    }
+
+    mod merge_into_target {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A
+        /// Result: Stack stays at A, with the tip Base -> X -> Merge (second parent A)
+        #[test]
+        fn the_new_target_is_the_first_parent() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    UpdatableResolutionApproach::MergeIntoTarget.into(),
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::IntegrationTip { tip })] = results.as_slice() else {
+                panic!("Expected an integration tip");
+            };
+            let tip = test_repository.repository.find_commit(*tip).unwrap();
+            assert_eq!(
+                tip.parent_ids().collect::<Vec<_>>(),
+                vec![upstream_x.id(), local_a.id()]
+            );
+            assert_commit_tree_matches(
+                &test_repository.repository,
+                &tip,
+                &[("foo.txt", b"foo1"), ("bar.txt", b"bar")],
+            );
+
+            let outcome = IntegrationOutcome::from_results(
+                &test_repository.repository,
+                upstream_x.id(),
+                &results,
+            )
+            .unwrap();
+            assert_eq!(outcome.integrated, vec![]);
+            assert_eq!(
+                outcome.integration_tips,
+                vec![IntegrationTip {
+                    stack_id: stack.id,
+                    tip: tip.id(),
+                }]
+            );
+        }
+        fn merge_into_target(stack: &Stack) -> Resolution {
+            Resolution::new(stack.id, stack.tree, ResolutionApproach::MergeIntoTarget)
+        }
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A
+        /// Result: The workspace has the changes of both X and A
+        #[test]
+        fn the_tip_is_previewed_on_top_of_the_new_target() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let tree = integrated_workspace_tree(&context, &[merge_into_target(&stack)]).unwrap();
+            assert_tree_matches(
+                &test_repository.repository,
+                &test_repository.repository.find_tree(tree).unwrap(),
+                &[("foo.txt", b"foo1"), ("bar.txt", b"bar")],
+            );
+            assert_eq!(
+                preview_integrated_tree(
+                    &context,
+                    stack.id,
+                    UpdatableResolutionApproach::MergeIntoTarget
+                )
+                .unwrap(),
+                tree
+            );
+        }
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stack:  Base -> A (changes foo.txt differently)
+        /// Result: The tip is conflicted, and merging into the target isn't offered
+        #[test]
+        fn a_conflicted_tip_is_reported() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results =
+                compute_resolutions(&context, &[merge_into_target(&stack)], None).unwrap();
+            let outcome = IntegrationOutcome::from_results(
+                &test_repository.repository,
+                upstream_x.id(),
+                &results,
+            )
+            .unwrap();
+            assert_eq!(outcome.conflicted, vec![stack.id]);
+            assert_eq!(outcome.integration_tips.len(), 1);
+
+            let StackStatuses::UpdatesRequired(statuses) =
+                upstream_integration_statuses(&context).unwrap()
+            else {
+                panic!("Expected updates to be required");
+            };
+            let [(_, status)] = statuses.as_slice() else {
+                panic!("Expected a single status");
+            };
+            assert!(!status.resolution_acceptable(&ResolutionApproach::MergeIntoTarget));
+            assert!(status.resolution_acceptable(&ResolutionApproach::Rebase));
+        }
+    }
+
+    mod nearest_integrated_ancestor {
+        use super::*;
+
+        /// Trunk:  Base -> S -> X
+        /// Stack:  Base -> S -> A -> B
+        #[test]
+        fn the_shared_tip_is_returned() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let shared = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_a = test_repository
+                .commit_tree(Some(&shared), &[("foo.txt", "foo1"), ("bar.txt", "bar")]);
+            let local_b = test_repository
+                .commit_tree(Some(&local_a), &[("foo.txt", "foo1"), ("bar.txt", "bar1")]);
+            let upstream_x = test_repository.commit_tree(Some(&shared), &[("foo.txt", "foo2")]);
+
+            let stack = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert_eq!(
+                nearest_integrated_ancestor(&context, stack.id).unwrap(),
+                Some(shared.id())
+            );
+        }
+
+        /// Trunk:  X
+        /// Stack:  Base -> A
+        #[test]
+        fn unrelated_histories_have_none() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x = test_repository.commit_tree(None, &[("bar.txt", "bar")]);
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert_eq!(
+                nearest_integrated_ancestor(&context, stack.id).unwrap(),
+                None
+            );
+        }
+    }
+
+    mod ancestry_cache {
+        use super::*;
+
+        #[test]
+        fn repeated_checks_are_answered_from_the_cache() {
+            let test_repository = TestingRepository::open();
+            let repository = &test_repository.repository;
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+
+            let cache = AncestryCache::default();
+            for _ in 0..2 {
+                assert!(cache
+                    .is_ancestor(repository, base_commit.id(), local_a.id())
+                    .unwrap());
+                assert!(!cache
+                    .is_ancestor(repository, upstream_x.id(), local_a.id())
+                    .unwrap());
+                assert!(cache
+                    .is_ancestor(repository, local_a.id(), local_a.id())
+                    .unwrap());
+            }
+            assert_eq!(
+                cache.entries.borrow().len(),
+                2,
+                "identical commits aren't cached"
+            );
+
+            cache
+                .entries
+                .borrow_mut()
+                .insert((upstream_x.id(), local_a.id()), true);
+            assert!(
+                cache
+                    .is_ancestor(repository, upstream_x.id(), local_a.id())
+                    .unwrap(),
+                "the remembered result is returned without asking the repository"
+            );
+
+            cache.clear();
+            assert!(!cache
+                .is_ancestor(repository, upstream_x.id(), local_a.id())
+                .unwrap());
+        }
+    }
+
+    mod skip_empty {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base
+        #[test]
+        fn a_workspace_of_empty_branches_is_up_to_date() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&base_commit, base_commit.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert!(matches!(
+                upstream_integration_statuses(&context).unwrap(),
+                StackStatuses::UpdatesRequired(_)
+            ));
+
+            let context = context.with_skip_empty(true);
+            assert_eq!(
+                upstream_integration_statuses(&context).unwrap(),
+                StackStatuses::UpToDate
+            );
+        }
+
+        /// Trunk:  Base -> X
+        /// Stacks: Base, and Base -> A
+        #[test]
+        fn only_the_empty_branches_are_left_out() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let empty = stack(&base_commit, base_commit.tree_id());
+            let with_commits = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![empty, with_commits.clone()],
+                base_commit.id(),
+                upstream_x,
+            )
+            .with_skip_empty(true);
+
+            let StackStatuses::UpdatesRequired(statuses) =
+                upstream_integration_statuses(&context).unwrap()
+            else {
+                panic!("Expected updates to be required");
+            };
+            let [(stack_id, status)] = statuses.as_slice() else {
+                panic!("Expected a single status");
+            };
+            assert_eq!(*stack_id, with_commits.id);
+            assert_eq!(
+                status.branch_statuses[0].status,
+                BranchStatus::SaflyUpdatable
+            );
+        }
+    }
+
+    mod unapplied_stack_statuses {
+        use super::*;
+
+        #[test]
+        fn unapplied_stacks_get_a_status_of_their_own() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_b = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+
+            let applied = stack(&local_b, local_b.tree_id());
+            let mut unapplied = stack(&local_a, local_a.tree_id());
+            unapplied.in_workspace = false;
+            let mut context = integration_context(
+                &test_repository.repository,
+                vec![applied.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+            context.stacks_not_in_workspace = vec![unapplied.clone()];
+
+            let statuses = unapplied_stack_statuses(&context).unwrap();
+
+            assert_eq!(
+                statuses,
+                vec![(
+                    unapplied.id,
+                    stack_status(
+                        &test_repository.repository,
+                        base_commit.id(),
+                        upstream_x.id(),
+                        &unapplied
+                    )
+                )]
+            );
+            assert!(statuses[0].1.is_conflicted());
+            let StackStatuses::UpdatesRequired(applied_statuses) =
+                upstream_integration_statuses(&context).unwrap()
+            else {
+                panic!("Expected updates to be required");
+            };
+            assert_eq!(
+                applied_statuses
+                    .iter()
+                    .map(|(id, _)| *id)
+                    .collect::<Vec<_>>(),
+                vec![applied.id]
+            );
+        }
+    }
+
+    mod mergetool {
+        use super::*;
+
+        #[cfg(unix)]
+        #[test]
+        fn the_configured_tool_resolves_the_conflict() {
+            let test_repository = TestingRepository::open();
+            let mut config = test_repository
+                .repository
+                .config()
+                .unwrap()
+                .open_level(git2::ConfigLevel::Local)
+                .unwrap();
+            config.set_str("merge.tool", "concat").unwrap();
+            config
+                .set_str(
+                    "mergetool.concat.cmd",
+                    r#"cat "$LOCAL" "$REMOTE" > "$MERGED""#,
+                )
+                .unwrap();
+
+            let base_commit = test_repository.commit_tree(None, &[("a.txt", "a\n")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("a.txt", "a1\n")]);
+            let upstream_x = test_repository.commit_tree(Some(&base_commit), &[("a.txt", "a2\n")]);
+
+            let stack = stack(&base_commit, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let mut journal = ResolutionJournal::default();
+            let resolved = launch_mergetool(&context, stack.id, "a.txt", &mut journal).unwrap();
+
+            assert_tree_matches(
+                &test_repository.repository,
+                &test_repository.repository.find_tree(resolved).unwrap(),
+                &[("a.txt", b"a1\na2\n")],
+            );
+            assert_eq!(
+                journal.entries[0].choice,
+                RecordedChoice::Content(test_repository.repository.blob(b"a1\na2\n").unwrap())
+            );
+        }
+
+        #[cfg(unix)]
+        #[test]
+        fn an_unresolved_result_is_rejected_unless_the_exit_code_is_trusted() {
+            let test_repository = TestingRepository::open();
+            let mut config = test_repository
+                .repository
+                .config()
+                .unwrap()
+                .open_level(git2::ConfigLevel::Local)
+                .unwrap();
+            config.set_str("merge.tool", "noop").unwrap();
+            config.set_str("mergetool.noop.cmd", "true").unwrap();
+
+            let base_commit = test_repository.commit_tree(None, &[("a.txt", "a\n")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("a.txt", "a1\n")]);
+            let upstream_x = test_repository.commit_tree(Some(&base_commit), &[("a.txt", "a2\n")]);
+
+            let stack = stack(&base_commit, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let mut journal = ResolutionJournal::default();
+            let err = launch_mergetool(&context, stack.id, "a.txt", &mut journal).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "The merge tool noop left conflict markers in a.txt"
+            );
+            assert!(journal.entries.is_empty());
+
+            config
+                .set_bool("mergetool.noop.trustExitCode", true)
+                .unwrap();
+            let resolved = launch_mergetool(&context, stack.id, "a.txt", &mut journal).unwrap();
+            let entry = test_repository
+                .repository
+                .find_tree(resolved)
+                .unwrap()
+                .get_path(Path::new("a.txt"))
+                .unwrap();
+            let content = test_repository
+                .repository
+                .find_blob(entry.id())
+                .unwrap()
+                .content()
+                .to_vec();
+            assert!(has_conflict_markers(&content));
+        }
+
+        #[test]
+        fn a_missing_tool_is_an_error() {
+            let test_repository = TestingRepository::open();
+            // An empty tool in the repository overrides one configured globally.
+            test_repository
+                .repository
+                .config()
+                .unwrap()
+                .open_level(git2::ConfigLevel::Local)
+                .unwrap()
+                .set_str("merge.tool", "")
+                .unwrap();
+
+            let base_commit = test_repository.commit_tree(None, &[("a.txt", "a")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("a.txt", "a1")]);
+            let upstream_x = test_repository.commit_tree(Some(&base_commit), &[("a.txt", "a2")]);
+
+            let stack = stack(&base_commit, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let mut journal = ResolutionJournal::default();
+            let err = launch_mergetool(&context, stack.id, "a.txt", &mut journal).unwrap_err();
+            assert_eq!(err.to_string(), "No merge.tool is configured");
+            assert!(journal.entries.is_empty());
+        }
+    }
+
+    mod integrated_workspace_tree {
+        use super::*;
+
+        fn rebase(stack: &Stack) -> Resolution {
+            Resolution::new(stack.id, stack.tree, ResolutionApproach::Rebase)
+        }
+
+        #[test]
+        fn the_integrated_stacks_are_merged() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("a.txt", "a")]);
+            let local_b = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("b.txt", "b")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack_a = stack(&local_a, local_a.tree_id());
+            let stack_b = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack_a.clone(), stack_b.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let tree =
+                integrated_workspace_tree(&context, &[rebase(&stack_a), rebase(&stack_b)]).unwrap();
+
+            assert_tree_matches(
+                &test_repository.repository,
+                &test_repository.repository.find_tree(tree).unwrap(),
+                &[
+                    ("foo.txt", b"foo"),
+                    ("bar.txt", b"bar"),
+                    ("a.txt", b"a"),
+                    ("b.txt", b"b"),
+                ],
+            );
+        }
+
+        #[test]
+        fn stacks_conflicting_with_each_other_are_reported() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_b = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack_a = stack(&local_a, local_a.tree_id());
+            let stack_b = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack_a.clone(), stack_b.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let err = integrated_workspace_tree(&context, &[rebase(&stack_a), rebase(&stack_b)])
+                .unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<ConflictingStacks>(),
+                Some(&ConflictingStacks {
+                    paths: vec!["foo.txt".to_string()]
+                })
+            );
+        }
+    }
+
+    mod conflict_risk {
+        use super::*;
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stacks: Base -> A (adds bar.txt), and Base -> B (changes foo.txt)
+        #[test]
+        fn overlapping_changes_are_risky() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            let local_b = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo\nb")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "x\nfoo")]);
+
+            let disjoint = stack(&local_a, local_a.tree_id());
+            let overlapping = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![disjoint.clone(), overlapping.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert_eq!(
+                conflict_risk(&context, disjoint.id).unwrap(),
+                RiskLevel::Low
+            );
+            assert_eq!(
+                conflict_risk(&context, overlapping.id).unwrap(),
+                RiskLevel::High
+            );
+        }
+    }
+
+    mod ignore_whitespace {
+        use super::*;
+
+        /// Base:   foo.txt = a, b, c
+        /// Stack:  foo.txt = a, x, c
+        /// Trunk:  foo.txt = a, b, c with CRLF line endings
+        #[test]
+        fn line_ending_changes_do_not_conflict() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "a\nb\nc\n")]);
+            let local_a =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "a\nx\nc\n")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "a\nb\r\nc\n")]);
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+            assert_eq!(
+                analyze_conflicts(&context, stack.tree).unwrap(),
+                ConflictAnalysis {
+                    conflicts: vec!["foo.txt".into()],
+                    whitespace_resolved: vec![],
+                }
+            );
+
+            let context = context.with_ignore_whitespace(true);
+            assert_eq!(
+                conflicted_files(&context, stack.tree).unwrap(),
+                Vec::<String>::new()
+            );
+            assert_eq!(
+                analyze_conflicts(&context, stack.tree).unwrap(),
+                ConflictAnalysis {
+                    conflicts: vec![],
+                    whitespace_resolved: vec!["foo.txt".into()],
+                }
+            );
+        }
+
+        /// Trunk:  Base -> X (changes the line ending of foo.txt)
+        /// Stack:  Base -> A (changes the content of foo.txt)
+        /// Result: Base -> X -> A', where A' keeps the stack's version of foo.txt
+        #[test]
+        fn integrating_takes_the_side_with_more_than_whitespace_changes() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "a\nb\nc\n")]);
+            let local_a =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "a\nx\nc\n")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "a\nb\r\nc\n"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            )
+            .with_ignore_whitespace(true);
+
+            let StackStatuses::UpdatesRequired(statuses) =
+                upstream_integration_statuses(&context).unwrap()
+            else {
+                panic!("Expected the stack to need an update")
+            };
+            assert!(!statuses[0].1.is_conflicted());
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let IntegrationResult::UpdatedObjects { head, .. } = results[0].1 else {
+                panic!("Expected the stack to be updated")
+            };
+            let head = test_repository.repository.find_commit(head).unwrap();
+            assert!(!head.is_conflicted());
+            assert_commit_tree_matches(
+                &test_repository.repository,
+                &head,
+                &[("foo.txt", b"a\nx\nc\n"), ("bar.txt", b"bar")],
+            );
+            assert_eq!(
+                context.whitespace_resolved(),
+                vec![WhitespaceResolved {
+                    stack_id: stack.id,
+                    paths: vec!["foo.txt".to_string()],
+                }]
+            );
+        }
+    }
+
+    mod resolved_tree {
+        use super::*;
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stack:  Base -> A (changes foo.txt)
+        /// Result: Base -> A -> Merge (with the resolved tree)
+        #[test]
+        fn the_resolved_tree_is_used_instead_of_merging() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let resolved_tree = test_repository
+                .commit_tree(Some(&local_a), &[("foo.txt", "foo1 and foo2")])
+                .tree_id();
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+            assert_eq!(
+                stack_status(
+                    &test_repository.repository,
+                    base_commit.id(),
+                    upstream_x.id(),
+                    &stack
+                )
+                .tree_status,
+                TreeStatus::Conflicted
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution {
+                    resolved_tree: Some(resolved_tree),
+                    ..Resolution::new(stack.id, stack.tree, ResolutionApproach::Merge)
+                }],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, tree, .. })] = results.as_slice()
+            else {
+                panic!("Expected the stack to be updated");
+            };
+            let head = test_repository.repository.find_commit(*head).unwrap();
+            assert_eq!(*tree, resolved_tree);
+            assert_eq!(head.tree_id(), resolved_tree);
+            assert!(!head.is_conflicted());
+            assert_eq!(
+                head.parent_ids().collect::<Vec<_>>(),
+                vec![local_a.id(), upstream_x.id()]
+            );
+        }
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stack:  Base -> A (changes foo.txt), with bar.txt uncommitted
+        /// Result: Base -> A -> Merge (with the resolved tree), with bar.txt uncommitted
+        #[test]
+        fn uncommitted_changes_are_carried_over() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let resolved_tree = test_repository
+                .commit_tree(Some(&local_a), &[("foo.txt", "foo1 and foo2")])
+                .tree_id();
+            let worktree = test_repository
+                .commit_tree(Some(&local_a), &[("foo.txt", "foo1"), ("bar.txt", "bar")])
+                .tree_id();
+
+            let stack = stack(&local_a, worktree);
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution {
+                    resolved_tree: Some(resolved_tree),
+                    ..Resolution::new(stack.id, stack.tree, ResolutionApproach::Merge)
+                }],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, tree, .. })] = results.as_slice()
+            else {
+                panic!("Expected the stack to be updated");
+            };
+            let head = test_repository.repository.find_commit(*head).unwrap();
+            assert_eq!(head.tree_id(), resolved_tree);
+            assert_tree_matches(
+                &test_repository.repository,
+                &test_repository.repository.find_tree(*tree).unwrap(),
+                &[("foo.txt", b"foo1 and foo2"), ("bar.txt", b"bar")],
+            );
+        }
+
+        #[test]
+        fn a_tree_with_conflicts_is_refused() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let conflicted_tree = test_repository
+                .commit_tree(
+                    Some(&local_a),
+                    &[("foo.txt", "foo1"), (".conflict-side-0/foo.txt", "foo1")],
+                )
+                .tree_id();
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let error = compute_resolutions(
+                &context,
+                &[Resolution {
+                    resolved_tree: Some(conflicted_tree),
+                    ..Resolution::new(stack.id, stack.tree, ResolutionApproach::Merge)
+                }],
+                None,
+            )
+            .unwrap_err();
+            assert!(error.to_string().contains("still contains conflicts"));
+        }
+    }
+
+    mod export_patch_series {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A -> B
+        #[test]
+        fn one_patch_per_commit_oldest_first() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree_with_message(
+                Some(&base_commit),
+                "Change foo\n\nWith some details.",
+                &[("foo.txt", "foo1")],
+            );
+            let local_b = test_repository.commit_tree_inner(
+                Some(&local_a),
+                "Add bar",
+                &[("foo.txt", "foo1"), ("bar.txt", "bar")],
+                Some("some-change-id"),
+            );
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+
+            let stack = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let series = export_patch_series(&context, stack.id).unwrap();
+
+            let patches: Vec<_> = series.split("\nFrom ").collect();
+            assert_eq!(patches.len(), 2, "{series}");
+            assert!(patches[0].starts_with(&format!("From {}", local_a.id())));
+            assert!(patches[1].starts_with(&local_b.id().to_string()));
+
+            let subjects: Vec<_> = series
+                .lines()
+                .filter(|line| line.starts_with("Subject: "))
+                .collect();
+            assert_eq!(
+                subjects,
+                vec![
+                    "Subject: [PATCH 1/2] Change foo",
+                    "Subject: [PATCH 2/2] Add bar"
+                ]
+            );
+            assert!(patches[0].contains("With some details."));
+            assert!(patches[0].contains("+foo1"));
+            assert!(patches[1].contains("Change-Id: some-change-id"));
+            assert!(patches[1].contains("+bar"));
+        }
+    }
+
+    mod uncommitted_conflicts {
+        use super::*;
+
+        /// Trunk:  Base -> X (changes a.txt)
+        /// Stack:  Base -> A (changes b.txt) (+ uncommitted change to a.txt)
+        #[test]
+        fn only_the_uncommitted_changes_are_considered() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("a.txt", "a"), ("b.txt", "b")]);
+            let local_a =
+                test_repository.commit_tree(Some(&base_commit), &[("a.txt", "a"), ("b.txt", "b1")]);
+            let uncommitted =
+                test_repository.commit_tree(Some(&local_a), &[("a.txt", "a1"), ("b.txt", "b1")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("a.txt", "a2"), ("b.txt", "b")]);
+
+            let stack = stack(&local_a, uncommitted.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            assert_eq!(
+                stack_status(
+                    &test_repository.repository,
+                    base_commit.id(),
+                    upstream_x.id(),
+                    &stack
+                )
+                .branch_statuses[0]
+                    .status,
+                BranchStatus::SaflyUpdatable
+            );
+            assert_eq!(
+                uncommitted_conflicts(&context, stack.id).unwrap(),
+                vec!["a.txt"]
+            );
+        }
+
+        #[test]
+        fn no_uncommitted_changes_means_no_conflicts() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("a.txt", "a")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("a.txt", "a1")]);
+            let upstream_x = test_repository.commit_tree(Some(&base_commit), &[("a.txt", "a2")]);
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert!(uncommitted_conflicts(&context, stack.id)
+                .unwrap()
+                .is_empty());
+        }
+    }
+
+    mod excluded_paths {
+        use super::*;
+
+        #[test]
+        fn excluded_paths_keep_the_stack_version() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(
+                None,
+                &[("schema.generated.rs", "schema"), ("src.rs", "src")],
+            );
+            let local_a = test_repository.commit_tree(
+                Some(&base_commit),
+                &[
+                    ("schema.generated.rs", "schema-ours"),
+                    ("src.rs", "src-ours"),
+                ],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[
+                    ("schema.generated.rs", "schema-theirs"),
+                    ("src.rs", "src-theirs"),
+                ],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            )
+            .with_excluded_paths(vec!["*.generated.rs".to_string()])
+            .with_conflict_preferences(HashMap::from([(
+                "src.rs".to_string(),
+                ConflictPreference::Theirs,
+            )]));
+
+            assert_eq!(
+                conflicted_files(&context, stack.tree).unwrap(),
+                vec!["src.rs".to_string()]
+            );
+            assert_eq!(
+                all_conflicts(&context).unwrap(),
+                vec![(stack.id, vec!["src.rs".to_string()])]
+            );
+
+            let tree = apply_conflict_preferences(&context, stack.id).unwrap();
+            assert_tree_matches(
+                &test_repository.repository,
+                &test_repository.repository.find_tree(tree).unwrap(),
+                &[
+                    ("schema.generated.rs", b"schema-ours"),
+                    ("src.rs", b"src-theirs"),
+                ],
+            );
+        }
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A
+        /// Result: Base -> X -> A', where A' keeps its version of the excluded file
+        #[test]
+        fn excluded_paths_keep_the_stack_version_when_integrating() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit =
+                test_repository.commit_tree(None, &[("schema.generated.rs", "schema")]);
+            let local_a = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("schema.generated.rs", "schema-ours")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("schema.generated.rs", "schema-theirs"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            )
+            .with_excluded_paths(vec!["*.generated.rs".to_string()]);
+
+            let StackStatuses::UpdatesRequired(statuses) =
+                upstream_integration_statuses(&context).unwrap()
+            else {
+                panic!("Expected the stack to need an update")
+            };
+            assert_eq!(
+                statuses[0].1.branch_statuses[0].status,
+                BranchStatus::SaflyUpdatable
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let IntegrationResult::UpdatedObjects { head, .. } = results[0].1 else {
+                panic!("Expected the stack to be updated")
+            };
+            let head = test_repository.repository.find_commit(head).unwrap();
+            assert!(!head.is_conflicted());
+            assert_eq!(head.parent_id(0).unwrap(), upstream_x.id());
+            assert_commit_tree_matches(
+                &test_repository.repository,
+                &head,
+                &[("schema.generated.rs", b"schema-ours"), ("bar.txt", b"bar")],
+            );
+        }
+
+        #[test]
+        fn patterns() {
+            assert!(path_matches("target/", "target/debug/build.rs"));
+            assert!(!path_matches("target/", "src/target.rs"));
+            assert!(path_matches("*.generated.rs", "src/schema.generated.rs"));
+            assert!(path_matches("Cargo.lock", "Cargo.lock"));
+            assert!(!path_matches("Cargo.lock", "crates/Cargo.lock"));
+        }
+    }
+
+    mod parse_resolutions {
+        use super::*;
+
+        fn statuses(stack: &Stack) -> StackStatuses {
+            StackStatuses::UpdatesRequired(vec![(
+                stack.id,
+                StackStatus::create(
+                    TreeStatus::SaflyUpdatable,
+                    vec![NameAndStatus {
+                        name: "branch".to_string(),
+                        status: BranchStatus::SaflyUpdatable,
+                        commit_counts: CommitCounts::default(),
+                    }],
+                )
+                .unwrap(),
+            )])
+        }
+
+        #[test]
+        fn valid_payload() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let stack = stack(&base_commit, base_commit.tree_id());
+
+            let json = format!(
+                r#"[{{"branchId":"{}","branchTree":"{}","approach":{{"type":"rebase"}}}}]"#,
+                stack.id, stack.tree
+            );
+
+            assert_eq!(
+                parse_resolutions(&json, &statuses(&stack)).unwrap(),
+                vec![Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase
+                )]
+            );
+        }
+
+        #[test]
+        fn unknown_branch_id() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let stack = stack(&base_commit, base_commit.tree_id());
+
+            let json = format!(
+                r#"[{{"branchId":"{}","branchTree":"{}","approach":{{"type":"rebase"}}}}]"#,
+                StackId::generate(),
+                stack.tree
+            );
+
+            assert_eq!(
+                parse_resolutions(&json, &statuses(&stack))
+                    .unwrap_err()
+                    .to_string(),
+                "Chosen resolutions do not match current integration statuses"
+            );
+        }
+    }
+
+    mod status_counts {
+        use super::*;
+
+        fn stack_status(tree_status: TreeStatus, status: BranchStatus) -> StackStatus {
+            StackStatus::create(
+                tree_status,
+                vec![NameAndStatus {
+                    name: "branch".to_string(),
+                    status,
+                    commit_counts: CommitCounts::default(),
+                }],
+            )
+            .unwrap()
+        }
+
+        #[test]
+        fn up_to_date() {
+            assert_eq!(StackStatuses::UpToDate.conflicted_count(), 0);
+            assert_eq!(StackStatuses::UpToDate.updatable_count(), 0);
+        }
+
+        #[test]
+        fn updates_required() {
+            let statuses = StackStatuses::UpdatesRequired(vec![
+                (
+                    StackId::generate(),
+                    stack_status(TreeStatus::SaflyUpdatable, BranchStatus::SaflyUpdatable),
+                ),
+                (
+                    StackId::generate(),
+                    stack_status(TreeStatus::Conflicted, BranchStatus::SaflyUpdatable),
+                ),
+                (
+                    StackId::generate(),
+                    stack_status(
+                        TreeStatus::Empty,
+                        BranchStatus::Conflicted { rebasable: true },
+                    ),
+                ),
+                (
+                    StackId::generate(),
+                    stack_status(TreeStatus::Empty, BranchStatus::Integrated),
+                ),
+            ]);
+
+            assert_eq!(statuses.conflicted_count(), 2);
+            assert_eq!(statuses.updatable_count(), 2);
+        }
+
+        #[test]
+        fn grouped_by_kind() {
+            let conflicted = StackId::generate();
+            let conflicted_tree = StackId::generate();
+            let safe = StackId::generate();
+            let empty = StackId::generate();
+            let integrated = StackId::generate();
+            let statuses = StackStatuses::UpdatesRequired(vec![
+                (
+                    conflicted,
+                    stack_status(
+                        TreeStatus::Empty,
+                        BranchStatus::Conflicted { rebasable: false },
+                    ),
+                ),
+                (
+                    safe,
+                    stack_status(TreeStatus::Empty, BranchStatus::SaflyUpdatable),
+                ),
+                (
+                    conflicted_tree,
+                    stack_status(TreeStatus::Conflicted, BranchStatus::Integrated),
+                ),
+                (empty, stack_status(TreeStatus::Empty, BranchStatus::Empty)),
+                (
+                    integrated,
+                    stack_status(TreeStatus::Empty, BranchStatus::Integrated),
+                ),
+            ]);
+
+            assert_eq!(
+                statuses.grouped(),
+                HashMap::from([
+                    ("conflicted", vec![conflicted, conflicted_tree]),
+                    ("safe", vec![safe]),
+                    ("empty", vec![empty]),
+                    ("integrated", vec![integrated]),
+                ])
+            );
+            assert_eq!(StackStatuses::UpToDate.grouped(), HashMap::new());
+        }
+    }
+
+    mod change_ids {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A (no change id) -> B
+        /// Result: Base -> X -> A' -> B'
+        #[test]
+        fn replayed_commits_without_a_change_id_get_one() {
+            let test_repository = TestingRepository::open();
+            let repository = &test_repository.repository;
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let a_tree = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("a.txt", "a")])
+                .tree()
+                .unwrap();
+            let signature = git2::Signature::now("test", "test@example.com").unwrap();
+            let untagged_a = repository
+                .commit(None, &signature, &signature, "A", &a_tree, &[&base_commit])
+                .unwrap();
+            let untagged_a = repository.find_commit(untagged_a).unwrap();
+            assert_eq!(untagged_a.change_id(), None);
+            let local_b = test_repository.commit_tree_with_change_id(
+                Some(&untagged_a),
+                "change-b",
+                &[("foo.txt", "foo"), ("a.txt", "a"), ("b.txt", "b")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution {
+                    generate_missing_change_ids: true,
+                    ..Resolution::new(stack.id, stack.tree, ResolutionApproach::Rebase)
+                }],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice() else {
+                panic!("Expected a single updated stack");
+            };
+            let new_b = repository.find_commit(*head).unwrap();
+            assert_eq!(new_b.change_id().as_deref(), Some("change-b"));
+            let new_a = new_b.parent(0).unwrap();
+            assert_eq!(new_a.message(), Some("A"));
+            assert!(new_a.change_id().is_some());
+        }
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A (no change id) -> B
+        /// Result: Base -> X -> A' -> B'
+        #[test]
+        fn replayed_commits_without_a_change_id_keep_lacking_one_by_default() {
+            let test_repository = TestingRepository::open();
+            let repository = &test_repository.repository;
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let a_tree = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("a.txt", "a")])
+                .tree()
+                .unwrap();
+            let signature = git2::Signature::now("test", "test@example.com").unwrap();
+            let untagged_a = repository
+                .commit(None, &signature, &signature, "A", &a_tree, &[&base_commit])
+                .unwrap();
+            let untagged_a = repository.find_commit(untagged_a).unwrap();
+            assert_eq!(untagged_a.change_id(), None);
+            let local_b = test_repository.commit_tree_with_change_id(
+                Some(&untagged_a),
+                "change-b",
+                &[("foo.txt", "foo"), ("a.txt", "a"), ("b.txt", "b")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice() else {
+                panic!("Expected a single updated stack");
+            };
+            let new_b = repository.find_commit(*head).unwrap();
+            assert_eq!(new_b.change_id().as_deref(), Some("change-b"));
+            let new_a = new_b.parent(0).unwrap();
+            assert_eq!(new_a.message(), Some("A"));
+            assert_eq!(new_a.change_id(), None);
+        }
+    }
+
+    mod preserve_committer_date {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A (committed long ago)
+        /// Result: Base -> X -> A'
+        #[test]
+        fn replayed_commits_keep_their_committer_date() {
+            let test_repository = TestingRepository::open();
+            let repository = &test_repository.repository;
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let a_tree = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("a.txt", "a")])
+                .tree()
+                .unwrap();
+            let long_ago = git2::Time::new(1_000_000_000, 60);
+            let signature = git2::Signature::new("test", "test@example.com", &long_ago).unwrap();
+            let local_a = repository
+                .commit(None, &signature, &signature, "A", &a_tree, &[&base_commit])
+                .unwrap();
+            let local_a = repository.find_commit(local_a).unwrap();
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+            let rebased_committer_time = |preserve_committer_date: bool| {
+                let results = compute_resolutions(
+                    &context,
+                    &[Resolution {
+                        preserve_committer_date,
+                        ..Resolution::new(stack.id, stack.tree, ResolutionApproach::Rebase)
+                    }],
+                    None,
+                )
+                .unwrap();
+                let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice()
+                else {
+                    panic!("Expected a single updated stack");
+                };
+                let new_a = repository.find_commit(*head).unwrap();
+                assert_eq!(new_a.parent_id(0).unwrap(), upstream_x.id());
+                let time = new_a.committer().when();
+                (time.seconds(), time.offset_minutes())
+            };
+
+            assert_ne!(rebased_committer_time(false), (1_000_000_000, 60));
+            assert_eq!(rebased_committer_time(true), (1_000_000_000, 60));
+        }
+    }
+
+    mod deterministic_time {
+        use super::*;
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stack:  Base (+ uncommitted change to foo.txt)
+        /// Result: Base -> X -> conflicted uncommitted changes
+        #[test]
+        fn same_inputs_give_the_same_commit() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let uncommitted =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+
+            let mut stack = stack(&base_commit, uncommitted.tree_id());
+            stack.updated_timestamp_ms = 1_000_000_000_000;
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+            let resolution = Resolution {
+                deterministic_time: true,
+                ..Resolution::new(stack.id, stack.tree, ResolutionApproach::Rebase)
+            };
+
+            let integrate = || {
+                let results =
+                    compute_resolutions(&context, std::slice::from_ref(&resolution), None).unwrap();
+                let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice()
+                else {
+                    panic!("Expected a single updated stack");
+                };
+                *head
+            };
+
+            let head = integrate();
+            assert_eq!(integrate(), head);
+
+            let commit = test_repository.repository.find_commit(head).unwrap();
+            assert!(commit.is_conflicted());
+            assert_eq!(commit.committer().when().seconds(), 1_000_000_000);
+            assert_eq!(commit.author().when().seconds(), 1_000_000_000);
+            assert_ne!(commit.change_id(), Some(stack.id.to_string()));
+        }
+    }
+
+    mod force_clean {
+        use super::*;
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stack:  Base -> A (+ uncommitted change to foo.txt)
+        /// Result: Base -> X -> A', with the uncommitted changes stashed
+        #[test]
+        fn conflicting_changes_are_stashed() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("a.txt", "a")]);
+            let uncommitted =
+                test_repository.commit_tree(Some(&local_a), &[("foo.txt", "foo1"), ("a.txt", "a")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+
+            let stack = stack(&local_a, uncommitted.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            )
+            .with_force_clean(true);
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(
+                _,
+                IntegrationResult::UpdatedObjects {
+                    head,
+                    tree,
+                    stashed_tree,
+                    ..
+                },
+            )] = results.as_slice()
+            else {
+                panic!("Expected a single updated stack");
+            };
+            let new_a = test_repository.repository.find_commit(*head).unwrap();
+            assert!(!new_a.is_conflicted());
+            assert_eq!(new_a.parent_id(0).unwrap(), upstream_x.id());
+            assert_eq!(*tree, new_a.tree_id());
+            assert_commit_tree_matches(
+                &test_repository.repository,
+                &new_a,
+                &[("foo.txt", b"foo2"), ("a.txt", b"a")],
+            );
+            assert_eq!(*stashed_tree, Some(uncommitted.tree_id()));
+
+            let outcome = IntegrationOutcome::from_results(
+                &test_repository.repository,
+                upstream_x.id(),
+                &results,
+            )
+            .unwrap();
+            assert_eq!(
+                outcome.stashed,
+                vec![StashedTree {
+                    stack_id: stack.id,
+                    tree: uncommitted.tree_id(),
+                }]
+            );
+            assert!(outcome.conflicted.is_empty());
+        }
+
+        #[test]
+        fn stashed_trees_survive_garbage_collection() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let stashed_tree = test_repository
+                .repository
+                .find_tree(
+                    test_repository
+                        .commit_tree(None, &[("foo.txt", "stashed")])
+                        .tree_id(),
+                )
+                .unwrap();
+            let stack = stack(&base_commit, base_commit.tree_id());
+
+            anchor_integration_results(
+                &test_repository.repository,
+                &[(
+                    stack.id,
+                    IntegrationResult::UpdatedObjects {
+                        head: upstream_x.id(),
+                        tree: upstream_x.tree_id(),
+                        rewritten_commits: HashMap::new(),
+                        stashed_tree: Some(stashed_tree.id()),
+                    },
+                )],
+            )
+            .unwrap();
+
+            let status = std::process::Command::new("git")
+                .args(["gc", "--prune=now", "--quiet"])
+                .current_dir(test_repository.tempdir.path())
+                .status()
+                .unwrap();
+            assert!(status.success());
+
+            let repository = git2::Repository::open(test_repository.tempdir.path()).unwrap();
+            let reference = repository.find_reference(&stash_refname(stack.id)).unwrap();
+            assert_eq!(reference.target(), Some(stashed_tree.id()));
+            assert_tree_matches(
+                &repository,
+                &repository.find_tree(stashed_tree.id()).unwrap(),
+                &[("foo.txt", b"stashed")],
+            );
+        }
+    }
+
+    mod pristine_branch {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base
+        /// Result: Base -> X
+        #[test]
+        fn fast_forwards_to_the_new_target() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&base_commit, base_commit.tree_id());
+
+            let status = stack_status(
+                &test_repository.repository,
+                base_commit.id(),
+                upstream_x.id(),
+                &stack,
+            );
+            assert_eq!(status.tree_status, TreeStatus::Empty);
+            assert_eq!(status.branch_statuses[0].status, BranchStatus::Empty);
+
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            assert_eq!(
+                results,
+                vec![(
+                    stack.id,
+                    IntegrationResult::UpdatedObjects {
+                        head: upstream_x.id(),
+                        tree: upstream_x.tree_id(),
+                        rewritten_commits: HashMap::new(),
+                        stashed_tree: None,
+                    }
+                )]
+            );
+        }
+    }
+
+    mod rebase_onto {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack1: Base -> A
+        /// Stack2: Base -> B
+        /// Result: Base -> B -> A'
+        #[test]
+        fn rebases_onto_another_stack() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("a.txt", "a")]);
+            let local_b = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("b.txt", "b")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack_a = stack(&local_a, local_a.tree_id());
+            let stack_b = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack_a.clone(), stack_b],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution {
+                    rebase_onto: Some(local_b.id()),
+                    ..Resolution::new(stack_a.id, stack_a.tree, ResolutionApproach::Rebase)
+                }],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice() else {
+                panic!("Expected a single updated stack");
+            };
+            let new_a = test_repository.repository.find_commit(*head).unwrap();
+            assert_eq!(new_a.parent_id(0).unwrap(), local_b.id());
+            assert_commit_tree_matches(
+                &test_repository.repository,
+                &new_a,
+                &[("foo.txt", b"foo"), ("a.txt", b"a"), ("b.txt", b"b")],
+            );
+        }
+
+        #[test]
+        fn unreachable_destination_is_rejected() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let dangling = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let error = compute_resolutions(
+                &context,
+                &[Resolution {
+                    rebase_onto: Some(dangling.id()),
+                    ..Resolution::new(stack.id, stack.tree, ResolutionApproach::Rebase)
+                }],
+                None,
+            )
+            .unwrap_err();
+
+            assert!(error.to_string().contains("is not reachable"));
+        }
+    }
+
+    mod preview_integrated_tree {
+        use super::*;
+
+        /// Trunk: Base -> X
+        /// Stack: Base -> A (+ uncommitted baz.txt)
+        #[test]
+        fn matches_the_integrated_tree() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let uncommitted = test_repository
+                .commit_tree(Some(&local_a), &[("foo.txt", "foo1"), ("baz.txt", "baz")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, uncommitted.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            for approach in [
+                UpdatableResolutionApproach::Rebase,
+                UpdatableResolutionApproach::Merge,
+            ] {
+                let preview = preview_integrated_tree(&context, stack.id, approach).unwrap();
+
+                let results = compute_resolutions(
+                    &context,
+                    &[Resolution::new(stack.id, stack.tree, approach.into())],
+                    None,
+                )
+                .unwrap();
+                let [(_, IntegrationResult::UpdatedObjects { tree, .. })] = results.as_slice()
+                else {
+                    panic!("Expected a single updated stack");
+                };
+
+                assert_eq!(preview, *tree);
+                assert_tree_matches(
+                    &test_repository.repository,
+                    &test_repository.repository.find_tree(preview).unwrap(),
+                    &[
+                        ("foo.txt", b"foo1"),
+                        ("bar.txt", b"bar"),
+                        ("baz.txt", b"baz"),
+                    ],
+                );
+            }
+        }
+    }
+
+    mod describe {
+        use super::*;
+
+        #[test]
+        fn branch_status() {
+            assert_eq!(
+                BranchStatus::SaflyUpdatable.describe(),
+                "can be updated safely"
+            );
+            assert_eq!(BranchStatus::Integrated.describe(), "fully integrated");
+            assert_eq!(
+                BranchStatus::Conflicted { rebasable: true }.describe(),
+                "conflicted, but can be rebased without conflicts"
+            );
+            assert_eq!(
+                BranchStatus::Conflicted { rebasable: false }.describe(),
+                "conflicted"
+            );
+            assert_eq!(BranchStatus::Empty.describe(), "empty");
+        }
+
+        #[test]
+        fn outcome_summary_line() {
+            let stacks = (0..6).map(|_| StackId::generate()).collect::<Vec<_>>();
+            let mut outcome = IntegrationOutcome {
+                integrated: vec![stacks[0], stacks[1], stacks[2], stacks[3]],
+                approaches: vec![],
+                unapplied: vec![],
+                deleted: vec![stacks[4], stacks[5]],
+                unchanged: vec![],
+                conflicted: vec![stacks[1]],
+                stashed: vec![],
+                integration_tips: vec![],
+                whitespace_resolved: vec![],
+            };
+            assert_eq!(
+                outcome.summary_line(),
+                "4 integrated, 1 conflicted, 2 deleted"
+            );
+
+            let resolution =
+                |stack_id, approach| Resolution::new(stack_id, git2::Oid::zero(), approach);
+            outcome.record_approaches(&[
+                resolution(stacks[0], ResolutionApproach::Rebase),
+                resolution(stacks[1], ResolutionApproach::Merge),
+                resolution(stacks[2], ResolutionApproach::RebaseCommitsOnly),
+                resolution(stacks[3], ResolutionApproach::Rebase),
+                resolution(stacks[4], ResolutionApproach::Delete),
+                resolution(stacks[5], ResolutionApproach::Delete),
+            ]);
+            assert_eq!(outcome.approaches.len(), 4);
+            assert_eq!(
+                outcome.summary_line(),
+                "3 rebased, 1 merged, 1 conflicted, 2 deleted"
+            );
+            assert_eq!(
+                IntegrationOutcome::default().summary_line(),
+                "nothing to integrate"
+            );
+        }
+    }
+
+    mod default_resolution {
+        use super::*;
+
+        #[test]
+        fn falls_back_to_rebase() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let stack = stack(&base_commit, base_commit.tree_id());
+
+            let resolution = Resolution::default_for_stack(&stack);
+
+            assert_eq!(
+                resolution,
+                Resolution::new(stack.id, base_commit.tree_id(), ResolutionApproach::Rebase)
+            );
+        }
+
+        #[test]
+        fn honours_merge_preference() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let mut stack = stack(&base_commit, base_commit.tree_id());
+            stack.preferred_integration_approach = Some(UpdatableResolutionApproach::Merge);
+
+            let resolution = Resolution::default_for_stack(&stack);
+
+            assert_eq!(resolution.approach, ResolutionApproach::Merge);
+        }
+    }
+
+    mod merge_base_strategy {
+        use super::*;
+
+        /// Old target: Base -> A
+        /// New target: Other (unrelated history)
+        #[test]
+        fn unrelated_histories_require_common_ancestor() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let old_target =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let new_target = test_repository.commit_tree(None, &[("bar.txt", "bar")]);
+
+            let result = MergeBaseStrategy::RequireCommonAncestor.merge_base_tree(
+                &test_repository.repository,
+                old_target.id(),
+                new_target.id(),
+            );
+
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                format!(
+                    "The old target {} and the new target {} share no common ancestor",
+                    old_target.id(),
+                    new_target.id()
+                )
+            );
+        }
+
+        /// Old target: Base -> A
+        /// New target: Other (unrelated history)
+        #[test]
+        fn unrelated_histories_fall_back_to_empty_tree() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let old_target =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let new_target = test_repository.commit_tree(None, &[("bar.txt", "bar")]);
+
+            let merge_base_tree = MergeBaseStrategy::EmptyTreeFallback
+                .merge_base_tree(
+                    &test_repository.repository,
+                    old_target.id(),
+                    new_target.id(),
+                )
+                .unwrap();
+
+            let empty_tree = test_repository
+                .repository
+                .treebuilder(None)
+                .unwrap()
+                .write()
+                .unwrap();
+            assert_eq!(merge_base_tree, empty_tree);
+        }
+
+        /// Old target: Base -> A
+        /// New target: Base -> X
+        #[test]
+        fn related_histories_use_merge_base_tree() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let old_target =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let new_target = test_repository.commit_tree(Some(&base_commit), &[("bar.txt", "bar")]);
+
+            for strategy in [
+                MergeBaseStrategy::RequireCommonAncestor,
+                MergeBaseStrategy::EmptyTreeFallback,
+            ] {
+                let merge_base_tree = strategy
+                    .merge_base_tree(
+                        &test_repository.repository,
+                        old_target.id(),
+                        new_target.id(),
+                    )
+                    .unwrap();
+                assert_eq!(merge_base_tree, base_commit.tree_id());
+            }
+        }
+    }
+
+    mod stack_status {
+        use super::*;
+
+        /// Trunk:  Base -> X (deletes shared.txt)
+        /// Branch: Base -> A (deletes shared.txt)
+        #[test]
+        fn both_sides_deleted_committed_file() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit =
+                test_repository.commit_tree(None, &[("foo.txt", "foo"), ("shared.txt", "shared")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let status = stack_status(
+                &test_repository.repository,
+                base_commit.id(),
+                upstream_x.id(),
+                &stack,
+            );
+
+            assert_eq!(status.tree_status, TreeStatus::Empty);
+            assert_eq!(
+                status.branch_statuses,
+                vec![NameAndStatus {
+                    name: "branch".into(),
+                    status: BranchStatus::SaflyUpdatable,
+                    commit_counts: CommitCounts {
+                        commits_to_replay: 1,
+                        upstream_commits_incoming: 1,
+                    },
+                }]
+            );
+        }
+
+        /// Trunk:  Base -> X (deletes shared.txt)
+        /// Branch: Base, with shared.txt deleted in the uncommitted changes
+        #[test]
+        fn both_sides_deleted_uncommitted_file() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit =
+                test_repository.commit_tree(None, &[("foo.txt", "foo"), ("shared.txt", "shared")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            let uncommitted = test_repository.commit_tree(None, &[("foo.txt", "foo1")]);
+
+            let stack = stack(&base_commit, uncommitted.tree_id());
+            let status = stack_status(
+                &test_repository.repository,
+                base_commit.id(),
+                upstream_x.id(),
+                &stack,
+            );
+
+            assert_eq!(status.tree_status, TreeStatus::SaflyUpdatable);
+        }
+    }
+
+    mod classify_merge {
+        use super::*;
+
+        fn classify(
+            base: &[(&str, &str)],
+            upstream: &[(&str, &str)],
+            branch: &[(&str, &str)],
+        ) -> BranchStatus {
+            classify_rebased(base, upstream, branch, branch)
+        }
+
+        fn classify_rebased(
+            base: &[(&str, &str)],
+            upstream: &[(&str, &str)],
+            branch: &[(&str, &str)],
+            rebased_branch: &[(&str, &str)],
+        ) -> BranchStatus {
+            let test_repository = TestingRepository::open();
+            let base_commit = test_repository.commit_tree(None, base);
+            let upstream_x = test_repository.commit_tree(Some(&base_commit), upstream);
+            let local_a = test_repository.commit_tree(Some(&base_commit), branch);
+            let rebased_a = test_repository.commit_tree(Some(&upstream_x), rebased_branch);
+            classify_merge(
+                &test_repository.repository,
+                base_commit.tree_id(),
+                upstream_x.tree_id(),
+                local_a.tree_id(),
+                rebased_a.tree_id(),
+            )
+            .unwrap()
+        }
+
+        #[test]
+        fn empty() {
+            assert_eq!(
+                classify(
+                    &[("foo.txt", "foo")],
+                    &[("foo.txt", "foo1")],
+                    &[("foo.txt", "foo")]
+                ),
+                BranchStatus::Empty
+            );
+        }
+
+        #[test]
+        fn conflicted() {
+            assert_eq!(
+                classify(
+                    &[("foo.txt", "foo")],
+                    &[("foo.txt", "foo1")],
+                    &[("foo.txt", "foo2")]
+                ),
+                BranchStatus::Conflicted { rebasable: true }
+            );
+        }
+
+        #[test]
+        fn conflicted_rebase() {
+            assert_eq!(
+                classify_rebased(
+                    &[("foo.txt", "foo")],
+                    &[("foo.txt", "foo1")],
+                    &[("bar.txt", "bar")],
+                    &[("bar.txt", "bar"), (".conflict-files", "bar.txt")]
+                ),
+                BranchStatus::Conflicted { rebasable: false }
+            );
+        }
+
+        #[test]
+        fn integrated() {
+            assert_eq!(
+                classify(
+                    &[("foo.txt", "foo")],
+                    &[("foo.txt", "foo1"), ("bar.txt", "bar")],
+                    &[("foo.txt", "foo1")]
+                ),
+                BranchStatus::Integrated
+            );
+        }
+
+        #[test]
+        fn safely_updatable() {
+            assert_eq!(
+                classify(
+                    &[("foo.txt", "foo")],
+                    &[("foo.txt", "foo1")],
+                    &[("foo.txt", "foo"), ("bar.txt", "bar")]
+                ),
+                BranchStatus::SaflyUpdatable
+            );
+        }
+    }
+
+    mod commit_counts {
+        use super::*;
+
+        /// Trunk:  Base -> X -> Y -> Z
+        /// Branch: Base -> A -> B
+        #[test]
+        fn counts_replayed_and_incoming_commits() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_b = test_repository.commit_tree(Some(&local_a), &[("foo.txt", "foo2")]);
+
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("bar.txt", "bar1")]);
+            let upstream_y = test_repository.commit_tree(Some(&upstream_x), &[("bar.txt", "bar2")]);
+            let upstream_z = test_repository.commit_tree(Some(&upstream_y), &[("bar.txt", "bar3")]);
+
+            let upstream_commits = test_repository
+                .repository
+                .l(upstream_z.id(), LogUntil::Commit(base_commit.id()), true)
+                .unwrap();
+            let counts = CommitCounts::compute(
+                &test_repository.repository,
+                local_b.id(),
+                base_commit.id(),
+                &upstream_commits,
+            )
+            .unwrap();
+
+            assert_eq!(
+                counts,
+                CommitCounts {
+                    commits_to_replay: 2,
+                    upstream_commits_incoming: 3,
+                }
+            );
+        }
+
+        /// Trunk:  Base
+        /// Branch: Base
+        #[test]
+        fn nothing_to_replay_or_incoming() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+
+            let counts = CommitCounts::compute(
+                &test_repository.repository,
+                base_commit.id(),
+                base_commit.id(),
+                &[],
+            )
+            .unwrap();
+
+            assert_eq!(counts, CommitCounts::default());
+        }
+    }
+
+    mod conflict_labels {
+        use super::*;
+
+        /// Base:   foo.txt = a
+        /// Stack:  foo.txt = x
+        /// Trunk:  foo.txt = y
+        #[test]
+        fn conflict_markers_use_the_given_labels() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "a\n")]);
+            let local_x = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "x\n")]);
+            let upstream_y = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "y\n")]);
+
+            let stack = stack(&local_x, local_x.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_y,
+            )
+            .with_conflict_labels(ConflictLabels {
+                ours: "my-branch".into(),
+                theirs: "upstream".into(),
+            });
+
+            let tree = merge_with_conflict_markers(&context, stack.id).unwrap();
+            let repository = &test_repository.repository;
+            let blob = repository
+                .find_tree(tree)
+                .unwrap()
+                .get_path(std::path::Path::new("foo.txt"))
+                .unwrap()
+                .id();
+            let content =
+                String::from_utf8(repository.find_blob(blob).unwrap().content().to_vec()).unwrap();
+
+            assert!(content.contains("<<<<<<< my-branch\nx\n"));
+            assert!(content.contains(">>>>>>> upstream\n"));
+        }
+
+        #[test]
+        fn integration_merges_use_the_given_labels() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "a\n")]);
+            let context = integration_context(
+                &test_repository.repository,
+                vec![],
+                base_commit.id(),
+                base_commit.clone(),
+            )
+            .with_conflict_labels(ConflictLabels {
+                ours: "my-branch".into(),
+                theirs: "upstream".into(),
+            });
+
+            // When rebasing, the new target is ours and the stack is theirs.
+            let labels = IntegrationConflictPolicy::for_rebase(&context).labels();
+            assert_eq!(labels.current, Some("upstream".into()));
+            assert_eq!(labels.other, Some("my-branch".into()));
+        }
+    }
+
+    mod target_ids {
+        use super::*;
+
+        #[test]
+        fn accessors_return_the_targets_the_context_was_opened_with() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "a")]);
+            let upstream_x = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "x")]);
+
+            let context = integration_context(
+                &test_repository.repository,
+                vec![],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            assert_eq!(context.old_target_id(), base_commit.id());
+            assert_eq!(context.new_target_id(), upstream_x.id());
+        }
+    }
+
+    mod merge_attributes {
+        use super::*;
+
+        /// Checks out the tree of `commit`, as the attributes are read from the worktree.
+        fn check_out(test_repository: &TestingRepository, commit: &git2::Commit) {
+            test_repository
+                .repository
+                .checkout_tree(
+                    commit.as_object(),
+                    Some(git2::build::CheckoutBuilder::new().force()),
+                )
+                .unwrap();
+        }
+
+        /// Base:   CHANGELOG = a
+        /// Stack:  CHANGELOG = a, x
+        /// Trunk:  CHANGELOG = a, y
+        #[test]
+        fn union_merges_keep_the_lines_of_both_sides() {
+            let test_repository = TestingRepository::open();
+
+            let attributes = (".gitattributes", "CHANGELOG merge=union\n");
+            let base_commit =
+                test_repository.commit_tree(None, &[attributes, ("CHANGELOG", "a\n")]);
+            let local_x = test_repository
+                .commit_tree(Some(&base_commit), &[attributes, ("CHANGELOG", "a\nx\n")]);
+            let upstream_y = test_repository
+                .commit_tree(Some(&base_commit), &[attributes, ("CHANGELOG", "a\ny\n")]);
+            check_out(&test_repository, &local_x);
+
+            let stack = stack(&local_x, local_x.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_y,
+            );
+
+            let tree = apply_conflict_preferences(&context, stack.id).unwrap();
+            let repository = &test_repository.repository;
+            let blob = repository
+                .find_tree(tree)
+                .unwrap()
+                .get_path(std::path::Path::new("CHANGELOG"))
+                .unwrap()
+                .id();
+            assert_eq!(repository.find_blob(blob).unwrap().content(), b"a\nx\ny\n");
+        }
+
+        /// Trunk:  Base -> Y (changes CHANGELOG and VERSION)
+        /// Stack:  Base -> X (changes CHANGELOG and VERSION)
+        /// Result: Base -> Y -> X', with the lines of both in CHANGELOG and the stack's VERSION
+        #[test]
+        fn integrating_merges_with_the_merge_drivers() {
+            let test_repository = TestingRepository::open();
+
+            let attributes = (
+                ".gitattributes",
+                "CHANGELOG merge=union\nVERSION merge=ours\n",
+            );
+            let base_commit = test_repository.commit_tree(
+                None,
+                &[attributes, ("CHANGELOG", "a\n"), ("VERSION", "1\n")],
+            );
+            let local_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[attributes, ("CHANGELOG", "a\nx\n"), ("VERSION", "2\n")],
+            );
+            let upstream_y = test_repository.commit_tree(
+                Some(&base_commit),
+                &[attributes, ("CHANGELOG", "a\ny\n"), ("VERSION", "3\n")],
+            );
+            check_out(&test_repository, &local_x);
+
+            let stack = stack(&local_x, local_x.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_y.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let IntegrationResult::UpdatedObjects { head, .. } = results[0].1 else {
+                panic!("Expected the stack to be updated")
+            };
+            let head = test_repository.repository.find_commit(head).unwrap();
+            assert!(!head.is_conflicted());
+            assert_eq!(head.parent_id(0).unwrap(), upstream_y.id());
+            assert_commit_tree_matches(
+                &test_repository.repository,
+                &head,
+                &[("CHANGELOG", b"a\ny\nx\n"), ("VERSION", b"2\n")],
+            );
+        }
+    }
+
+    mod statuses_lazy {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stacks: Base -> A, Base -> B, and Base
+        #[test]
+        fn yields_the_statuses_of_the_eager_api_in_order() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_b = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar1")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let context = integration_context(
+                &test_repository.repository,
+                vec![
+                    stack(&local_a, local_a.tree_id()),
+                    stack(&local_b, local_b.tree_id()),
+                    stack(&base_commit, base_commit.tree_id()),
+                ],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let lazy = statuses_lazy(&context)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(lazy.len(), 3);
+            assert_eq!(
+                upstream_integration_statuses(&context).unwrap(),
+                StackStatuses::UpdatesRequired(lazy)
+            );
+        }
+
+        #[test]
+        fn can_stop_after_the_first_status() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+
+            let first = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![first.clone(), stack(&base_commit, base_commit.tree_id())],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let mut statuses = statuses_lazy(&context).unwrap();
+            let (stack_id, status) = statuses.next().unwrap().unwrap();
+            assert_eq!(stack_id, first.id);
+            assert!(status.is_conflicted());
+        }
+    }
+
+    mod force_pushed_target {
+        use super::*;
+
+        /// Old trunk: Base -> O
+        /// New trunk: Base -> X (O was force-pushed away)
+        /// Stack:     Base -> O -> A
+        /// Result:    Base -> X -> A'
+        #[test]
+        fn stacks_rooted_on_the_old_target_leave_it_behind() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let old_upstream_o = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("old.txt", "old")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            let local_a = test_repository.commit_tree(
+                Some(&old_upstream_o),
+                &[("foo.txt", "foo1"), ("old.txt", "old")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                old_upstream_o.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Rebase,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(
+                _,
+                IntegrationResult::UpdatedObjects {
+                    head,
+                    tree,
+                    rewritten_commits,
+                    ..
+                },
+            )] = results.as_slice()
+            else {
+                panic!("Expected a single updated stack");
+            };
+            let new_a = test_repository.repository.find_commit(*head).unwrap();
+            assert_eq!(new_a.parent_id(0).unwrap(), upstream_x.id());
+            assert_eq!(rewritten_commits, &HashMap::from([(local_a.id(), *head)]));
+
+            assert_tree_matches(
+                &test_repository.repository,
+                &test_repository.repository.find_tree(*tree).unwrap(),
+                &[("foo.txt", b"foo1"), ("bar.txt", b"bar")],
+            );
+            let tree = test_repository.repository.find_tree(*tree).unwrap();
+            assert!(tree.get_name("old.txt").is_none());
+        }
+    }
+
+    mod rewritten_commits {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base
+        #[test]
+        fn fast_forwards_rewrite_nothing() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&base_commit, base_commit.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert_eq!(
+                rewritten_commits(&context, stack.id, &ResolutionApproach::Rebase).unwrap(),
+                vec![]
+            );
+        }
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A -> B
+        #[test]
+        fn rebases_rewrite_every_commit() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_b = test_repository.commit_tree(Some(&local_a), &[("foo.txt", "foo2")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert_eq!(
+                rewritten_commits(&context, stack.id, &ResolutionApproach::Rebase).unwrap(),
+                vec![local_b.id(), local_a.id()]
+            );
+            assert_eq!(
+                rewritten_commits(&context, stack.id, &ResolutionApproach::Merge).unwrap(),
+                vec![]
+            );
+        }
+    }
+
+    mod integration_excluded {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stacks: Base -> A, and the excluded Base -> B
+        #[test]
+        fn excluded_stacks_are_left_out() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_b = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("baz.txt", "baz")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let included = stack(&local_a, local_a.tree_id());
+            let mut excluded = stack(&local_b, local_b.tree_id());
+            excluded.integration_excluded = true;
+            let context = integration_context(
+                &test_repository.repository,
+                vec![included.clone(), excluded],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let StackStatuses::UpdatesRequired(statuses) =
+                upstream_integration_statuses(&context).unwrap()
+            else {
+                panic!("Expected updates to be required");
+            };
+            assert_eq!(
+                statuses
+                    .iter()
+                    .map(|(stack_id, _)| *stack_id)
+                    .collect::<Vec<_>>(),
+                vec![included.id]
+            );
+            assert_eq!(
+                default_resolutions(&context).unwrap(),
+                vec![Resolution::default_for_stack(&included)]
+            );
+        }
+    }
+
+    mod branch_contribution_diff {
+        use super::*;
+
+        /// Base:   foo.txt = foo
+        /// Stack:  foo.txt = foo1, baz.txt = baz
+        /// Trunk:  foo.txt = foo2, bar.txt = bar
+        #[test]
+        fn contains_only_the_changes_of_the_stack() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo1"), ("baz.txt", "baz")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo2"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let diff = branch_contribution_diff(&context, stack.id).unwrap();
+            let changes = diff
+                .deltas()
+                .map(|delta| {
+                    (
+                        delta
+                            .new_file()
+                            .path()
+                            .unwrap()
+                            .to_str()
+                            .unwrap()
+                            .to_string(),
+                        delta.status(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                changes,
+                vec![
+                    ("baz.txt".to_string(), git2::Delta::Added),
+                    ("foo.txt".to_string(), git2::Delta::Modified),
+                ]
+            );
+        }
+    }
+
+    mod integrate_trailer {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stacks: Base -> A (with trailer), and Base -> B
+        #[test]
+        fn the_trailer_of_the_head_picks_the_approach() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree_with_message(
+                Some(&base_commit),
+                "A\n\ngitbutler-integrate: merge",
+                &[("foo.txt", "foo1")],
+            );
+            let local_b = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let with_trailer = stack(&local_a, local_a.tree_id());
+            let without_trailer = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![with_trailer, without_trailer],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let approaches = default_resolutions(&context)
+                .unwrap()
+                .into_iter()
+                .map(|resolution| resolution.approach)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                approaches,
+                vec![ResolutionApproach::Merge, ResolutionApproach::Rebase]
+            );
+        }
+
+        #[test]
+        fn an_unknown_approach_is_an_error() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree_with_message(
+                Some(&base_commit),
+                "A\n\ngitbutler-integrate: squash",
+                &[("foo.txt", "foo1")],
+            );
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack(&local_a, local_a.tree_id())],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let err = default_resolutions(&context).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "Commit {} names an unknown approach in its gitbutler-integrate trailer: squash",
+                    local_a.id()
+                )
+            );
+        }
+    }
+
+    mod would_produce_conflicts {
+        use super::*;
+
+        fn rebase(stack: &Stack) -> Resolution {
+            Resolution::new(stack.id, stack.tree, ResolutionApproach::Rebase)
+        }
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stack:  Base -> A (changes foo.txt)
+        #[test]
+        fn conflicting_stacks_produce_conflicts() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert!(would_produce_conflicts(&context, &[rebase(&stack)]).unwrap());
+        }
+
+        /// Trunk:  Base -> X (adds bar.txt)
+        /// Stack:  Base -> A (changes foo.txt)
+        #[test]
+        fn clean_stacks_produce_no_conflicts() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert!(!would_produce_conflicts(&context, &[rebase(&stack)]).unwrap());
+        }
+    }
+
+    mod merge_message_template {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A
+        /// Result: Base -> A -> Merge (with the rendered message)
+        #[test]
+        fn the_merge_commit_gets_the_rendered_message() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution {
+                    merge_message_template: Some(
+                        "Merge upstream into {branch} ({old_target}..{new_target})".into(),
+                    ),
+                    ..Resolution::new(stack.id, stack.tree, ResolutionApproach::Merge)
+                }],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice() else {
+                panic!("Expected the stack to be updated");
+            };
+            let merge_commit = test_repository.repository.find_commit(*head).unwrap();
+            assert_eq!(merge_commit.parent_count(), 2);
+            assert_eq!(
+                merge_commit.message().unwrap(),
+                format!(
+                    "Merge upstream into branch ({}..{})",
+                    base_commit.id(),
+                    upstream_x.id()
+                )
+            );
+        }
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A
+        /// Result: Base -> A -> Merge (with the usual message)
+        #[test]
+        fn without_a_template_the_usual_message_is_used() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_a, local_a.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution::new(
+                    stack.id,
+                    stack.tree,
+                    ResolutionApproach::Merge,
+                )],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice() else {
+                panic!("Expected the stack to be updated");
+            };
+            let merge_commit = test_repository.repository.find_commit(*head).unwrap();
+            assert_eq!(
+                merge_commit.message().unwrap(),
+                "Merge `origin/master` into `branch`"
+            );
+        }
+    }
+
+    mod resign_commits {
+        use super::*;
+
+        /// Trunk:  Base -> X
+        /// Stack:  Base -> A (signed) -> B
+        /// Result: Base -> X -> A' (signed) -> B'
+        #[cfg(unix)]
+        #[test]
+        fn signed_commits_are_signed_again_after_the_rebase() {
+            use std::os::unix::fs::PermissionsExt as _;
+
+            let test_repository = TestingRepository::open();
+            let repository = &test_repository.repository;
+
+            let signer = test_repository.tempdir.path().join("stub-signer.sh");
+            std::fs::write(
+                &signer,
+                "#!/bin/sh\ncat > /dev/null\nprintf -- '-----BEGIN PGP SIGNATURE-----\\nstub\\n-----END PGP SIGNATURE-----\\n'\n",
+            )
+            .unwrap();
+            std::fs::set_permissions(&signer, std::fs::Permissions::from_mode(0o755)).unwrap();
+            let mut config = repository.config().unwrap();
+            config.set_str("user.signingkey", "stub").unwrap();
+            config
+                .set_str("gpg.program", signer.to_str().unwrap())
+                .unwrap();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let local_a = sign_commit(repository, local_a.id()).unwrap();
+            let local_a = repository.find_commit(local_a).unwrap();
+            let local_b = test_repository.commit_tree(Some(&local_a), &[("foo.txt", "foo2")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+
+            let stack = stack(&local_b, local_b.tree_id());
+            let context = integration_context(
+                repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+
+            let results = compute_resolutions(
+                &context,
+                &[Resolution {
+                    resign_commits: true,
+                    ..Resolution::new(stack.id, stack.tree, ResolutionApproach::Rebase)
+                }],
+                None,
+            )
+            .unwrap();
+
+            let [(_, IntegrationResult::UpdatedObjects { head, .. })] = results.as_slice() else {
+                panic!("Expected the stack to be updated");
+            };
+            let new_b = repository.find_commit(*head).unwrap();
+            let new_a = new_b.parent(0).unwrap();
+            assert_eq!(new_a.parent_id(0).unwrap(), upstream_x.id());
+            assert_ne!(new_a.id(), local_a.id());
+
+            let (signature, _) = repository.extract_signature(&new_a.id(), None).unwrap();
+            assert!(signature.as_str().unwrap().contains("stub"));
+            assert!(!is_signed(repository, new_b.id()));
+        }
+    }
+
+    mod resolution_presets {
+        use super::*;
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stack:  Base -> A (changes foo.txt, prefers merging)
+        fn presets_for(
+            preset: ResolutionPreset,
+        ) -> (Vec<Resolution>, HashMap<String, ConflictPreference>) {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+
+            let mut stack = stack(&local_a, local_a.tree_id());
+            stack.preferred_integration_approach = Some(UpdatableResolutionApproach::Merge);
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+            let statuses = upstream_integration_statuses(&context).unwrap();
+
+            let (resolutions, preferences) =
+                Resolution::from_preset(&context, &statuses, preset).unwrap();
+            assert_eq!(resolutions.len(), 1);
+            assert_eq!(resolutions[0].branch_id, stack.id);
+            assert_eq!(resolutions[0].branch_tree, stack.tree);
+            validate_resolutions(&resolutions, &statuses).unwrap();
+            (resolutions, preferences)
+        }
+
+        #[test]
+        fn prefer_upstream_rebases_and_takes_theirs() {
+            let (resolutions, preferences) = presets_for(ResolutionPreset::PreferUpstream);
+            assert_eq!(resolutions[0].approach, ResolutionApproach::Rebase);
+            assert_eq!(
+                preferences,
+                HashMap::from([("foo.txt".to_string(), ConflictPreference::Theirs)])
+            );
+        }
+
+        #[test]
+        fn prefer_local_rebases_and_keeps_ours() {
+            let (resolutions, preferences) = presets_for(ResolutionPreset::PreferLocal);
+            assert_eq!(resolutions[0].approach, ResolutionApproach::Rebase);
+            assert_eq!(
+                preferences,
+                HashMap::from([("foo.txt".to_string(), ConflictPreference::Ours)])
+            );
+        }
+
+        #[test]
+        fn interactive_keeps_the_preferred_approach_and_sets_no_preferences() {
+            let (resolutions, preferences) = presets_for(ResolutionPreset::Interactive);
+            assert_eq!(resolutions[0].approach, ResolutionApproach::Merge);
+            assert!(preferences.is_empty());
+        }
+
+        #[test]
+        fn up_to_date_has_nothing_to_resolve() {
+            let test_repository = TestingRepository::open();
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let context = integration_context(
+                &test_repository.repository,
+                vec![],
+                base_commit.id(),
+                base_commit.clone(),
+            );
+
+            let (resolutions, preferences) = Resolution::from_preset(
+                &context,
+                &StackStatuses::UpToDate,
+                ResolutionPreset::PreferUpstream,
+            )
+            .unwrap();
+            assert!(resolutions.is_empty());
+            assert!(preferences.is_empty());
+        }
+    }
+
+    mod statuses_by_target {
+        use super::*;
+
+        /// Trunk:   Base -> X (adds bar.txt)
+        /// Release: Base -> R (changes foo.txt)
+        /// Stacks:  Base -> A (adds a.txt, based on trunk)
+        ///          Base -> B (changes foo.txt, based on release)
+        #[test]
+        fn stacks_are_grouped_by_their_target() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            let release_r = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let local_a = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("a.txt", "a")]);
+            let local_b = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+
+            let release = RemoteRefname::new("origin", "release");
+            let trunk_stack = stack(&local_a, local_a.tree_id());
+            let mut release_stack = stack(&local_b, local_b.tree_id());
+            release_stack.integration_target = Some(release.clone());
+
+            let context = integration_context(
+                &test_repository.repository,
+                vec![trunk_stack.clone(), release_stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            );
+            let release_target = Target {
+                branch: release.clone(),
+                ..target(base_commit.id())
+            };
+
+            let statuses =
+                statuses_by_target(&context, &[(release_target, release_r.id())]).unwrap();
+
+            assert_eq!(statuses.len(), 2);
+            let (trunk, trunk_statuses) = &statuses[0];
+            assert_eq!(trunk, &RemoteRefname::new("origin", "master"));
+            assert_eq!(
+                trunk_statuses.grouped(),
+                HashMap::from([("safe", vec![trunk_stack.id])])
+            );
+            let (release_branch, release_statuses) = &statuses[1];
+            assert_eq!(release_branch, &release);
+            assert_eq!(
+                release_statuses.grouped(),
+                HashMap::from([("conflicted", vec![release_stack.id])])
+            );
+        }
+
+        #[test]
+        fn unknown_targets_fail() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+
+            let mut stack = stack(&local_a, local_a.tree_id());
+            stack.integration_target = Some(RemoteRefname::new("origin", "release"));
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            let error = statuses_by_target(&context, &[]).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "Stack stack is based on an unknown target refs/remotes/origin/release"
+            );
+        }
+
+        /// Trunk:  Base -> X (adds bar.txt)
+        /// Stacks: Base -> A (adds a.txt, based on trunk)
+        ///         Base -> B (adds b.txt, based on release)
+        #[test]
+        fn stacks_on_other_targets_are_left_out_of_the_default_target() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x = test_repository.commit_tree(
+                Some(&base_commit),
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            );
+            let local_a = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("a.txt", "a")]);
+            let local_b = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("b.txt", "b")]);
+
+            let release = RemoteRefname::new("origin", "release");
+            let trunk_stack = stack(&local_a, local_a.tree_id());
+            let mut release_stack = stack(&local_b, local_b.tree_id());
+            release_stack.integration_target = Some(release.clone());
+
+            let context = integration_context(
+                &test_repository.repository,
+                vec![trunk_stack.clone(), release_stack.clone()],
+                base_commit.id(),
+                upstream_x,
+            )
+            .with_message_rewriter(|message, _| message.to_string());
+
+            let StackStatuses::UpdatesRequired(statuses) =
+                upstream_integration_statuses(&context).unwrap()
+            else {
+                panic!("Expected updates to be required");
+            };
+            assert_eq!(
+                statuses
+                    .iter()
+                    .map(|(stack_id, _)| *stack_id)
+                    .collect::<Vec<_>>(),
+                vec![trunk_stack.id]
+            );
+            assert_eq!(
+                default_resolutions(&context).unwrap(),
+                vec![Resolution::default_for_stack(&trunk_stack)]
+            );
+            assert!(compute_resolutions(
+                &context,
+                &[Resolution::default_for_stack(&release_stack)],
+                None
+            )
+            .is_err());
+
+            let release_context = context.for_target(
+                Target {
+                    branch: release,
+                    ..target(base_commit.id())
+                },
+                base_commit.clone(),
+                vec![release_stack],
+            );
+            assert!(release_context.message_rewriter.is_some());
+        }
+    }
+
+    mod trees_conflict {
+        use super::*;
+
+        fn conflicts(
+            base: &[(&str, &str)],
+            ours: &[(&str, &str)],
+            theirs: &[(&str, &str)],
+        ) -> bool {
+            let test_repository = TestingRepository::open();
+            let gix_repository = gitbutler_command_context::gix_repository_for_merging(
+                test_repository.repository.path(),
+            )
+            .unwrap()
+            .with_object_memory();
+
+            let base = test_repository.commit_tree(None, base);
+            let ours = test_repository.commit_tree(Some(&base), ours);
+            let theirs = test_repository.commit_tree(Some(&base), theirs);
+
+            trees_conflict(
+                &gix_repository,
+                git2_to_gix_object_id(base.tree_id()),
+                git2_to_gix_object_id(ours.tree_id()),
+                git2_to_gix_object_id(theirs.tree_id()),
+            )
+            .unwrap()
+        }
+
+        #[test]
+        fn changes_to_the_same_lines_conflict() {
+            assert!(conflicts(
+                &[("foo.txt", "foo")],
+                &[("foo.txt", "foo1")],
+                &[("foo.txt", "foo2")],
+            ));
+        }
+
+        #[test]
+        fn changes_to_different_files_dont_conflict() {
+            assert!(!conflicts(
+                &[("foo.txt", "foo")],
+                &[("foo.txt", "foo1")],
+                &[("foo.txt", "foo"), ("bar.txt", "bar")],
+            ));
+        }
+
+        #[test]
+        fn identical_changes_dont_conflict() {
+            assert!(!conflicts(
+                &[("foo.txt", "foo")],
+                &[("foo.txt", "foo1")],
+                &[("foo.txt", "foo1")],
+            ));
+        }
+    }
+
+    mod auto_integratable {
+        use super::*;
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stacks: Base -> A (adds a.txt)
+        ///         Base -> B (changes foo.txt)
+        ///         Base (no commits)
+        #[test]
+        fn only_stacks_without_conflicts_are_listed() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let local_a = test_repository
+                .commit_tree(Some(&base_commit), &[("foo.txt", "foo"), ("a.txt", "a")]);
+            let local_b = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+
+            let safe_stack = stack(&local_a, local_a.tree_id());
+            let conflicted_stack = stack(&local_b, local_b.tree_id());
+            let empty_stack = stack(&base_commit, base_commit.tree_id());
+            let context = integration_context(
+                &test_repository.repository,
+                vec![
+                    safe_stack.clone(),
+                    conflicted_stack.clone(),
+                    empty_stack.clone(),
+                ],
+                base_commit.id(),
+                upstream_x,
+            );
+
+            assert_eq!(
+                auto_integratable(&context).unwrap(),
+                vec![safe_stack.id, empty_stack.id]
+            );
+        }
+
+        #[test]
+        fn nothing_is_listed_when_up_to_date() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack(&local_a, local_a.tree_id())],
+                base_commit.id(),
+                base_commit.clone(),
+            );
+
+            assert!(auto_integratable(&context).unwrap().is_empty());
+        }
+    }
+
+    mod branch_has_uncommitted_changes {
+        use super::*;
+
+        #[test]
+        fn a_tree_matching_the_head_has_no_uncommitted_changes() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+
+            let stack = stack(&local_a, local_a.tree_id());
+            assert!(!branch_has_uncommitted_changes(&test_repository.repository, &stack).unwrap());
+        }
+
+        #[test]
+        fn a_tree_differing_from_the_head_has_uncommitted_changes() {
+            let test_repository = TestingRepository::open();
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1")]);
+            let worktree = test_repository
+                .commit_tree(Some(&local_a), &[("foo.txt", "foo1"), ("bar.txt", "bar")]);
+
+            let stack = stack(&local_a, worktree.tree_id());
+            assert!(branch_has_uncommitted_changes(&test_repository.repository, &stack).unwrap());
+        }
+    }
+
+    mod resolve_with_patch {
+        use super::*;
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stack:  Base -> A (changes foo.txt)
+        fn conflicted_setup(
+            test_repository: &TestingRepository,
+        ) -> (Stack, git2::Oid, git2::Commit<'_>) {
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo\n")]);
+            let local_a = test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo1\n")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2\n")]);
+            (
+                stack(&local_a, local_a.tree_id()),
+                base_commit.id(),
+                upstream_x,
+            )
+        }
+
+        #[test]
+        fn a_patch_resolves_the_conflict() {
+            let test_repository = TestingRepository::open();
+            let (stack, base, upstream_x) = conflicted_setup(&test_repository);
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base,
+                upstream_x,
+            );
+
+            let patch = b"diff --git a/foo.txt b/foo.txt
+--- a/foo.txt
++++ b/foo.txt
+@@ -1 +1 @@
+-foo1
++foo1 and foo2
+";
+            let tree = resolve_with_patch(&context, stack.id, patch).unwrap();
+
+            assert_tree_matches(
+                &test_repository.repository,
+                &test_repository.repository.find_tree(tree).unwrap(),
+                &[("foo.txt", b"foo1 and foo2\n")],
+            );
+        }
+
+        #[test]
+        fn a_patch_that_doesnt_apply_is_rejected() {
+            let test_repository = TestingRepository::open();
+            let (stack, base, upstream_x) = conflicted_setup(&test_repository);
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base,
+                upstream_x,
+            );
+
+            let patch = b"diff --git a/foo.txt b/foo.txt
+--- a/foo.txt
++++ b/foo.txt
+@@ -1 +1 @@
+-foo2
++foo1 and foo2
+";
+            let error = resolve_with_patch(&context, stack.id, patch).unwrap_err();
+
+            assert_eq!(
+                error.to_string(),
+                "The patch doesn't apply to the conflicted tree"
+            );
+        }
+
+        #[test]
+        fn a_patch_that_leaves_conflict_markers_is_rejected() {
+            let test_repository = TestingRepository::open();
+            let (stack, base, upstream_x) = conflicted_setup(&test_repository);
+            let context = integration_context(
+                &test_repository.repository,
+                vec![stack.clone()],
+                base,
+                upstream_x,
+            );
+
+            let patch = b"diff --git a/foo.txt b/foo.txt
+--- a/foo.txt
++++ b/foo.txt
+@@ -1 +1,5 @@
+-foo1
++<<<<<<< ours
++foo1
++=======
++foo2
++>>>>>>> theirs
+";
+            let error = resolve_with_patch(&context, stack.id, patch).unwrap_err();
+
+            assert_eq!(
+                error.to_string(),
+                "The patch leaves conflict markers in foo.txt"
+            );
+        }
+    }
+
+    mod shared_target_state {
+        use super::*;
+
+        /// Trunk:  Base -> X (changes foo.txt)
+        /// Stacks: 30 stacks on Base, every third of them also changing foo.txt
+        #[test]
+        fn statuses_match_those_computed_one_stack_at_a_time() {
+            let test_repository = TestingRepository::open();
+            let repository = &test_repository.repository;
+
+            let base_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+            let upstream_x =
+                test_repository.commit_tree(Some(&base_commit), &[("foo.txt", "foo2")]);
+            let stacks = (0..30)
+                .map(|i| {
+                    let file = format!("file{i}.txt");
+                    let foo = if i % 3 == 0 { "foo1" } else { "foo" };
+                    let commit = test_repository.commit_tree(
+                        Some(&base_commit),
+                        &[("foo.txt", foo), (file.as_str(), "content")],
+                    );
+                    stack(&commit, commit.tree_id())
+                })
+                .collect::<Vec<_>>();
+
+            let context = integration_context(
+                repository,
+                stacks.clone(),
+                base_commit.id(),
+                upstream_x.clone(),
+            );
+            let StackStatuses::UpdatesRequired(statuses) =
+                upstream_integration_statuses(&context).unwrap()
+            else {
+                panic!("Expected the stacks to need updates");
+            };
+
+            assert_eq!(statuses.len(), stacks.len());
+            for (i, ((stack_id, status), stack)) in statuses.iter().zip(&stacks).enumerate() {
+                assert_eq!(*stack_id, stack.id);
+                assert_eq!(
+                    status,
+                    &stack_status(repository, base_commit.id(), upstream_x.id(), stack)
+                );
+                assert_eq!(status.is_conflicted(), i % 3 == 0);
+                assert_eq!(
+                    status.branch_statuses[0]
+                        .commit_counts
+                        .upstream_commits_incoming,
+                    1
+                );
+            }
+        }
+    }
 }
@@ -35,8 +35,8 @@ use gitbutler_repo::{
 };
 use gitbutler_repo_actions::RepoActionsExt;
 use gitbutler_stack::{
-    reconcile_claims, stack_context::CommandContextExt, BranchOwnershipClaims, Stack, StackId,
-    Target, VirtualBranchesHandle,
+    reconcile_claims, stack_context::CommandContextExt, BranchOwnershipClaims, PushDetails, Stack,
+    StackId, Target, VirtualBranchesHandle,
 };
 use gitbutler_time::time::now_since_unix_epoch_ms;
 use itertools::Itertools;
@@ -906,7 +906,14 @@ pub(crate) fn push(
         ))
     };
 
-    ctx.push(stack.head(), &remote_branch, with_force, None, askpass)?;
+    ctx.push(
+        &PushDetails {
+            head: stack.head(),
+            remote_refname: remote_branch.clone(),
+        },
+        with_force,
+        askpass,
+    )?;
 
     stack.upstream = Some(remote_branch.clone());
     stack.upstream_head = Some(stack.head());
@@ -927,16 +934,17 @@ type MergeBaseCommitGraph<'repo, 'cache> = gix::revwalk::Graph<
     gix::revision::plumbing::graph::Commit<gix::revision::plumbing::merge_base::Flags>,
 >;
 
-pub(crate) struct IsCommitIntegrated<'repo, 'cache, 'graph> {
+pub(crate) struct IsCommitIntegrated<'repo, 'cache, 'graph, 'upstream> {
     gix_repo: &'repo gix::Repository,
     graph: &'graph mut MergeBaseCommitGraph<'repo, 'cache>,
     target_commit_id: gix::ObjectId,
     upstream_tree_id: gix::ObjectId,
-    upstream_commits: Vec<git2::Oid>,
+    /// Sorted, so they can be searched.
+    upstream_commits: Cow<'upstream, [git2::Oid]>,
     upstream_change_ids: Vec<String>,
 }
 
-impl<'repo, 'cache, 'graph> IsCommitIntegrated<'repo, 'cache, 'graph> {
+impl<'repo, 'cache, 'graph, 'upstream> IsCommitIntegrated<'repo, 'cache, 'graph, 'upstream> {
     pub(crate) fn new(
         ctx: &'repo CommandContext,
         target: &Target,
@@ -962,29 +970,35 @@ impl<'repo, 'cache, 'graph> IsCommitIntegrated<'repo, 'cache, 'graph> {
             .iter()
             .map(|commit| commit.id())
             .sorted()
-            .collect();
+            .collect::<Vec<_>>();
         Ok(Self {
             gix_repo,
             graph,
             target_commit_id: git2_to_gix_object_id(target.sha),
             upstream_tree_id: git2_to_gix_object_id(upstream_tree_id),
-            upstream_commits,
+            upstream_commits: upstream_commits.into(),
             upstream_change_ids,
         })
     }
 
     /// Used to construct [`IsCommitIntegrated`] without a [`CommandContext`]. If
     /// you have a `CommandContext` available, use [`Self::new`] instead.
+    ///
+    /// `upstream_commits` are only copied if they aren't sorted yet, so they can be
+    /// shared by many checks.
     pub(crate) fn new_basic(
         gix_repository: &'repo gix::Repository,
         repository: &'repo git2::Repository,
         graph: &'graph mut MergeBaseCommitGraph<'repo, 'cache>,
         target_commit_id: gix::ObjectId,
         upstream_tree_id: gix::ObjectId,
-        mut upstream_commits: Vec<git2::Oid>,
+        upstream_commits: impl Into<Cow<'upstream, [git2::Oid]>>,
     ) -> Self {
         // Ensure upstream commits are sorted for binary search
-        upstream_commits.sort();
+        let mut upstream_commits = upstream_commits.into();
+        if !upstream_commits.windows(2).all(|pair| pair[0] <= pair[1]) {
+            upstream_commits.to_mut().sort();
+        }
         let upstream_change_ids = upstream_commits
             .iter()
             .filter_map(|oid| {
@@ -1004,7 +1018,7 @@ impl<'repo, 'cache, 'graph> IsCommitIntegrated<'repo, 'cache, 'graph> {
     }
 }
 
-impl IsCommitIntegrated<'_, '_, '_> {
+impl IsCommitIntegrated<'_, '_, '_, '_> {
     pub(crate) fn is_integrated(&mut self, commit: &git2::Commit) -> Result<bool> {
         if self.target_commit_id == git2_to_gix_object_id(commit.id()) {
             // could not be integrated if heads are the same.
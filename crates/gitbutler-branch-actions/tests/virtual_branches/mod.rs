@@ -76,5 +76,6 @@ mod unapply_without_saving_virtual_branch;
 mod undo_commit;
 mod update_commit_message;
 mod upstream;
+mod upstream_integration;
 mod verify_branch;
 mod workspace_migration;
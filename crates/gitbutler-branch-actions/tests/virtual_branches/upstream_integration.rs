@@ -0,0 +1,1153 @@
+use gitbutler_branch::BranchCreateRequest;
+use gitbutler_branch_actions::upstream_integration::{
+    self, ConflictChoice, IntegratedStack, IntegrationOutcome, IntegrationPlan, IntegrationResult,
+    IntegrationSnapshot, RecordedChoice, Resolution, ResolutionApproach, StackSnapshot,
+    StackStatuses, TargetBranchMissing, UnrelatedTargets, UpstreamIntegrationContext,
+};
+use gitbutler_command_context::CommandContext;
+use gitbutler_commit::commit_ext::CommitExt;
+use gitbutler_reference::RemoteRefname;
+use gitbutler_stack::{
+    CommitOrChangeId, StackBranch, Target, UpdatableResolutionApproach, VirtualBranchesHandle,
+};
+use std::collections::HashMap;
+
+use super::*;
+
+#[test]
+fn abort_restores_pre_integration_state() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    // make sure we have an undiscovered commit in the remote branch
+    {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+    }
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let branch_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("another_file.txt"), "virtual").unwrap();
+    gitbutler_branch_actions::create_commit(project, branch_id, "commit", None, false).unwrap();
+    fs::write(repository.path().join("uncommitted.txt"), "uncommitted").unwrap();
+
+    let branch = gitbutler_branch_actions::list_virtual_branches(project)
+        .unwrap()
+        .branches
+        .into_iter()
+        .find(|branch| branch.id == branch_id)
+        .unwrap();
+    let state = VirtualBranchesHandle::new(project.gb_dir());
+    let snapshot = IntegrationSnapshot {
+        target_sha: state.get_default_target().unwrap().sha,
+        stacks: state
+            .list_stacks_in_workspace()
+            .unwrap()
+            .iter()
+            .map(StackSnapshot::from)
+            .collect(),
+    };
+
+    gitbutler_branch_actions::integrate_upstream(
+        project,
+        &[Resolution::new(
+            branch_id,
+            branch.tree,
+            ResolutionApproach::Rebase,
+        )],
+        None,
+    )
+    .unwrap();
+    assert_ne!(state.get_default_target().unwrap().sha, snapshot.target_sha);
+
+    gitbutler_branch_actions::abort_integration(project, &snapshot).unwrap();
+
+    assert_eq!(state.get_default_target().unwrap().sha, snapshot.target_sha);
+    let stack = state.get_stack(branch_id).unwrap();
+    assert_eq!(stack.head(), snapshot.stacks[0].head);
+    assert_eq!(stack.tree, snapshot.stacks[0].tree);
+    assert_eq!(
+        fs::read_to_string(repository.path().join("file.txt")).unwrap(),
+        "one"
+    );
+    assert_eq!(
+        fs::read_to_string(repository.path().join("uncommitted.txt")).unwrap(),
+        "uncommitted"
+    );
+}
+
+#[test]
+fn abort_restores_unapplied_stacks() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    // make sure we have an undiscovered commit in the remote branch
+    {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+    }
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let branch_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("another_file.txt"), "virtual").unwrap();
+    gitbutler_branch_actions::create_commit(project, branch_id, "commit", None, false).unwrap();
+    fs::write(repository.path().join("uncommitted.txt"), "uncommitted").unwrap();
+
+    let state = VirtualBranchesHandle::new(project.gb_dir());
+    let stack = state.get_stack(branch_id).unwrap();
+    let snapshot = IntegrationSnapshot {
+        target_sha: state.get_default_target().unwrap().sha,
+        stacks: vec![StackSnapshot::from(&stack)],
+    };
+
+    let outcome = gitbutler_branch_actions::integrate_upstream(
+        project,
+        &[Resolution::new(
+            branch_id,
+            stack.tree,
+            ResolutionApproach::Unapply,
+        )],
+        None,
+    )
+    .unwrap();
+    assert_eq!(outcome.unapplied, vec![branch_id]);
+    assert!(state.list_stacks_in_workspace().unwrap().is_empty());
+
+    gitbutler_branch_actions::abort_integration(project, &snapshot).unwrap();
+
+    assert_eq!(state.get_default_target().unwrap().sha, snapshot.target_sha);
+    let restored = state.get_stack(branch_id).unwrap();
+    assert!(restored.in_workspace);
+    assert_eq!(restored.heads, stack.heads);
+    assert_eq!(restored.head(), stack.head());
+    assert_eq!(restored.tree, stack.tree);
+    assert_eq!(
+        fs::read_to_string(repository.path().join("another_file.txt")).unwrap(),
+        "virtual"
+    );
+    assert_eq!(
+        fs::read_to_string(repository.path().join("uncommitted.txt")).unwrap(),
+        "uncommitted"
+    );
+}
+
+#[test]
+fn rebased_change_ids_map_to_their_new_commits() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    // make sure we have an undiscovered commit in the remote branch
+    {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+    }
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let branch_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("another_file.txt"), "virtual").unwrap();
+    let old_commit_id =
+        gitbutler_branch_actions::create_commit(project, branch_id, "commit", None, false).unwrap();
+
+    let state = VirtualBranchesHandle::new(project.gb_dir());
+    let stack = state.get_stack(branch_id).unwrap();
+    let snapshot = IntegrationSnapshot {
+        target_sha: state.get_default_target().unwrap().sha,
+        stacks: vec![StackSnapshot::from(&stack)],
+    };
+
+    gitbutler_branch_actions::integrate_upstream(
+        project,
+        &[Resolution::new(
+            branch_id,
+            stack.tree,
+            ResolutionApproach::Rebase,
+        )],
+        None,
+    )
+    .unwrap();
+
+    let command_context = CommandContext::open(project).unwrap();
+    let change_id = command_context
+        .repo()
+        .find_commit(old_commit_id)
+        .unwrap()
+        .change_id()
+        .unwrap();
+    let new_commit_id = state.get_stack(branch_id).unwrap().head();
+    assert_ne!(new_commit_id, old_commit_id);
+
+    let obsoleted =
+        upstream_integration::obsoleted_change_ids(&snapshot, &command_context).unwrap();
+    assert_eq!(obsoleted, HashMap::from([(change_id, new_commit_id)]));
+}
+
+#[test]
+fn branches_absorbed_by_upstream_are_newly_empty() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    // make sure we have an undiscovered commit in the remote branch
+    {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+    }
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    // The branch commits the same change as upstream, and keeps some uncommitted
+    // work so it can still be rebased.
+    let branch_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("file.txt"), "two").unwrap();
+    gitbutler_branch_actions::create_commit(project, branch_id, "same as upstream", None, false)
+        .unwrap();
+    fs::write(repository.path().join("wip.txt"), "wip").unwrap();
+
+    let branch = gitbutler_branch_actions::list_virtual_branches(project)
+        .unwrap()
+        .branches
+        .into_iter()
+        .find(|branch| branch.id == branch_id)
+        .unwrap();
+    let state = VirtualBranchesHandle::new(project.gb_dir());
+    let snapshot = IntegrationSnapshot {
+        target_sha: state.get_default_target().unwrap().sha,
+        stacks: vec![StackSnapshot::from(&state.get_stack(branch_id).unwrap())],
+    };
+
+    gitbutler_branch_actions::integrate_upstream(
+        project,
+        &[Resolution::new(
+            branch_id,
+            branch.tree,
+            ResolutionApproach::Rebase,
+        )],
+        None,
+    )
+    .unwrap();
+
+    let command_context = CommandContext::open(project).unwrap();
+    assert_eq!(
+        upstream_integration::newly_empty_branches(&snapshot, &command_context).unwrap(),
+        vec![branch_id]
+    );
+}
+
+#[test]
+fn revert_target_restores_the_default_target() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    // make sure we have an undiscovered commit in the remote branch
+    {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+    }
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let state = VirtualBranchesHandle::new(project.gb_dir());
+    let old_target_sha = state.get_default_target().unwrap().sha;
+
+    gitbutler_branch_actions::integrate_upstream(project, &[], None).unwrap();
+    assert_ne!(state.get_default_target().unwrap().sha, old_target_sha);
+
+    gitbutler_branch_actions::revert_target(project, old_target_sha).unwrap();
+    assert_eq!(state.get_default_target().unwrap().sha, old_target_sha);
+}
+
+#[test]
+fn integrating_the_current_target_changes_nothing() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let branch_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("file.txt"), "content").unwrap();
+    gitbutler_branch_actions::create_commit(project, branch_id, "commit", None, false).unwrap();
+
+    let state = VirtualBranchesHandle::new(project.gb_dir());
+    let target_sha = state.get_default_target().unwrap().sha;
+    let stack = state.get_stack(branch_id).unwrap();
+
+    let outcome = gitbutler_branch_actions::integrate_upstream(
+        project,
+        &[Resolution::new(
+            branch_id,
+            stack.tree,
+            ResolutionApproach::Rebase,
+        )],
+        None,
+    )
+    .unwrap();
+
+    assert_eq!(outcome, IntegrationOutcome::default());
+    assert_eq!(state.get_default_target().unwrap().sha, target_sha);
+    let stack_after = state.get_stack(branch_id).unwrap();
+    assert_eq!(stack_after.head(), stack.head());
+    assert_eq!(stack_after.tree, stack.tree);
+}
+
+#[test]
+fn a_missing_target_branch_is_named() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    git2::Repository::open(repository.path())
+        .unwrap()
+        .find_reference("refs/remotes/origin/master")
+        .unwrap()
+        .delete()
+        .unwrap();
+
+    let err = gitbutler_branch_actions::upstream_integration_statuses(project, None).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<TargetBranchMissing>(),
+        Some(&TargetBranchMissing("refs/remotes/origin/master".into()))
+    );
+}
+
+#[test]
+fn unrelated_targets_are_rejected() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let git_repository = git2::Repository::open(repository.path()).unwrap();
+    let signature = git2::Signature::now("test", "test@example.com").unwrap();
+    let empty_tree = git_repository
+        .find_tree(git_repository.treebuilder(None).unwrap().write().unwrap())
+        .unwrap();
+    let unrelated_commit = git_repository
+        .commit(None, &signature, &signature, "unrelated", &empty_tree, &[])
+        .unwrap();
+
+    let err =
+        gitbutler_branch_actions::upstream_integration_statuses(project, Some(unrelated_commit))
+            .unwrap_err();
+    let unrelated_targets = err.downcast_ref::<UnrelatedTargets>().unwrap();
+    assert_eq!(unrelated_targets.new_target, unrelated_commit);
+}
+
+#[test]
+fn statuses_against_a_remote_branch() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    // make sure we have an undiscovered commit in the remote branch
+    {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+    }
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let branch_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("another_file.txt"), "virtual").unwrap();
+    gitbutler_branch_actions::create_commit(project, branch_id, "commit", None, false).unwrap();
+
+    let command_context = CommandContext::open(project).unwrap();
+    let mut guard = project.exclusive_worktree_access();
+    let context = UpstreamIntegrationContext::open_against_remote(
+        &command_context,
+        guard.write_permission(),
+        "origin",
+        "master",
+    )
+    .unwrap();
+
+    let StackStatuses::UpdatesRequired(statuses) =
+        upstream_integration::upstream_integration_statuses(&context).unwrap()
+    else {
+        panic!("Expected the remote branch to require updates");
+    };
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0].0, branch_id);
+}
+
+#[test]
+fn a_context_against_a_patch_reference() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    // make sure we have an undiscovered commit in the remote branch
+    let second_commit_oid = {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        let second_commit_oid = repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+        second_commit_oid
+    };
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let reference = StackBranch {
+        name: "upstream".into(),
+        head: CommitOrChangeId::CommitId(second_commit_oid.to_string()),
+        description: None,
+        pr_number: None,
+        archived: false,
+    };
+
+    let command_context = CommandContext::open(project).unwrap();
+    let mut guard = project.exclusive_worktree_access();
+    let context = UpstreamIntegrationContext::open_with_patch_reference(
+        &command_context,
+        guard.write_permission(),
+        &reference,
+    )
+    .unwrap();
+
+    assert_eq!(context.new_target_id(), second_commit_oid);
+}
+
+#[test]
+fn conflict_count_for_a_candidate_target() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    // make sure we have an undiscovered commit in the remote branch
+    let candidate = {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        let second_commit_oid = repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+        second_commit_oid
+    };
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let conflicting_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("file.txt"), "three").unwrap();
+    gitbutler_branch_actions::create_commit(project, conflicting_id, "conflicting", None, false)
+        .unwrap();
+
+    let clean_id = gitbutler_branch_actions::create_virtual_branch(
+        project,
+        &BranchCreateRequest {
+            selected_for_changes: Some(true),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    fs::write(repository.path().join("clean.txt"), "clean").unwrap();
+    gitbutler_branch_actions::create_commit(project, clean_id, "clean", None, false).unwrap();
+
+    let count = gitbutler_branch_actions::conflict_count_for_target(project, candidate).unwrap();
+
+    let statuses =
+        gitbutler_branch_actions::upstream_integration_statuses(project, Some(candidate)).unwrap();
+    assert_eq!(count, statuses.conflicted_count());
+    assert_eq!(count, 1);
+}
+
+#[test]
+fn resolved_conflicts_are_recorded_in_the_integration_state() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    // make sure we have an undiscovered commit in the remote branch
+    {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+    }
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let branch_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("file.txt"), "three").unwrap();
+    gitbutler_branch_actions::create_commit(project, branch_id, "conflicting", None, false)
+        .unwrap();
+
+    assert_eq!(
+        upstream_integration::load_integration_state(project.gb_dir()).unwrap(),
+        None
+    );
+
+    gitbutler_branch_actions::resolve_integration_conflict(
+        project,
+        branch_id,
+        "file.txt",
+        ConflictChoice::Theirs,
+    )
+    .unwrap();
+
+    let state = upstream_integration::load_integration_state(project.gb_dir())
+        .unwrap()
+        .unwrap();
+    assert_eq!(state.conflicted_stack_ids, vec![branch_id]);
+    assert_eq!(state.journal.entries.len(), 1);
+    assert_eq!(state.journal.entries[0].branch_id, branch_id);
+    assert_eq!(state.journal.entries[0].path, "file.txt");
+    assert_eq!(state.journal.entries[0].choice, RecordedChoice::Theirs);
+}
+
+#[test]
+fn outcome_lists_the_applied_resolutions() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    // make sure we have an undiscovered commit in the remote branch
+    {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+    }
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let rebased_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("rebased.txt"), "rebased").unwrap();
+    gitbutler_branch_actions::create_commit(project, rebased_id, "rebased", None, false).unwrap();
+
+    let unapplied_id = gitbutler_branch_actions::create_virtual_branch(
+        project,
+        &BranchCreateRequest {
+            selected_for_changes: Some(true),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    fs::write(repository.path().join("unapplied.txt"), "unapplied").unwrap();
+    gitbutler_branch_actions::create_commit(project, unapplied_id, "unapplied", None, false)
+        .unwrap();
+
+    let resolutions = gitbutler_branch_actions::list_virtual_branches(project)
+        .unwrap()
+        .branches
+        .into_iter()
+        .map(|branch| {
+            Resolution::new(
+                branch.id,
+                branch.tree,
+                if branch.id == rebased_id {
+                    ResolutionApproach::Rebase
+                } else {
+                    ResolutionApproach::Unapply
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let outcome =
+        gitbutler_branch_actions::integrate_upstream(project, &resolutions, None).unwrap();
+
+    assert_eq!(
+        outcome,
+        IntegrationOutcome {
+            integrated: vec![rebased_id],
+            approaches: vec![IntegratedStack {
+                stack_id: rebased_id,
+                approach: ResolutionApproach::Rebase,
+            }],
+            unapplied: vec![unapplied_id],
+            deleted: vec![],
+            unchanged: vec![],
+            conflicted: vec![],
+            stashed: vec![],
+            integration_tips: vec![],
+            whitespace_resolved: vec![],
+        }
+    );
+}
+
+#[test]
+fn partial_integration_only_moves_the_resolved_branches() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    // make sure we have an undiscovered commit in the remote branch
+    {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+    }
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let resolved_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("resolved.txt"), "resolved").unwrap();
+    gitbutler_branch_actions::create_commit(project, resolved_id, "resolved", None, false).unwrap();
+
+    let pending_id = gitbutler_branch_actions::create_virtual_branch(
+        project,
+        &BranchCreateRequest {
+            selected_for_changes: Some(true),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    fs::write(repository.path().join("pending.txt"), "pending").unwrap();
+    gitbutler_branch_actions::create_commit(project, pending_id, "pending", None, false).unwrap();
+
+    let state = VirtualBranchesHandle::new(project.gb_dir());
+    let target_sha = state.get_default_target().unwrap().sha;
+    let resolved_before = state.get_stack(resolved_id).unwrap();
+    let pending_before = state.get_stack(pending_id).unwrap();
+
+    let outcome = gitbutler_branch_actions::integrate_upstream_partial(
+        project,
+        &[Resolution::new(
+            resolved_id,
+            resolved_before.tree,
+            ResolutionApproach::Rebase,
+        )],
+    )
+    .unwrap();
+
+    assert_eq!(outcome.integrated, vec![resolved_id]);
+    assert_ne!(
+        state.get_stack(resolved_id).unwrap().head(),
+        resolved_before.head()
+    );
+    assert_eq!(
+        state.get_stack(pending_id).unwrap().head(),
+        pending_before.head()
+    );
+    assert_ne!(state.get_default_target().unwrap().sha, target_sha);
+    assert_eq!(
+        state.get_stack(pending_id).unwrap().integration_base,
+        Some(target_sha)
+    );
+    assert_eq!(state.get_stack(resolved_id).unwrap().integration_base, None);
+
+    let StackStatuses::UpdatesRequired(statuses) =
+        gitbutler_branch_actions::upstream_integration_statuses(project, None).unwrap()
+    else {
+        panic!("Expected the pending branch to still require updates");
+    };
+    assert_eq!(
+        statuses.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+        vec![pending_id]
+    );
+
+    gitbutler_branch_actions::integrate_upstream(
+        project,
+        &[Resolution::new(
+            pending_id,
+            pending_before.tree,
+            ResolutionApproach::Rebase,
+        )],
+        None,
+    )
+    .unwrap();
+
+    assert_eq!(state.get_stack(pending_id).unwrap().integration_base, None);
+    assert_eq!(
+        gitbutler_branch_actions::upstream_integration_statuses(project, None).unwrap(),
+        StackStatuses::UpToDate
+    );
+}
+
+/// Sets up a remote with a commit that isn't integrated yet, and a branch with a commit
+/// of its own. Returns the branch's resolution for a rebase.
+fn plan_setup(repository: &TestProject, project: &Project) -> Resolution {
+    // make sure we have an undiscovered commit in the remote branch
+    {
+        fs::write(repository.path().join("file.txt"), "one").unwrap();
+        let first_commit_oid = repository.commit_all("first");
+        fs::write(repository.path().join("file.txt"), "two").unwrap();
+        repository.commit_all("second");
+        repository.push();
+        repository.reset_hard(Some(first_commit_oid));
+    }
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let branch_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("other.txt"), "other").unwrap();
+    gitbutler_branch_actions::create_commit(project, branch_id, "other", None, false).unwrap();
+
+    let stack = VirtualBranchesHandle::new(project.gb_dir())
+        .get_stack(branch_id)
+        .unwrap();
+    Resolution::new(branch_id, stack.tree, ResolutionApproach::Rebase)
+}
+
+#[test]
+fn a_stored_plan_can_be_applied_later() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    let resolution = plan_setup(repository, project);
+    let branch_id = resolution.branch_id;
+    let plan =
+        gitbutler_branch_actions::build_integration_plan(project, vec![resolution], None).unwrap();
+    assert_eq!(plan.expected_outcome.integrated, vec![branch_id]);
+
+    let stored = serde_json::to_string(&plan).unwrap();
+    let restored: IntegrationPlan = serde_json::from_str(&stored).unwrap();
+    assert_eq!(restored, plan);
+
+    let new_target = restored.new_target;
+    let outcome = gitbutler_branch_actions::apply_integration_plan(project, restored).unwrap();
+
+    assert_eq!(outcome, plan.expected_outcome);
+    assert_eq!(
+        VirtualBranchesHandle::new(project.gb_dir())
+            .get_default_target()
+            .unwrap()
+            .sha,
+        new_target
+    );
+}
+
+#[test]
+fn integration_tips_are_anchored_in_a_reference() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    let resolution = Resolution {
+        approach: ResolutionApproach::MergeIntoTarget,
+        ..plan_setup(repository, project)
+    };
+    let branch_id = resolution.branch_id;
+
+    let outcome =
+        gitbutler_branch_actions::integrate_upstream(project, &[resolution], None).unwrap();
+    let [tip] = outcome.integration_tips.as_slice() else {
+        panic!("Expected a single integration tip");
+    };
+
+    let command_context = CommandContext::open(project).unwrap();
+    let reference = command_context
+        .repo()
+        .find_reference(&upstream_integration::integration_tip_refname(branch_id))
+        .unwrap();
+    assert_eq!(reference.target(), Some(tip.tip));
+}
+
+#[test]
+fn the_default_target_remote_is_the_remote_of_the_target_branch() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    plan_setup(repository, project);
+    let state = VirtualBranchesHandle::new(project.gb_dir());
+    let target = state.get_default_target().unwrap();
+    state
+        .set_default_target(Target {
+            branch: "refs/remotes/upstream/main".parse().unwrap(),
+            ..target
+        })
+        .unwrap();
+
+    let command_context = CommandContext::open(project).unwrap();
+    assert_eq!(
+        upstream_integration::default_target_remote(&command_context).unwrap(),
+        "upstream"
+    );
+    // A local branch has no remote, so it can't be the default target to begin with.
+    assert!("refs/heads/main".parse::<RemoteRefname>().is_err());
+}
+
+#[test]
+fn abort_deletes_the_integration_references() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    let resolution = Resolution {
+        approach: ResolutionApproach::MergeIntoTarget,
+        ..plan_setup(repository, project)
+    };
+    let state = VirtualBranchesHandle::new(project.gb_dir());
+    let snapshot = IntegrationSnapshot {
+        target_sha: state.get_default_target().unwrap().sha,
+        stacks: state
+            .list_all_stacks()
+            .unwrap()
+            .iter()
+            .map(StackSnapshot::from)
+            .collect(),
+    };
+
+    let outcome =
+        gitbutler_branch_actions::integrate_upstream(project, &[resolution], None).unwrap();
+    assert_eq!(outcome.integration_tips.len(), 1);
+
+    gitbutler_branch_actions::abort_integration(project, &snapshot).unwrap();
+
+    let command_context = CommandContext::open(project).unwrap();
+    let references = command_context
+        .repo()
+        .references_glob("refs/gitbutler/*")
+        .unwrap()
+        .map(|reference| reference.unwrap().name().unwrap().to_owned())
+        .filter(|name| {
+            name.starts_with("refs/gitbutler/integration-tips/")
+                || name.starts_with("refs/gitbutler/stash/")
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(references, Vec::<String>::new());
+}
+
+#[test]
+fn stacks_based_on_another_target_are_not_integrated() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    let branch_id = plan_setup(repository, project).branch_id;
+    gitbutler_branch_actions::stack::set_integration_target(
+        project,
+        branch_id,
+        Some("refs/remotes/origin/release".parse().unwrap()),
+    )
+    .unwrap();
+
+    let state = VirtualBranchesHandle::new(project.gb_dir());
+    assert_eq!(
+        state.get_stack(branch_id).unwrap().integration_target,
+        Some("refs/remotes/origin/release".parse().unwrap())
+    );
+    let StackStatuses::UpdatesRequired(statuses) =
+        gitbutler_branch_actions::upstream_integration_statuses(project, None).unwrap()
+    else {
+        panic!("Expected the target to require updates");
+    };
+    assert!(statuses.is_empty());
+}
+
+#[test]
+fn the_preferred_integration_approach_is_persisted() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    let branch_id = plan_setup(repository, project).branch_id;
+    gitbutler_branch_actions::stack::set_preferred_integration_approach(
+        project,
+        branch_id,
+        Some(UpdatableResolutionApproach::Merge),
+    )
+    .unwrap();
+
+    let state = VirtualBranchesHandle::new(project.gb_dir());
+    let stack = state.get_stack(branch_id).unwrap();
+    assert_eq!(
+        stack.preferred_integration_approach,
+        Some(UpdatableResolutionApproach::Merge)
+    );
+    assert_eq!(
+        Resolution::default_for_stack(&stack).approach,
+        ResolutionApproach::Merge
+    );
+}
+
+#[test]
+fn a_plan_is_refused_once_the_target_moved() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    let resolution = plan_setup(repository, project);
+    let plan =
+        gitbutler_branch_actions::build_integration_plan(project, vec![resolution], None).unwrap();
+
+    gitbutler_branch_actions::integrate_upstream(project, &plan.resolutions, None).unwrap();
+
+    let error = gitbutler_branch_actions::apply_integration_plan(project, plan).unwrap_err();
+    assert!(error.to_string().contains("The target moved"), "{error}");
+}
+
+#[test]
+fn branches_ahead_of_their_upstream_need_a_push() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    gitbutler_branch_actions::set_base_branch(
+        project,
+        &"refs/remotes/origin/master".parse().unwrap(),
+    )
+    .unwrap();
+
+    let pushed_id =
+        gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+            .unwrap();
+    fs::write(repository.path().join("file.txt"), "pushed").unwrap();
+    gitbutler_branch_actions::create_commit(project, pushed_id, "pushed", None, false).unwrap();
+    #[allow(deprecated)]
+    gitbutler_branch_actions::push_virtual_branch(project, pushed_id, false, None).unwrap();
+
+    let command_context = CommandContext::open(project).unwrap();
+    assert_eq!(
+        upstream_integration::branches_needing_push(&command_context).unwrap(),
+        vec![]
+    );
+
+    fs::write(repository.path().join("file.txt"), "moved").unwrap();
+    gitbutler_branch_actions::create_commit(project, pushed_id, "moved", None, false).unwrap();
+
+    gitbutler_branch_actions::create_virtual_branch(project, &BranchCreateRequest::default())
+        .unwrap();
+
+    assert_eq!(
+        upstream_integration::branches_needing_push(&command_context).unwrap(),
+        vec![pushed_id]
+    );
+}
+
+#[test]
+fn a_single_upstream_commit_can_be_cherry_picked_into_a_branch() {
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    let branch_id = plan_setup(repository, project).branch_id;
+    let command_context = CommandContext::open(project).unwrap();
+    let upstream_commit = command_context
+        .repo()
+        .find_reference("refs/remotes/origin/master")
+        .unwrap()
+        .peel_to_commit()
+        .unwrap();
+
+    let result =
+        gitbutler_branch_actions::cherry_pick_into_branch(project, branch_id, upstream_commit.id())
+            .unwrap();
+    let IntegrationResult::UpdatedObjects { head, .. } = result else {
+        panic!("Expected the branch to be updated");
+    };
+
+    let stack = VirtualBranchesHandle::new(project.gb_dir())
+        .get_stack(branch_id)
+        .unwrap();
+    assert_eq!(stack.head(), head);
+    let head = command_context.repo().find_commit(head).unwrap();
+    assert!(!head.is_conflicted());
+    assert_eq!(head.message(), Some("second"));
+    assert_eq!(head.parent(0).unwrap().message(), Some("other"));
+    assert_eq!(
+        fs::read_to_string(repository.path().join("file.txt")).unwrap(),
+        "two"
+    );
+}
+
+#[test]
+fn integrating_upstream_is_recorded_in_the_oplog_and_can_be_undone() {
+    use gitbutler_oplog::{entry::OperationKind, OplogExt};
+
+    let Test {
+        repository,
+        project,
+        ..
+    } = &Test::default();
+
+    let resolution = plan_setup(repository, project);
+    let handle = VirtualBranchesHandle::new(project.gb_dir());
+    let old_target = handle.get_default_target().unwrap().sha;
+    let old_head = handle.get_stack(resolution.branch_id).unwrap().head();
+
+    gitbutler_branch_actions::integrate_upstream(project, &[resolution], None).unwrap();
+    assert_ne!(handle.get_default_target().unwrap().sha, old_target);
+
+    let snapshots = project.list_snapshots(10, None).unwrap();
+    let details = snapshots[0].details.as_ref().unwrap();
+    assert_eq!(details.operation, OperationKind::UpdateWorkspaceBase);
+    assert!(details
+        .trailers
+        .iter()
+        .any(|trailer| trailer.key == "outcome" && trailer.value == "1 integrated"));
+
+    let mut guard = project.exclusive_worktree_access();
+    project
+        .restore_snapshot(snapshots[0].commit_id, guard.write_permission())
+        .unwrap();
+    drop(guard);
+
+    let stacks = handle.list_stacks_in_workspace().unwrap();
+    assert_eq!(handle.get_default_target().unwrap().sha, old_target);
+    assert_eq!(stacks.len(), 1);
+    assert_eq!(stacks[0].head(), old_head);
+}
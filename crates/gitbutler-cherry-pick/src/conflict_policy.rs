@@ -0,0 +1,137 @@
+use anyhow::{Context, Result};
+use gitbutler_oxidize::GixRepositoryExt as _;
+use gix::bstr::BStr;
+use gix::merge::blob::builtin_driver::text::Labels;
+use gix::merge::tree::TreatAsUnresolved;
+use gix::object::tree::EntryKind;
+
+use crate::conflicting_paths;
+
+/// The entry a conflicting path has in each tree of a merge, or `None` if it has none there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConflictSides {
+    pub base: Option<(EntryKind, gix::ObjectId)>,
+    pub ours: Option<(EntryKind, gix::ObjectId)>,
+    pub theirs: Option<(EntryKind, gix::ObjectId)>,
+}
+
+/// How a [`ConflictPolicy`] merges a path that conflicts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictResolution {
+    /// Take our version.
+    Ours,
+    /// Take their version.
+    Theirs,
+    /// Take the given blob, e.g. the result of a merge driver.
+    Blob(gix::ObjectId),
+}
+
+/// Decides how the paths that conflict in a merge are merged instead, e.g. to honour a
+/// preference for one side. Paths without a resolution stay conflicted.
+pub trait ConflictPolicy {
+    /// Returns the labels of the conflict markers of the files that stay conflicted.
+    fn labels(&self) -> Labels<'_>;
+
+    /// Returns how to merge `path`, which conflicts between `sides`, or `None` to leave it
+    /// conflicted. The blobs of the sides can be read from `repository`.
+    fn resolve(
+        &self,
+        repository: &gix::Repository,
+        path: &BStr,
+        sides: &ConflictSides,
+    ) -> Result<Option<ConflictResolution>>;
+}
+
+/// Merges `ours` and `theirs` on top of `base`, like [`gix::Repository::merge_trees()`] does.
+///
+/// With a `policy`, the paths that conflict are resolved as it says. This is done by
+/// replacing the side that should lose with the base version in its tree and merging again,
+/// so everything else merges exactly like it would have without the policy.
+pub fn merge_trees_with_policy<'repo>(
+    repository: &'repo gix::Repository,
+    base: gix::ObjectId,
+    ours: gix::ObjectId,
+    theirs: gix::ObjectId,
+    options: gix::merge::tree::Options,
+    policy: Option<&dyn ConflictPolicy>,
+) -> Result<gix::merge::tree::Outcome<'repo>> {
+    let Some(policy) = policy else {
+        return repository
+            .merge_trees(
+                base,
+                ours,
+                theirs,
+                repository.default_merge_labels(),
+                options,
+            )
+            .context("failed to merge trees");
+    };
+
+    let merge = repository
+        .merge_trees(base, ours, theirs, policy.labels(), options.clone())
+        .context("failed to merge trees")?;
+    let paths = conflicting_paths(&merge, TreatAsUnresolved::forced_resolution());
+    if paths.is_empty() {
+        return Ok(merge);
+    }
+
+    let (base_tree, our_tree, their_tree) = (
+        repository.find_tree(base)?,
+        repository.find_tree(ours)?,
+        repository.find_tree(theirs)?,
+    );
+    let entry = |tree: &gix::Tree<'_>, path: &BStr| -> Result<_> {
+        Ok(tree
+            .lookup_entry_by_path(gix::path::from_bstr(path))?
+            .map(|entry| (entry.mode().kind(), entry.object_id())))
+    };
+
+    let mut our_editor = repository.edit_tree(ours)?;
+    let mut their_editor = repository.edit_tree(theirs)?;
+    let mut resolved_any = false;
+    for path in &paths {
+        let path = path.as_ref();
+        let sides = ConflictSides {
+            base: entry(&base_tree, path)?,
+            ours: entry(&our_tree, path)?,
+            theirs: entry(&their_tree, path)?,
+        };
+        let Some(resolution) = policy.resolve(repository, path, &sides)? else {
+            continue;
+        };
+        match resolution {
+            ConflictResolution::Ours => set_entry(&mut their_editor, path, sides.base)?,
+            ConflictResolution::Theirs => set_entry(&mut our_editor, path, sides.base)?,
+            ConflictResolution::Blob(blob) => {
+                let kind = sides
+                    .ours
+                    .or(sides.theirs)
+                    .map_or(EntryKind::Blob, |(kind, _)| kind);
+                set_entry(&mut our_editor, path, Some((kind, blob)))?;
+                set_entry(&mut their_editor, path, Some((kind, blob)))?;
+            }
+        }
+        resolved_any = true;
+    }
+    if !resolved_any {
+        return Ok(merge);
+    }
+
+    let ours = our_editor.write()?.detach();
+    let theirs = their_editor.write()?.detach();
+    repository
+        .merge_trees(base, ours, theirs, policy.labels(), options)
+        .context("failed to merge trees with resolved conflicts")
+}
+
+fn set_entry(
+    editor: &mut gix::object::tree::Editor<'_>,
+    path: &BStr,
+    entry: Option<(EntryKind, gix::ObjectId)>,
+) -> Result<()> {
+    match entry {
+        Some((kind, id)) => editor.upsert(path, kind, id)?,
+        None => editor.remove(path)?,
+    };
+    Ok(())
+}
@@ -3,6 +3,12 @@ use std::ops::Deref;
 use anyhow::{Context, Result};
 use gitbutler_commit::commit_ext::CommitExt;
 use gitbutler_oxidize::git2_to_gix_object_id;
+use gix::bstr::BString;
+
+mod conflict_policy;
+pub use conflict_policy::{
+    merge_trees_with_policy, ConflictPolicy, ConflictResolution, ConflictSides,
+};
 
 #[derive(Default)]
 pub enum ConflictedTreeKey {
@@ -54,6 +60,15 @@ pub trait GixRepositoryExt {
         to_rebase: &git2::Commit,
     ) -> Result<gix::merge::tree::Outcome<'repo>>;
 
+    /// Like [`Self::cherry_pick_gitbutler()`], but resolves the files that conflict as `policy`
+    /// says. In its terms, *ours* is `head` and *theirs* is `to_rebase`.
+    fn cherry_pick_gitbutler_with_policy<'repo>(
+        &'repo self,
+        head: &git2::Commit,
+        to_rebase: &git2::Commit,
+        policy: Option<&dyn ConflictPolicy>,
+    ) -> Result<gix::merge::tree::Outcome<'repo>>;
+
     /// Find the real tree of a commit, which is the tree of the commit if it's not in a conflicted state
     /// or the tree according to `side` if it is conflicted.
     ///
@@ -86,6 +101,15 @@ impl GixRepositoryExt for gix::Repository {
         &'repo self,
         head: &git2::Commit,
         to_rebase: &git2::Commit,
+    ) -> Result<gix::merge::tree::Outcome<'repo>> {
+        self.cherry_pick_gitbutler_with_policy(head, to_rebase, None)
+    }
+
+    fn cherry_pick_gitbutler_with_policy<'repo>(
+        &'repo self,
+        head: &git2::Commit,
+        to_rebase: &git2::Commit,
+        policy: Option<&dyn ConflictPolicy>,
     ) -> Result<gix::merge::tree::Outcome<'repo>> {
         // we need to do a manual 3-way patch merge
         // find the base, which is the parent of to_rebase
@@ -109,12 +133,13 @@ impl GixRepositoryExt for gix::Repository {
         )?;
 
         use gitbutler_oxidize::GixRepositoryExt;
-        self.merge_trees(
-            base,
-            ours,
-            theirs,
-            self.default_merge_labels(),
+        merge_trees_with_policy(
+            self,
+            base.detach(),
+            ours.detach(),
+            theirs.detach(),
             self.merge_options_force_ours()?,
+            policy,
         )
         .context("failed to merge trees for cherry pick")
     }
@@ -136,3 +161,24 @@ impl GixRepositoryExt for gix::Repository {
         })
     }
 }
+
+/// Returns the paths of the conflicts in `merge` that count as unresolved according to
+/// `treat_as_unresolved`, in order and without duplicates. Conflicts involving a rename
+/// contribute the paths of both sides.
+pub fn conflicting_paths(
+    merge: &gix::merge::tree::Outcome<'_>,
+    treat_as_unresolved: gix::merge::tree::TreatAsUnresolved,
+) -> Vec<BString> {
+    let mut paths = merge
+        .conflicts
+        .iter()
+        .filter(|conflict| conflict.is_unresolved(treat_as_unresolved))
+        .flat_map(|conflict| {
+            let (ours, theirs) = conflict.changes_in_resolution();
+            [ours.location().to_owned(), theirs.location().to_owned()]
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths.dedup();
+    paths
+}
@@ -66,6 +66,7 @@ pub mod workspace {
     use crate::args::UpdateMode;
     use gitbutler_branch_actions::upstream_integration;
     use gitbutler_project::Project;
+    use gitbutler_stack::VirtualBranchesHandle;
 
     pub fn update(project: Project, mode: UpdateMode) -> anyhow::Result<()> {
         let approach = match mode {
@@ -74,15 +75,18 @@ pub mod workspace {
             UpdateMode::Unapply => upstream_integration::ResolutionApproach::Unapply,
             UpdateMode::Delete => upstream_integration::ResolutionApproach::Delete,
         };
+        let stacks = VirtualBranchesHandle::new(project.gb_dir()).list_stacks_in_workspace()?;
         let resolutions: Vec<_> = gitbutler_branch_actions::list_virtual_branches(&project)?
             .branches
             .into_iter()
-            .map(|b| upstream_integration::Resolution {
-                branch_id: b.id,
-                branch_tree: b.tree,
-                approach,
+            .filter(|b| {
+                !stacks
+                    .iter()
+                    .any(|stack| stack.id == b.id && stack.integration_excluded)
             })
+            .map(|b| upstream_integration::Resolution::new(b.id, b.tree, approach.clone()))
             .collect();
-        gitbutler_branch_actions::integrate_upstream(&project, &resolutions, None)
+        gitbutler_branch_actions::integrate_upstream(&project, &resolutions, None)?;
+        Ok(())
     }
 }
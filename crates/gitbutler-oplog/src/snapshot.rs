@@ -70,6 +70,15 @@ pub trait SnapshotExt {
         new_branch_name: &str,
         perm: &mut WorktreeWritePermission,
     ) -> anyhow::Result<()>;
+    /// Records integrating upstream changes into the workspace, with `snapshot_tree`
+    /// capturing the workspace from before, so the integration can be undone.
+    /// `result` is a summary of what the integration did, or why it failed.
+    fn snapshot_upstream_integration(
+        &self,
+        snapshot_tree: git2::Oid,
+        result: Result<String, &anyhow::Error>,
+        perm: &mut WorktreeWritePermission,
+    ) -> anyhow::Result<()>;
 }
 
 /// Snapshot functionality
@@ -300,6 +309,17 @@ impl SnapshotExt for Project {
         self.create_snapshot(details, perm)?;
         Ok(())
     }
+    fn snapshot_upstream_integration(
+        &self,
+        snapshot_tree: git2::Oid,
+        result: Result<String, &anyhow::Error>,
+        perm: &mut WorktreeWritePermission,
+    ) -> anyhow::Result<()> {
+        let details = SnapshotDetails::new(OperationKind::UpdateWorkspaceBase)
+            .with_trailers(result_trailer(result.map(Some), "outcome".to_string()));
+        self.commit_snapshot(snapshot_tree, details, perm)?;
+        Ok(())
+    }
 }
 
 fn result_trailer(result: Result<Option<String>, &anyhow::Error>, key: String) -> Vec<Trailer> {
@@ -6,7 +6,7 @@ use gitbutler_commit::commit_headers::CommitHeadersV2;
 use gitbutler_error::error::Code;
 use gitbutler_project::AuthKey;
 use gitbutler_reference::{Refname, RemoteRefname};
-use gitbutler_stack::{Stack, StackId};
+use gitbutler_stack::{PushDestination, PushDetails, Stack, StackId};
 
 use crate::askpass;
 use gitbutler_repo::{
@@ -18,10 +18,16 @@ pub trait RepoActionsExt {
     fn fetch(&self, remote_name: &str, askpass: Option<String>) -> Result<()>;
     fn push(
         &self,
-        head: git2::Oid,
-        branch: &RemoteRefname,
+        push_details: &PushDetails,
+        with_force: bool,
+        askpass_broker: Option<Option<StackId>>,
+    ) -> Result<()>;
+    /// Like `push`, but pushes to `destination` on the remote, e.g. for review.
+    fn push_to(
+        &self,
+        push_details: &PushDetails,
+        destination: &PushDestination,
         with_force: bool,
-        refspec: Option<String>,
         askpass_broker: Option<Option<StackId>>,
     ) -> Result<()>;
     fn commit(
@@ -154,19 +160,28 @@ impl RepoActionsExt for CommandContext {
 
     fn push(
         &self,
-        head: git2::Oid,
-        branch: &RemoteRefname,
+        push_details: &PushDetails,
         with_force: bool,
-        refspec: Option<String>,
         askpass_broker: Option<Option<StackId>>,
     ) -> Result<()> {
-        let refspec = refspec.unwrap_or_else(|| {
-            if with_force {
-                format!("+{}:refs/heads/{}", head, branch.branch())
-            } else {
-                format!("{}:refs/heads/{}", head, branch.branch())
-            }
-        });
+        self.push_to(
+            push_details,
+            &PushDestination::Branch,
+            with_force,
+            askpass_broker,
+        )
+    }
+
+    fn push_to(
+        &self,
+        push_details: &PushDetails,
+        destination: &PushDestination,
+        with_force: bool,
+        askpass_broker: Option<Option<StackId>>,
+    ) -> Result<()> {
+        let head = push_details.head;
+        let branch = &push_details.remote_refname;
+        let refspec = push_details.push_refspec_to(destination, with_force)?;
 
         // NOTE(qix-): This is a nasty hack, however the codebase isn't structured
         // NOTE(qix-): in a way that allows us to really incorporate new backends
@@ -6,7 +6,9 @@ use crate::{
 };
 use anyhow::{Context, Result};
 use bstr::ByteSlice;
-use gitbutler_cherry_pick::{ConflictedTreeKey, GixRepositoryExt, RepositoryExt};
+use gitbutler_cherry_pick::{
+    merge_trees_with_policy, ConflictPolicy, ConflictedTreeKey, GixRepositoryExt, RepositoryExt,
+};
 use gitbutler_command_context::{gix_repository_for_merging, CommandContext};
 use gitbutler_commit::{
     commit_ext::CommitExt,
@@ -53,6 +55,25 @@ pub fn cherry_rebase_group(
     target_commit_oid: git2::Oid,
     ids_to_rebase: &[git2::Oid],
     always_rebase: bool,
+) -> Result<git2::Oid> {
+    cherry_rebase_group_with_policy(
+        repository,
+        target_commit_oid,
+        ids_to_rebase,
+        always_rebase,
+        None,
+    )
+}
+
+/// Like [`cherry_rebase_group`], but the files that conflict are resolved as `policy` says,
+/// with the commit rebased onto as *ours* and the commit being rebased as *theirs*.
+#[instrument(level = tracing::Level::DEBUG, skip(repository, ids_to_rebase, policy))]
+pub fn cherry_rebase_group_with_policy(
+    repository: &git2::Repository,
+    target_commit_oid: git2::Oid,
+    ids_to_rebase: &[git2::Oid],
+    always_rebase: bool,
+    policy: Option<&dyn ConflictPolicy>,
 ) -> Result<git2::Oid> {
     // now, rebase unchanged commits onto the new commit
     let commits_to_rebase = ids_to_rebase
@@ -80,7 +101,7 @@ pub fn cherry_rebase_group(
                 };
 
                 let mut cherrypick_result = gix_repo
-                    .cherry_pick_gitbutler(&head, &to_rebase)
+                    .cherry_pick_gitbutler_with_policy(&head, &to_rebase, policy)
                     .context("failed to cherry pick")?;
 
                 let tree_id = cherrypick_result.tree.write()?;
@@ -309,6 +330,34 @@ pub fn gitbutler_merge_commits<'repository>(
     incoming_commit: git2::Commit<'repository>,
     target_branch_name: &str,
     incoming_branch_name: &str,
+) -> Result<git2::Commit<'repository>> {
+    gitbutler_merge_commits_with_message(
+        repository,
+        target_commit,
+        incoming_commit,
+        &merge_commit_message(target_branch_name, incoming_branch_name),
+        None,
+    )
+}
+
+/// The message [`gitbutler_merge_commits`] gives its merge commits.
+pub fn merge_commit_message(target_branch_name: &str, incoming_branch_name: &str) -> String {
+    format!(
+        "Merge `{}` into `{}`",
+        incoming_branch_name, target_branch_name
+    )
+}
+
+/// Like [`gitbutler_merge_commits`], but with the given `message` for the merge commit.
+///
+/// With a `policy`, the files that conflict are resolved as it says, with `incoming_commit`
+/// as *ours* and `target_commit` as *theirs*.
+pub fn gitbutler_merge_commits_with_message<'repository>(
+    repository: &'repository git2::Repository,
+    target_commit: git2::Commit<'repository>,
+    incoming_commit: git2::Commit<'repository>,
+    message: &str,
+    policy: Option<&dyn ConflictPolicy>,
 ) -> Result<git2::Commit<'repository>> {
     let merge_base = repository.merge_base(target_commit.id(), incoming_commit.id())?;
     let merge_base = repository.find_commit(merge_base)?;
@@ -322,12 +371,13 @@ pub fn gitbutler_merge_commits<'repository>(
     let target_merge_tree = repository.find_real_tree(&target_commit, Default::default())?;
     let incoming_merge_tree = repository.find_real_tree(&incoming_commit, Default::default())?;
     let gix_repo = gix_repository_for_merging(repository.path())?;
-    let mut merge_result = gix_repo.merge_trees(
+    let mut merge_result = merge_trees_with_policy(
+        &gix_repo,
         git2_to_gix_object_id(base_tree.id()),
         git2_to_gix_object_id(incoming_merge_tree.id()),
         git2_to_gix_object_id(target_merge_tree.id()),
-        gix_repo.default_merge_labels(),
         gix_repo.merge_options_force_ours()?,
+        policy,
     )?;
     let merged_tree_id = merge_result.tree.write()?;
 
@@ -378,10 +428,7 @@ pub fn gitbutler_merge_commits<'repository>(
         None,
         &author,
         &committer,
-        &format!(
-            "Merge `{}` into `{}`",
-            incoming_branch_name, target_branch_name
-        ),
+        message,
         &repository
             .find_tree(tree_oid)
             .context("failed to find tree")?,
@@ -8,12 +8,17 @@ mod target;
 
 pub use file_ownership::OwnershipClaim;
 pub use ownership::{reconcile_claims, BranchOwnershipClaims, ClaimOutcome};
-pub use stack::{Stack, StackId};
+pub use stack::{Stack, StackId, UpdatableResolutionApproach};
 pub use state::{VirtualBranches as VirtualBranchesState, VirtualBranchesHandle};
 pub use target::Target;
 
 mod heads;
-pub use stack::{commit_by_oid_or_change_id, CommitsForId, PatchReferenceUpdate, TargetUpdate};
+pub use stack::{
+    commit_by_oid_or_change_id, resolve_all, resolve_commit, ChangeIdResolution, CommitsForId,
+    PatchReferenceUpdate, PushDestination, PushDetails, TargetUpdate,
+};
 
 mod stack_branch;
-pub use stack_branch::{CommitOrChangeId, StackBranch};
+pub use stack_branch::{
+    references_affected_by_drop, retarget_references, CommitOrChangeId, StackBranch,
+};
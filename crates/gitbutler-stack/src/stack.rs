@@ -22,6 +22,8 @@ use serde::{Deserialize, Serialize};
 use crate::heads::add_head;
 use crate::heads::get_head;
 use crate::heads::remove_head;
+use crate::stack_branch::retarget_references;
+use crate::stack_branch::review_reference;
 use crate::stack_branch::RepositoryExt as _;
 use crate::stack_context::CommandContextExt;
 use crate::stack_context::StackContext;
@@ -87,6 +89,49 @@ pub struct Stack {
     pub heads: Vec<StackBranch>,
     #[serde(default = "default_false")]
     pub post_commits: bool,
+    /// The approach to pick by default when the stack needs to be updated with upstream changes.
+    /// If unset, the stack is rebased.
+    #[serde(default)]
+    pub preferred_integration_approach: Option<UpdatableResolutionApproach>,
+    /// If set, the stack is left out when integrating upstream changes, e.g. for a
+    /// long-running experiment that should stay on the target it was started on.
+    #[serde(default)]
+    pub integration_excluded: bool,
+    /// The target branch the stack is based on, if it isn't the default target, e.g. a
+    /// release branch.
+    #[serde(default)]
+    pub integration_target: Option<RemoteRefname>,
+    /// The commit of the default target the stack is still based on, if a partial
+    /// integration moved the default target past it without integrating the stack.
+    #[serde(with = "gitbutler_serde::oid_opt", default)]
+    pub integration_base: Option<git2::Oid>,
+}
+
+/// The ways in which a stack can be updated with upstream changes while keeping it applied.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum UpdatableResolutionApproach {
+    #[default]
+    Rebase,
+    /// Merges the new target into the stack, adding the merge commit on top of it.
+    Merge,
+    /// Merges the stack into the new target instead, so the new target is the first
+    /// parent. The merge commit is an integration tip of its own, and the stack stays
+    /// where it is.
+    MergeIntoTarget,
+}
+
+impl UpdatableResolutionApproach {
+    /// Returns `true` if the existing commits of the stack get new ids.
+    /// A merge keeps them and adds a merge commit on top.
+    pub fn rewrites_history(&self) -> bool {
+        match self {
+            UpdatableResolutionApproach::Rebase => true,
+            UpdatableResolutionApproach::Merge | UpdatableResolutionApproach::MergeIntoTarget => {
+                false
+            }
+        }
+    }
 }
 
 fn default_true() -> bool {
@@ -165,6 +210,10 @@ impl Stack {
             not_in_workspace_wip_change_id: None,
             heads: Default::default(),
             post_commits: false,
+            preferred_integration_approach: None,
+            integration_excluded: false,
+            integration_target: None,
+            integration_base: None,
         }
     }
 
@@ -375,6 +424,10 @@ impl Stack {
         let state = branch_state(ctx);
         let patches = self.stack_patches(&ctx.to_stack_context()?, true)?;
         validate_name(&new_head, &state)?;
+        let new_head = StackBranch {
+            head: new_head.head.expanded(ctx.repo())?,
+            ..new_head
+        };
         validate_target(&new_head, ctx.repo(), self.head(), &state)?;
         let updated_heads = add_head(self.heads.clone(), new_head, preceding_head, patches)?;
         self.heads = updated_heads;
@@ -680,6 +733,30 @@ impl Stack {
         Ok(())
     }
 
+    /// Moves the heads that point to a rewritten commit to the commit it was rewritten to, e.g. after a rebase.
+    /// `rewritten` maps the original commit ids to their new commit ids, see [`retarget_references`].
+    ///
+    /// Heads that point to a change id already follow their change and are left untouched.
+    ///
+    /// This operation mutates the gitbutler::Branch.heads list and updates the state in `virtual_branches.toml`
+    pub fn remap_heads(
+        &mut self,
+        ctx: &CommandContext,
+        rewritten: &HashMap<git2::Oid, git2::Oid>,
+    ) -> Result<()> {
+        self.ensure_initialized()?;
+        if !retarget_references(&mut self.heads, rewritten, ctx.repo())? {
+            return Ok(());
+        }
+        let state = branch_state(ctx);
+        let stack_head = self.head();
+        for head in self.heads.iter().filter(|h| !h.archived) {
+            validate_target(head, ctx.repo(), stack_head, &state)?;
+        }
+        self.updated_timestamp_ms = gitbutler_time::time::now_ms();
+        state.set_stack(self.clone())
+    }
+
     /// Sets the forge identifier for a given series/branch.
     /// Existing value is overwritten - passing `None` sets the forge identifier to `None`.
     ///
@@ -762,6 +839,49 @@ pub struct PushDetails {
     pub remote_refname: RemoteRefname,
 }
 
+/// Where a push puts the branch on the remote.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub enum PushDestination {
+    /// The remote branch of the same name.
+    #[default]
+    Branch,
+    /// The review reference for the `target` branch, e.g. `refs/for/main` with Gerrit,
+    /// see [`StackBranch::remote_reference_for_review`].
+    Review { target: String },
+}
+
+impl PushDetails {
+    /// Returns the refspec that pushes `head` to the remote branch, e.g. `+<sha>:refs/heads/foo` when forced.
+    /// The source is a commit, as stack branches don't need a local git reference.
+    pub fn push_refspec(&self, force: bool) -> String {
+        refspec(
+            self.head,
+            &format!("refs/heads/{}", self.remote_refname.branch()),
+            force,
+        )
+    }
+
+    /// Like [`Self::push_refspec`], but pushes to `destination` instead, e.g. `<sha>:refs/for/main`
+    /// for review. Fails if the review target isn't a valid branch name.
+    pub fn push_refspec_to(&self, destination: &PushDestination, force: bool) -> Result<String> {
+        Ok(match destination {
+            PushDestination::Branch => self.push_refspec(force),
+            PushDestination::Review { target } => {
+                refspec(self.head, &review_reference(target)?, force)
+            }
+        })
+    }
+}
+
+fn refspec(head: git2::Oid, destination_ref: &str, force: bool) -> String {
+    format!(
+        "{}{}:{}",
+        if force { "+" } else { "" },
+        head,
+        destination_ref
+    )
+}
+
 impl TryFrom<&Stack> for VirtualRefname {
     type Error = anyhow::Error;
 
@@ -784,6 +904,7 @@ fn validate_target(
     stack_head: git2::Oid,
     state: &VirtualBranchesHandle,
 ) -> Result<()> {
+    reference.head.validate()?;
     let default_target = state.get_default_target()?;
     let merge_base = repo.merge_base(stack_head, default_target.sha)?;
     let commit = commit_by_oid_or_change_id(&reference.head, repo, stack_head, merge_base)?.head;
@@ -835,6 +956,23 @@ fn validate_name(reference: &StackBranch, state: &VirtualBranchesHandle) -> Resu
     Ok(())
 }
 
+/// Returns the commits of the stack, newest first, including the merge base.
+fn commits_with_merge_base(
+    repo: &git2::Repository,
+    stack_head: git2::Oid,
+    merge_base: git2::Oid,
+) -> Result<Vec<Commit<'_>>> {
+    if stack_head == merge_base {
+        Ok(vec![repo.find_commit(stack_head)?])
+    } else {
+        // Include the merge base, in case the change ID being searched for is the merge base itself.
+        // TODO: Use the Stack `commits_with_merge_base` method instead.
+        let mut commits = repo.log(stack_head, LogUntil::Commit(merge_base), false)?;
+        commits.push(repo.find_commit(merge_base)?);
+        Ok(commits)
+    }
+}
+
 /// Given a branch id and a change id, returns the commit associated with the change id.
 // TODO: We need a more efficient way of getting a commit by change id.
 // NB: There can be multiple commits with the same change id on the same branch id.
@@ -846,16 +984,7 @@ fn commit_by_branch_id_and_change_id<'a>(
     merge_base: git2::Oid,
     change_id: &str,
 ) -> Result<CommitsForId<'a>> {
-    let commits = if stack_head == merge_base {
-        vec![repo.find_commit(stack_head)?]
-    } else {
-        // Include the merge base, in case the change ID being searched for is the merge base itself.
-        // TODO: Use the Stack `commits_with_merge_base` method instead.
-        let mut commits = repo.log(stack_head, LogUntil::Commit(merge_base), false)?;
-        commits.push(repo.find_commit(merge_base)?);
-        commits
-    };
-    let commits = commits
+    let commits = commits_with_merge_base(repo, stack_head, merge_base)?
         .into_iter()
         .filter(|c| c.change_id().as_deref() == Some(change_id))
         .collect_vec();
@@ -906,6 +1035,97 @@ pub struct CommitsForId<'a> {
     pub tail: Vec<Commit<'a>>,
 }
 
+/// Which commit to pick when a change id matches multiple commits, e.g. because the
+/// change was split into two commits that both kept the change id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChangeIdResolution {
+    Newest,
+    Oldest,
+    /// Fail, so the caller has to handle the ambiguity explicitly.
+    #[default]
+    Error,
+}
+
+impl<'a> CommitsForId<'a> {
+    /// Returns the single commit for the id, picking one according to `policy` if there are multiple.
+    pub fn resolve(self, policy: ChangeIdResolution) -> Result<Commit<'a>> {
+        if self.tail.is_empty() {
+            return Ok(self.head);
+        }
+        match policy {
+            ChangeIdResolution::Newest => Ok(self.head),
+            ChangeIdResolution::Oldest => {
+                Ok(self.tail.into_iter().last().expect("tail is not empty"))
+            }
+            ChangeIdResolution::Error => bail!(
+                "Change id {} matches {} commits",
+                self.head.change_id().unwrap_or_default(),
+                self.tail.len() + 1
+            ),
+        }
+    }
+}
+
+/// Returns the commit a reference target points to, using `policy` to pick one if
+/// a change id matches multiple commits.
+pub fn resolve_commit<'a>(
+    reference_target: &'a CommitOrChangeId,
+    repo: &'a git2::Repository,
+    stack_head: git2::Oid,
+    merge_base: git2::Oid,
+    policy: ChangeIdResolution,
+) -> Result<Commit<'a>> {
+    commit_by_oid_or_change_id(reference_target, repo, stack_head, merge_base)?.resolve(policy)
+}
+
+/// Resolves the heads of all `references` like [`resolve_commit`], but walks the stack
+/// only once. The oids are in the same order as `references`, and the first reference
+/// that can't be resolved fails the call.
+pub fn resolve_all(
+    references: &[StackBranch],
+    repo: &git2::Repository,
+    stack_head: git2::Oid,
+    merge_base: git2::Oid,
+    policy: ChangeIdResolution,
+) -> Result<Vec<git2::Oid>> {
+    let mut commits_by_change_id: HashMap<String, Vec<Commit<'_>>> = HashMap::new();
+    if references
+        .iter()
+        .any(|reference| matches!(reference.head, CommitOrChangeId::ChangeId(_)))
+    {
+        for commit in commits_with_merge_base(repo, stack_head, merge_base)? {
+            if let Some(change_id) = commit.change_id() {
+                commits_by_change_id
+                    .entry(change_id)
+                    .or_default()
+                    .push(commit);
+            }
+        }
+    }
+
+    references
+        .iter()
+        .map(|reference| match &reference.head {
+            CommitOrChangeId::CommitId(commit_id) => Ok(repo.find_commit(commit_id.parse()?)?.id()),
+            CommitOrChangeId::ChangeId(change_id) => {
+                let mut commits = commits_by_change_id
+                    .get(change_id)
+                    .cloned()
+                    .unwrap_or_default()
+                    .into_iter();
+                let head = commits
+                    .next()
+                    .ok_or_else(|| anyhow!("No commit with change id {} found", change_id))?;
+                let commits_for_id = CommitsForId {
+                    head,
+                    tail: commits.collect(),
+                };
+                Ok(commits_for_id.resolve(policy)?.id())
+            }
+        })
+        .collect()
+}
+
 fn patch_reference_exists(state: &VirtualBranchesHandle, name: &str) -> Result<bool> {
     Ok(state
         .list_stacks_in_workspace()?
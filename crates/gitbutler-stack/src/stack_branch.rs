@@ -1,12 +1,18 @@
-use anyhow::Result;
+use anyhow::{bail, Context as _, Result};
 use git2::{Commit, Oid};
+use gitbutler_command_context::CommandContext;
 use gitbutler_commit::commit_ext::{CommitExt, CommitVecExt};
 use gitbutler_repo::logging::{LogUntil, RepositoryExt as _};
+use gix::validate::reference::name_partial;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::Display;
 
-use crate::{commit_by_oid_or_change_id, stack_context::StackContext, Stack};
+use crate::stack_context::CommandContextExt as _;
+use crate::{
+    commit_by_oid_or_change_id, stack_context::StackContext, Stack, VirtualBranchesHandle,
+};
 
 /// A GitButler-specific reference type that points to a commit or a patch (change).
 /// The principal difference between a `PatchReference` and a regular git reference is that a `PatchReference` can point to a change (patch) that is mutable.
@@ -50,6 +56,64 @@ impl Display for CommitOrChangeId {
     }
 }
 
+impl CommitOrChangeId {
+    /// Checks that a `CommitId` is a hexadecimal commit id of 4 to 64 characters, i.e. one
+    /// that may be abbreviated, and that a `ChangeId` isn't empty, so malformed targets are
+    /// rejected before they are looked up.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            CommitOrChangeId::CommitId(id) => {
+                if !(4..=64).contains(&id.len()) || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+                    bail!("'{id}' is not a valid commit id");
+                }
+            }
+            CommitOrChangeId::ChangeId(id) => {
+                if id.trim().is_empty() {
+                    bail!("The change id must not be empty");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the target with an abbreviated commit id expanded to the full id of its
+    /// commit, as that is what gets stored. Change ids are returned as they are.
+    pub fn expanded(&self, repo: &git2::Repository) -> Result<Self> {
+        self.validate()?;
+        Ok(match self {
+            CommitOrChangeId::CommitId(commit_id) => CommitOrChangeId::CommitId(
+                repo.revparse_single(commit_id)?
+                    .peel_to_commit()?
+                    .id()
+                    .to_string(),
+            ),
+            CommitOrChangeId::ChangeId(_) => self.clone(),
+        })
+    }
+
+    /// Returns `true` if both targets point to the same patch. Unlike `==`, this resolves
+    /// abbreviated commit ids first, so a short and a full id of the same commit are equal.
+    /// A commit id and a change id are never equal.
+    pub fn resolves_equal(&self, other: &Self, ctx: &CommandContext) -> Result<bool> {
+        let resolve = |commit_id: &str| -> Result<Oid> {
+            Ok(ctx
+                .repo()
+                .revparse_single(commit_id)?
+                .peel_to_commit()?
+                .id())
+        };
+        Ok(match (self, other) {
+            (CommitOrChangeId::CommitId(ours), CommitOrChangeId::CommitId(theirs)) => {
+                ours == theirs || resolve(ours)? == resolve(theirs)?
+            }
+            (CommitOrChangeId::ChangeId(ours), CommitOrChangeId::ChangeId(theirs)) => {
+                ours == theirs
+            }
+            _ => false,
+        })
+    }
+}
+
 impl From<git2::Commit<'_>> for CommitOrChangeId {
     fn from(commit: git2::Commit) -> Self {
         if let Some(change_id) = commit.change_id() {
@@ -60,6 +124,63 @@ impl From<git2::Commit<'_>> for CommitOrChangeId {
     }
 }
 
+/// Points the references with a `CommitId` head at the commits their commits were rewritten
+/// to, e.g. by a rebase. `ChangeId` heads are left as they are, as they follow their change.
+/// Abbreviated commit ids are [expanded](CommitOrChangeId::expanded) in `repo` first.
+///
+/// Returns `true` if any reference was moved.
+pub fn retarget_references(
+    references: &mut [StackBranch],
+    mapping: &HashMap<Oid, Oid>,
+    repo: &git2::Repository,
+) -> Result<bool> {
+    let mut retargeted = false;
+    for reference in references {
+        let CommitOrChangeId::CommitId(commit_id) = reference.head.expanded(repo)? else {
+            continue;
+        };
+        if let Some(new_commit_id) = mapping.get(&commit_id.parse::<Oid>()?) {
+            reference.head = CommitOrChangeId::CommitId(new_commit_id.to_string());
+            retargeted = true;
+        }
+    }
+    Ok(retargeted)
+}
+
+/// Returns the references that would dangle if the commit `dropped` was removed from
+/// history: those pointing at it by its id, and those pointing at its change id.
+pub fn references_affected_by_drop<'a>(
+    references: &'a [StackBranch],
+    dropped: Oid,
+    ctx: &CommandContext,
+) -> Result<Vec<&'a StackBranch>> {
+    let dropped_change_id = ctx.repo().find_commit(dropped)?.change_id();
+    let dropped_commit_id = CommitOrChangeId::CommitId(dropped.to_string());
+
+    let mut affected = vec![];
+    for reference in references {
+        let points_at_dropped = match &reference.head {
+            CommitOrChangeId::CommitId(_) => {
+                reference.head.resolves_equal(&dropped_commit_id, ctx)?
+            }
+            CommitOrChangeId::ChangeId(change_id) => dropped_change_id.as_ref() == Some(change_id),
+        };
+        if points_at_dropped {
+            affected.push(reference);
+        }
+    }
+    Ok(affected)
+}
+
+/// Returns the reference to push to for review against `target_branch`, e.g. `refs/for/main`.
+pub(crate) fn review_reference(target_branch: &str) -> Result<String> {
+    if target_branch.starts_with("refs/") {
+        bail!("The review target must be a branch name, got {target_branch}");
+    }
+    name_partial(target_branch.into()).context("Invalid review target")?;
+    Ok(format!("refs/for/{}", target_branch))
+}
+
 pub trait RepositoryExt {
     fn lookup_change_id_or_oid(&self, oid: git2::Oid) -> Result<CommitOrChangeId>;
 }
@@ -82,11 +203,30 @@ impl StackBranch {
                 .id();
         Ok(head_commit)
     }
+
+    /// Returns a copy of this branch with its head resolved to a commit id, so it can't drift
+    /// while an operation is in progress. Don't persist it, as stored heads prefer change ids.
+    pub fn pin(&self, stack_context: &StackContext, stack: &Stack) -> Result<StackBranch> {
+        let head_oid = self.head_oid(stack_context, stack)?;
+        Ok(StackBranch {
+            head: CommitOrChangeId::CommitId(head_oid.to_string()),
+            ..self.clone()
+        })
+    }
+
     /// Returns a fully qualified reference with the supplied remote e.g. `refs/remotes/origin/base-branch-improvements`
     pub fn remote_reference(&self, remote: &str) -> String {
         format!("refs/remotes/{}/{}", remote, self.name)
     }
 
+    /// Returns the reference to push to in order to put the branch up for review against
+    /// `target_branch`, e.g. `refs/for/main` with Gerrit, rather than pushing the branch itself.
+    /// `target_branch` must be a valid branch name without the `refs/heads/` prefix.
+    pub fn remote_reference_for_review(&self, target_branch: &str) -> Result<String> {
+        review_reference(target_branch)
+            .with_context(|| format!("Cannot push {} for review", self.name))
+    }
+
     /// Returns `true` if the reference is pushed to the provided remote
     pub fn pushed(&self, remote: &str, repository: &git2::Repository) -> bool {
         repository
@@ -94,6 +234,70 @@ impl StackBranch {
             .is_ok()
     }
 
+    /// Returns the remote this reference should be pushed to: the one configured as
+    /// `branch.<name>.remote`, or else the push remote of the default target.
+    /// Returns `None` if neither is set.
+    pub fn push_remote(&self, ctx: &CommandContext) -> Result<Option<String>> {
+        match ctx
+            .repo()
+            .config()?
+            .get_string(&format!("branch.{}.remote", self.name))
+        {
+            Ok(remote) => return Ok(Some(remote)),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        Ok(VirtualBranchesHandle::new(ctx.project().gb_dir())
+            .try_default_target()?
+            .map(|target| target.push_remote_name()))
+    }
+
+    /// Returns `false` if `refs/heads/{name}` already exists in the repository, in which case
+    /// writing this reference out as a local branch would clobber it.
+    pub fn name_available(&self, ctx: &CommandContext) -> Result<bool> {
+        match ctx
+            .repo()
+            .find_reference(&format!("refs/heads/{}", self.name))
+        {
+            Ok(_) => Ok(false),
+            Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(true),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns `false` if the reference dangles, i.e. its commit doesn't exist anymore, e.g.
+    /// after it was garbage collected, or no stack in the workspace has a commit with its change id.
+    /// Abbreviated commit ids are resolved like [`CommitOrChangeId::expanded()`], and ids of
+    /// objects that aren't commits dangle as well.
+    pub fn target_exists(&self, ctx: &CommandContext) -> Result<bool> {
+        let repository = ctx.repo();
+        match &self.head {
+            CommitOrChangeId::CommitId(commit_id) => {
+                self.head.validate()?;
+                match repository.revparse_single(commit_id) {
+                    Ok(object) => Ok(object.kind() == Some(git2::ObjectType::Commit)),
+                    Err(err) if err.code() == git2::ErrorCode::NotFound => Ok(false),
+                    Err(err) => Err(err.into()),
+                }
+            }
+            CommitOrChangeId::ChangeId(change_id) => {
+                let stack_context = ctx.to_stack_context()?;
+                for stack in
+                    VirtualBranchesHandle::new(ctx.project().gb_dir()).list_stacks_in_workspace()?
+                {
+                    for commit_id in stack.commits_with_merge_base(&stack_context)? {
+                        if repository.find_commit(commit_id)?.change_id().as_ref()
+                            == Some(change_id)
+                        {
+                            return Ok(true);
+                        }
+                    }
+                }
+                Ok(false)
+            }
+        }
+    }
+
     /// Returns the commits that are part of the branch.
     pub fn commits<'a>(
         &self,
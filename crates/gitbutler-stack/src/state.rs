@@ -95,6 +95,14 @@ impl VirtualBranchesHandle {
             .ok_or(anyhow!("there is no default target").context(Code::DefaultTargetNotFound))
     }
 
+    /// Gets the default target for the given repository, or `None` if there is none yet.
+    ///
+    /// Errors if the file cannot be read.
+    pub fn try_default_target(&self) -> Result<Option<Target>> {
+        let virtual_branches = self.read_file()?;
+        Ok(virtual_branches.default_target)
+    }
+
     /// Sets the state of the given virtual branch.
     ///
     /// Errors if the file cannot be read or written.
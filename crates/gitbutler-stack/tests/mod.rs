@@ -7,8 +7,14 @@ use gitbutler_commit::commit_ext::CommitExt;
 use gitbutler_repo::logging::{LogUntil, RepositoryExt as _};
 use gitbutler_repo_actions::RepoActionsExt;
 use gitbutler_stack::stack_context::CommandContextExt;
-use gitbutler_stack::{CommitOrChangeId, StackBranch, VirtualBranchesHandle};
-use gitbutler_stack::{PatchReferenceUpdate, TargetUpdate};
+use gitbutler_stack::{
+    references_affected_by_drop, resolve_all, resolve_commit, retarget_references,
+    ChangeIdResolution, PatchReferenceUpdate, PushDestination, PushDetails, TargetUpdate,
+};
+use gitbutler_stack::{
+    CommitOrChangeId, StackBranch, UpdatableResolutionApproach, VirtualBranchesHandle,
+};
+use gitbutler_testsupport::testing_repository::TestingRepository;
 use itertools::Itertools;
 use tempfile::TempDir;
 
@@ -220,6 +226,99 @@ fn add_series_matching_git_ref_is_ok() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn name_available_checks_local_refs() -> Result<()> {
+    let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
+    let test_ctx = test_ctx(&ctx)?;
+    let existing = StackBranch {
+        name: "existing-branch".into(),
+        head: test_ctx.commits[0].clone().into(),
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+    assert!(!existing.name_available(&ctx)?);
+
+    let new = StackBranch {
+        name: "not-a-branch-yet".into(),
+        ..existing
+    };
+    assert!(new.name_available(&ctx)?);
+    Ok(())
+}
+
+#[test]
+fn target_exists_detects_dangling_commit_references() -> Result<()> {
+    let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
+    let test_ctx = test_ctx(&ctx)?;
+    let valid = StackBranch {
+        name: "a-branch".into(),
+        head: CommitOrChangeId::CommitId(test_ctx.commits[0].id().to_string()),
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+    assert!(valid.target_exists(&ctx)?);
+
+    let abbreviated = StackBranch {
+        head: CommitOrChangeId::CommitId(test_ctx.commits[0].id().to_string()[..7].into()),
+        ..valid.clone()
+    };
+    assert!(abbreviated.target_exists(&ctx)?);
+
+    let tree = StackBranch {
+        head: CommitOrChangeId::CommitId(test_ctx.commits[0].tree_id().to_string()),
+        ..valid.clone()
+    };
+    assert!(!tree.target_exists(&ctx)?);
+
+    let dangling = StackBranch {
+        head: CommitOrChangeId::CommitId("0123456789abcdef0123456789abcdef01234567".into()),
+        ..valid
+    };
+    assert!(!dangling.target_exists(&ctx)?);
+    Ok(())
+}
+
+#[test]
+fn push_remote_prefers_the_branch_remote() -> Result<()> {
+    let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
+    let test_ctx = test_ctx(&ctx)?;
+    let reference = StackBranch {
+        name: "a-branch".into(),
+        head: test_ctx.commits[0].clone().into(),
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+    assert_eq!(reference.push_remote(&ctx)?, Some("origin".into()));
+
+    ctx.repo()
+        .config()?
+        .set_str("branch.a-branch.remote", "fork")?;
+    assert_eq!(reference.push_remote(&ctx)?, Some("fork".into()));
+    Ok(())
+}
+
+#[test]
+fn push_remote_fails_if_the_target_cannot_be_read() -> Result<()> {
+    let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
+    let test_ctx = test_ctx(&ctx)?;
+    let reference = StackBranch {
+        name: "a-branch".into(),
+        head: test_ctx.commits[0].clone().into(),
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+    std::fs::write(
+        ctx.project().gb_dir().join("virtual_branches.toml"),
+        "not toml",
+    )?;
+    assert!(reference.push_remote(&ctx).is_err());
+    Ok(())
+}
+
 #[test]
 fn add_series_including_refs_head_fails() -> Result<()> {
     let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
@@ -259,6 +358,77 @@ fn add_series_target_commit_doesnt_exist() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn add_series_malformed_target_fails() -> Result<()> {
+    let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
+    let mut test_ctx = test_ctx(&ctx)?;
+    let reference = StackBranch {
+        name: "my-branch".into(),
+        head: CommitOrChangeId::CommitId("not-a-sha".into()),
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+    let result = test_ctx.stack.add_series(&ctx, reference, None);
+    assert_eq!(
+        result.err().unwrap().to_string(),
+        "'not-a-sha' is not a valid commit id"
+    );
+    Ok(())
+}
+
+#[test]
+fn add_series_abbreviated_target_is_expanded() -> Result<()> {
+    let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
+    let mut test_ctx = test_ctx(&ctx)?;
+    let merge_base = ctx.repo().find_commit(
+        ctx.repo()
+            .merge_base(test_ctx.stack.head(), test_ctx.default_target.sha)?,
+    )?;
+    let reference = StackBranch {
+        name: "asdf".into(),
+        head: CommitOrChangeId::CommitId(merge_base.id().to_string()[..7].to_string()),
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+    test_ctx.stack.add_series(&ctx, reference, None)?;
+    let added = test_ctx
+        .stack
+        .heads
+        .iter()
+        .find(|head| head.name == "asdf")
+        .expect("the series was added");
+    assert_eq!(
+        added.head,
+        CommitOrChangeId::CommitId(merge_base.id().to_string())
+    );
+    Ok(())
+}
+
+#[test]
+fn validate_targets() {
+    assert!(
+        CommitOrChangeId::CommitId("30696678319e0fa3a20e54f22d47fc8cf1ceaade".into())
+            .validate()
+            .is_ok()
+    );
+    assert!(CommitOrChangeId::CommitId("not-a-sha".into())
+        .validate()
+        .is_err());
+    assert!(CommitOrChangeId::CommitId("3069667".into())
+        .validate()
+        .is_ok());
+    assert!(CommitOrChangeId::CommitId("306".into()).validate().is_err());
+    assert!(CommitOrChangeId::CommitId("3".repeat(65))
+        .validate()
+        .is_err());
+    assert!(CommitOrChangeId::ChangeId("some-change-id".into())
+        .validate()
+        .is_ok());
+    assert!(CommitOrChangeId::ChangeId("".into()).validate().is_err());
+}
+
 #[test]
 fn add_series_target_change_id_doesnt_exist() -> Result<()> {
     let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
@@ -610,13 +780,7 @@ fn update_name_after_push() -> Result<()> {
     state.set_default_target(target)?;
 
     let push_details = test_ctx.stack.push_details(&ctx, "a-branch-2".into())?;
-    let result = ctx.push(
-        push_details.head,
-        &push_details.remote_refname,
-        false,
-        None,
-        Some(Some(test_ctx.stack.id)),
-    );
+    let result = ctx.push(&push_details, false, Some(Some(test_ctx.stack.id)));
     assert!(result.is_ok());
     let result = test_ctx.stack.update_series(
         &ctx,
@@ -1146,6 +1310,348 @@ fn set_pr_numberentifiers_series_not_found_fails() -> Result<()> {
     );
     Ok(())
 }
+#[test]
+fn pin_change_id_reference() -> Result<()> {
+    let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
+    let test_ctx = test_ctx(&ctx)?;
+    let reference = StackBranch {
+        name: "asdf".into(),
+        head: CommitOrChangeId::ChangeId(test_ctx.commits[1].change_id().unwrap()),
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+    let pinned = reference.pin(&ctx.to_stack_context()?, &test_ctx.stack)?;
+    assert_eq!(pinned.name, reference.name);
+    assert_eq!(
+        pinned.head,
+        CommitOrChangeId::CommitId(test_ctx.commits[1].id().to_string())
+    );
+    Ok(())
+}
+
+#[test]
+fn pin_commit_id_reference_is_unchanged() -> Result<()> {
+    let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
+    let test_ctx = test_ctx(&ctx)?;
+    let merge_base = ctx
+        .repo()
+        .merge_base(test_ctx.stack.head(), test_ctx.default_target.sha)?;
+    let reference = StackBranch {
+        name: "asdf".into(),
+        head: CommitOrChangeId::CommitId(merge_base.to_string()),
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+    let pinned = reference.pin(&ctx.to_stack_context()?, &test_ctx.stack)?;
+    assert_eq!(pinned, reference);
+    Ok(())
+}
+
+#[test]
+fn resolves_equal_abbreviated_commit_id() -> Result<()> {
+    let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
+    let test_ctx = test_ctx(&ctx)?;
+    let full_id = test_ctx.commits[0].id().to_string();
+    let full = CommitOrChangeId::CommitId(full_id.clone());
+    let abbreviated = CommitOrChangeId::CommitId(full_id[..7].to_string());
+    assert_ne!(full, abbreviated);
+    assert!(full.resolves_equal(&abbreviated, &ctx)?);
+
+    let other = CommitOrChangeId::CommitId(test_ctx.commits[1].id().to_string());
+    assert!(!other.resolves_equal(&abbreviated, &ctx)?);
+    Ok(())
+}
+
+#[test]
+fn retarget_references_follows_the_mapping() -> Result<()> {
+    let test_repository = TestingRepository::open();
+    let old_commit = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+    let abbreviated_commit = test_repository.commit_tree(Some(&old_commit), &[("foo.txt", "bar")]);
+    let untouched_commit = test_repository.commit_tree(Some(&old_commit), &[("foo.txt", "baz")]);
+    let new_commit = git2::Oid::from_str("2222222222222222222222222222222222222222")?;
+    let new_abbreviated_commit = git2::Oid::from_str("4444444444444444444444444444444444444444")?;
+    let reference = |name: &str, head: CommitOrChangeId| StackBranch {
+        name: name.into(),
+        head,
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+    let mut references = vec![
+        reference(
+            "rebased",
+            CommitOrChangeId::CommitId(old_commit.id().to_string()),
+        ),
+        reference(
+            "abbreviated",
+            CommitOrChangeId::CommitId(abbreviated_commit.id().to_string()[..7].into()),
+        ),
+        reference(
+            "untouched",
+            CommitOrChangeId::CommitId(untouched_commit.id().to_string()),
+        ),
+        reference("change", CommitOrChangeId::ChangeId("change".into())),
+    ];
+
+    assert!(retarget_references(
+        &mut references,
+        &[
+            (old_commit.id(), new_commit),
+            (abbreviated_commit.id(), new_abbreviated_commit)
+        ]
+        .into(),
+        &test_repository.repository,
+    )?);
+
+    assert_eq!(
+        references.iter().map(|r| r.head.clone()).collect_vec(),
+        vec![
+            CommitOrChangeId::CommitId(new_commit.to_string()),
+            CommitOrChangeId::CommitId(new_abbreviated_commit.to_string()),
+            CommitOrChangeId::CommitId(untouched_commit.id().to_string()),
+            CommitOrChangeId::ChangeId("change".into()),
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn references_affected_by_drop_covers_both_kinds_of_heads() -> Result<()> {
+    let (ctx, _temp_dir) = command_ctx("multiple-commits")?;
+    let test_ctx = test_ctx(&ctx)?;
+    let dropped = &test_ctx.commits[1];
+    let reference = |name: &str, head: CommitOrChangeId| StackBranch {
+        name: name.into(),
+        head,
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+    let references = vec![
+        reference(
+            "by-commit",
+            CommitOrChangeId::CommitId(dropped.id().to_string()),
+        ),
+        reference(
+            "by-change",
+            CommitOrChangeId::ChangeId(dropped.change_id().unwrap()),
+        ),
+        reference(
+            "by-abbreviated-commit",
+            CommitOrChangeId::CommitId(dropped.id().to_string()[..7].into()),
+        ),
+        reference(
+            "other-commit",
+            CommitOrChangeId::CommitId(test_ctx.commits[0].id().to_string()),
+        ),
+        reference(
+            "other-change",
+            CommitOrChangeId::ChangeId(test_ctx.commits[2].change_id().unwrap()),
+        ),
+    ];
+
+    let affected = references_affected_by_drop(&references, dropped.id(), &ctx)?;
+
+    assert_eq!(
+        affected.iter().map(|r| r.name.as_str()).collect_vec(),
+        vec!["by-commit", "by-change", "by-abbreviated-commit"]
+    );
+    Ok(())
+}
+
+#[test]
+fn resolve_commit_split_change() -> Result<()> {
+    let test_repository = TestingRepository::open();
+    let base = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+    let first_half =
+        test_repository.commit_tree_with_change_id(Some(&base), "split", &[("foo.txt", "foo1")]);
+    let second_half = test_repository.commit_tree_with_change_id(
+        Some(&first_half),
+        "split",
+        &[("foo.txt", "foo2")],
+    );
+    let target = CommitOrChangeId::ChangeId("split".to_string());
+    let repository = &test_repository.repository;
+
+    let newest = resolve_commit(
+        &target,
+        repository,
+        second_half.id(),
+        base.id(),
+        ChangeIdResolution::Newest,
+    )?;
+    assert_eq!(newest.id(), second_half.id());
+
+    let oldest = resolve_commit(
+        &target,
+        repository,
+        second_half.id(),
+        base.id(),
+        ChangeIdResolution::Oldest,
+    )?;
+    assert_eq!(oldest.id(), first_half.id());
+
+    let result = resolve_commit(
+        &target,
+        repository,
+        second_half.id(),
+        base.id(),
+        ChangeIdResolution::default(),
+    );
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "Change id split matches 2 commits"
+    );
+    Ok(())
+}
+
+#[test]
+fn resolve_commit_single_match_ignores_policy() -> Result<()> {
+    let test_repository = TestingRepository::open();
+    let base = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+    let commit =
+        test_repository.commit_tree_with_change_id(Some(&base), "single", &[("foo.txt", "foo1")]);
+
+    let resolved = resolve_commit(
+        &CommitOrChangeId::ChangeId("single".to_string()),
+        &test_repository.repository,
+        commit.id(),
+        base.id(),
+        ChangeIdResolution::Error,
+    )?;
+    assert_eq!(resolved.id(), commit.id());
+    Ok(())
+}
+
+#[test]
+fn resolve_all_mixed_references() -> Result<()> {
+    let test_repository = TestingRepository::open();
+    let base = test_repository.commit_tree(None, &[("foo.txt", "foo")]);
+    let first =
+        test_repository.commit_tree_with_change_id(Some(&base), "first", &[("foo.txt", "foo1")]);
+    let second = test_repository.commit_tree(Some(&first), &[("foo.txt", "foo2")]);
+    let third =
+        test_repository.commit_tree_with_change_id(Some(&second), "third", &[("foo.txt", "foo3")]);
+    let reference = |head| StackBranch {
+        name: "asdf".into(),
+        head,
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+
+    let resolved = resolve_all(
+        &[
+            reference(CommitOrChangeId::ChangeId("third".to_string())),
+            reference(CommitOrChangeId::CommitId(second.id().to_string())),
+            reference(CommitOrChangeId::ChangeId("first".to_string())),
+        ],
+        &test_repository.repository,
+        third.id(),
+        base.id(),
+        ChangeIdResolution::default(),
+    )?;
+    assert_eq!(resolved, vec![third.id(), second.id(), first.id()]);
+
+    let result = resolve_all(
+        &[
+            reference(CommitOrChangeId::ChangeId("first".to_string())),
+            reference(CommitOrChangeId::ChangeId("missing".to_string())),
+        ],
+        &test_repository.repository,
+        third.id(),
+        base.id(),
+        ChangeIdResolution::default(),
+    );
+    assert_eq!(
+        result.unwrap_err().to_string(),
+        "No commit with change id missing found"
+    );
+    Ok(())
+}
+
+#[test]
+fn push_refspec_for_review() -> Result<()> {
+    let push_details = PushDetails {
+        head: git2::Oid::from_str("0123456789abcdef0123456789abcdef01234567")?,
+        remote_refname: "refs/remotes/origin/a-branch".parse()?,
+    };
+
+    assert_eq!(
+        push_details.push_refspec_to(&PushDestination::Branch, true)?,
+        push_details.push_refspec(true)
+    );
+    assert_eq!(
+        push_details.push_refspec_to(
+            &PushDestination::Review {
+                target: "main".into()
+            },
+            false
+        )?,
+        "0123456789abcdef0123456789abcdef01234567:refs/for/main"
+    );
+    assert!(push_details
+        .push_refspec_to(
+            &PushDestination::Review {
+                target: "refs/heads/main".into()
+            },
+            false
+        )
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn remote_reference_for_review() -> Result<()> {
+    let reference = StackBranch {
+        name: "a-branch".into(),
+        head: CommitOrChangeId::ChangeId("change".into()),
+        description: None,
+        pr_number: Default::default(),
+        archived: Default::default(),
+    };
+    assert_eq!(
+        reference.remote_reference_for_review("main")?,
+        "refs/for/main"
+    );
+    assert_eq!(
+        reference.remote_reference_for_review("release/1.0")?,
+        "refs/for/release/1.0"
+    );
+    assert!(reference
+        .remote_reference_for_review("refs/heads/main")
+        .is_err());
+    assert!(reference.remote_reference_for_review("").is_err());
+    Ok(())
+}
+
+#[test]
+fn push_refspec() -> Result<()> {
+    let push_details = PushDetails {
+        head: git2::Oid::from_str("0123456789abcdef0123456789abcdef01234567")?,
+        remote_refname: "refs/remotes/origin/a-branch".parse()?,
+    };
+
+    assert_eq!(
+        push_details.push_refspec(false),
+        "0123456789abcdef0123456789abcdef01234567:refs/heads/a-branch"
+    );
+    assert_eq!(
+        push_details.push_refspec(true),
+        "+0123456789abcdef0123456789abcdef01234567:refs/heads/a-branch"
+    );
+    Ok(())
+}
+
+#[test]
+fn updatable_resolution_approach_rewrites_history() {
+    assert!(UpdatableResolutionApproach::Rebase.rewrites_history());
+    assert!(!UpdatableResolutionApproach::Merge.rewrites_history());
+    assert!(!UpdatableResolutionApproach::MergeIntoTarget.rewrites_history());
+}
+
 fn command_ctx(name: &str) -> Result<(CommandContext, TempDir)> {
     gitbutler_testsupport::writable::fixture("stacking.sh", name)
 }
@@ -112,3 +112,18 @@ pub fn push_stack(
     emit_vbranches(&windows, project_id);
     Ok(())
 }
+
+#[tauri::command(async)]
+#[instrument(skip(projects, windows), err(Debug))]
+pub fn push_stack_for_review(
+    windows: State<'_, WindowState>,
+    projects: State<'_, projects::Controller>,
+    project_id: ProjectId,
+    branch_id: StackId,
+    target_branch: String,
+) -> Result<(), Error> {
+    let project = projects.get(project_id)?;
+    gitbutler_branch_actions::stack::push_stack_for_review(&project, branch_id, target_branch)?;
+    emit_vbranches(&windows, project_id);
+    Ok(())
+}
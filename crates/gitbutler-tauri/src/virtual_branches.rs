@@ -4,7 +4,8 @@ pub mod commands {
     use gitbutler_branch_actions::branch_upstream_integration::IntegrationStrategy;
     use gitbutler_branch_actions::internal::StackListResult;
     use gitbutler_branch_actions::upstream_integration::{
-        BaseBranchResolution, BaseBranchResolutionApproach, Resolution, StackStatuses,
+        BaseBranchResolution, BaseBranchResolutionApproach, IntegrationOutcome, Resolution,
+        StackStatuses,
     };
     use gitbutler_branch_actions::{
         BaseBranch, BranchListing, BranchListingDetails, BranchListingFilter, RemoteBranchData,
@@ -597,9 +598,9 @@ pub mod commands {
         project_id: ProjectId,
         resolutions: Vec<Resolution>,
         base_branch_resolution: Option<BaseBranchResolution>,
-    ) -> Result<(), Error> {
+    ) -> Result<IntegrationOutcome, Error> {
         let project = projects.get(project_id)?;
-        gitbutler_branch_actions::integrate_upstream(
+        let outcome = gitbutler_branch_actions::integrate_upstream(
             &project,
             &resolutions,
             base_branch_resolution,
@@ -607,7 +608,7 @@ pub mod commands {
 
         emit_vbranches(&windows, project_id);
 
-        Ok(())
+        Ok(outcome)
     }
 
     #[tauri::command(async)]
@@ -1,11 +1,11 @@
 use anyhow::{bail, Result};
-use gitbutler_cherry_pick::RepositoryExt;
+use gitbutler_cherry_pick::{ConflictPolicy, RepositoryExt};
 use gitbutler_command_context::CommandContext;
 use gitbutler_commit::commit_ext::CommitExt as _;
 use gitbutler_oxidize::{git2_to_gix_object_id, gix_to_git2_oid, GixRepositoryExt};
 use gitbutler_project::access::WorktreeWritePermission;
 use gitbutler_project::AUTO_TRACK_LIMIT_BYTES;
-use gitbutler_repo::rebase::cherry_rebase_group;
+use gitbutler_repo::rebase::cherry_rebase_group_with_policy;
 use gitbutler_repo::RepositoryExt as _;
 use gitbutler_stack::{Stack, VirtualBranchesHandle};
 use tracing::instrument;
@@ -117,6 +117,20 @@ pub fn compute_updated_branch_head_for_commits(
     old_head: git2::Oid,
     old_tree: git2::Oid,
     new_head: git2::Oid,
+) -> Result<BranchHeadAndTree> {
+    compute_updated_branch_head_for_commits_with_policy(
+        repository, old_head, old_tree, new_head, None,
+    )
+}
+
+/// Like [`compute_updated_branch_head_for_commits`], but the files that conflict are
+/// resolved as `policy` says, with `new_head` as *ours* and the uncommitted changes as *theirs*.
+pub fn compute_updated_branch_head_for_commits_with_policy(
+    repository: &git2::Repository,
+    old_head: git2::Oid,
+    old_tree: git2::Oid,
+    new_head: git2::Oid,
+    policy: Option<&dyn ConflictPolicy>,
 ) -> Result<BranchHeadAndTree> {
     let (author, committer) = repository.signatures()?;
 
@@ -130,7 +144,8 @@ pub fn compute_updated_branch_head_for_commits(
         Default::default(),
     )?;
 
-    let rebased_tree = cherry_rebase_group(repository, new_head, &[commited_tree], false)?;
+    let rebased_tree =
+        cherry_rebase_group_with_policy(repository, new_head, &[commited_tree], false, policy)?;
     let rebased_tree = repository.find_commit(rebased_tree)?;
 
     if rebased_tree.is_conflicted() {
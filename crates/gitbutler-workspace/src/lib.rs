@@ -2,5 +2,5 @@ mod branch_trees;
 
 pub use branch_trees::{
     checkout_branch_trees, compute_updated_branch_head, compute_updated_branch_head_for_commits,
-    BranchHeadAndTree,
+    compute_updated_branch_head_for_commits_with_policy, BranchHeadAndTree,
 };